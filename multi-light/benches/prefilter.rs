@@ -0,0 +1,45 @@
+//! Time tokenizing plain prose with and without `Registry::set_prefilter`,
+//! using a grammar whose rules all start with punctuation.  Run with
+//! `cargo bench --bench prefilter`.
+
+use std::time::{Duration, Instant};
+
+use multi_light::Registry;
+
+const GRAMMAR: &str = r##"{
+    "scopeName": "source.notes",
+    "patterns": [
+        {"match": "#.*", "name": "comment.line"},
+        {"begin": "\"", "end": "\"", "name": "string.quoted"},
+        {"match": "\\*\\*[^*]+\\*\\*", "name": "markup.bold"},
+        {"match": "`[^`]*`", "name": "markup.inline.raw"}
+    ]
+}"##;
+
+const LINE: &str = "Most lines of a notes file are prose, with nothing to highlight";
+
+fn time(registry: &Registry, input: &str) -> Duration {
+    let start = Instant::now();
+    for _ in 0..10 {
+        registry.parse("source.notes", input).unwrap();
+    }
+    start.elapsed() / 10
+}
+
+fn main() {
+    let input = vec![LINE; 10_000].join("\n");
+
+    let mut registry = Registry::new();
+    registry.add_grammar("notes.json", GRAMMAR).unwrap();
+
+    let filtered = time(&registry, &input);
+    registry.set_prefilter(false);
+    let full = time(&registry, &input);
+
+    println!("prefilter on:  {filtered:?} per parse");
+    println!("prefilter off: {full:?} per parse");
+    println!(
+        "speedup: {:.1}x",
+        full.as_secs_f64() / filtered.as_secs_f64()
+    );
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use indexmap::IndexMap;
+
+use crate::Error;
+
+use super::{Config, ConfigTree, NodeTables};
+
+/// Seconds between the unix epoch and the binary plist epoch, 2001-01-01
+const PLIST_EPOCH: f64 = 978_307_200.0;
+
+/// Deepest nesting of arrays and dicts accepted, so that hostile documents can't
+/// overflow the stack
+const MAX_DEPTH: usize = 128;
+
+/// Most values the decoded tree may contain.  Objects can be referred to any
+/// number of times, so a small document sharing objects can describe a tree
+/// far larger than itself.
+const MAX_VALUES: usize = 1 << 20;
+
+impl Config {
+    /// Parse a binary plist, in the `bplist00` format written by macOS tools.
+    /// Values map onto the same tree as an XML plist would; UIDs, which only
+    /// appear in keyed archives, are stored as integers.
+    pub fn from_plist_binary(file_name: impl Into<PathBuf>, bytes: &[u8]) -> Result<Self, Error> {
+        let file_name = file_name.into();
+
+        let tree = BinaryPlist::new(bytes, &file_name)?.parse()?;
+
+        Ok(Self {
+            tree,
+            file_name,
+            tables: NodeTables::default(),
+            warnings: vec![],
+        })
+    }
+}
+
+/// Reader for the object table of a binary plist.  The file is a header, a
+/// table of objects that refer to each other by index, a table of each
+/// object's offset, and a trailer describing the sizes used by the tables.
+struct BinaryPlist<'a> {
+    bytes: &'a [u8],
+    file_name: &'a Path,
+
+    /// Size in bytes of each entry in the offset table
+    offset_size: usize,
+
+    /// Size in bytes of each object reference within arrays and dicts
+    ref_size: usize,
+
+    num_objects: u64,
+    top_object: u64,
+    offset_table: usize,
+
+    /// Objects currently being decoded, to reject objects that contain
+    /// themselves
+    open: Vec<u64>,
+
+    /// Objects that have already been decoded, with the number of values each
+    /// contains, so objects referred to more than once are only decoded once
+    decoded: HashMap<u64, (ConfigTree, usize)>,
+
+    /// Number of values in the tree decoded so far
+    values: usize,
+}
+
+impl<'a> BinaryPlist<'a> {
+    /// Read the header and trailer of the document
+    fn new(bytes: &'a [u8], file_name: &'a Path) -> Result<Self, Error> {
+        let mut plist = Self {
+            bytes,
+            file_name,
+            offset_size: 0,
+            ref_size: 0,
+            num_objects: 0,
+            top_object: 0,
+            offset_table: 0,
+            open: vec![],
+            decoded: HashMap::new(),
+            values: 0,
+        };
+
+        if !bytes.starts_with(b"bplist00") || bytes.len() < 8 + 32 {
+            return Err(plist.error("missing bplist00 header"));
+        }
+
+        let trailer = bytes.len() - 32;
+        plist.offset_size = bytes[trailer + 6] as usize;
+        plist.ref_size = bytes[trailer + 7] as usize;
+        plist.num_objects = plist.uint(trailer + 8, 8)?;
+        plist.top_object = plist.uint(trailer + 16, 8)?;
+        let offset_table = plist.uint(trailer + 24, 8)?;
+
+        if !(1..=8).contains(&plist.offset_size) || !(1..=8).contains(&plist.ref_size) {
+            return Err(plist.error("invalid integer sizes in trailer"));
+        }
+
+        let table_len = plist.num_objects.checked_mul(plist.offset_size as u64);
+        match (usize::try_from(offset_table), table_len) {
+            (Ok(start), Some(len)) if start as u64 + len <= trailer as u64 => {
+                plist.offset_table = start;
+            }
+            _ => return Err(plist.error("offset table out of range")),
+        }
+
+        Ok(plist)
+    }
+
+    /// Decode the whole document
+    fn parse(mut self) -> Result<ConfigTree, Error> {
+        self.object(self.top_object)
+    }
+
+    /// Get the object with the given index, and everything it contains,
+    /// decoding it if it hasn't been seen before
+    fn object(&mut self, index: u64) -> Result<ConfigTree, Error> {
+        if let Some(&(_, count)) = self.decoded.get(&index) {
+            self.count_values(count)?;
+            return Ok(self.decoded[&index].0.clone());
+        }
+
+        let before = self.values;
+        let value = self.decode(index)?;
+        self.decoded
+            .insert(index, (value.clone(), self.values - before));
+
+        Ok(value)
+    }
+
+    /// Decode the object with the given index, and everything it contains
+    fn decode(&mut self, index: u64) -> Result<ConfigTree, Error> {
+        if index >= self.num_objects {
+            return Err(self.error(format!("reference to missing object {index}")));
+        }
+        if self.open.contains(&index) {
+            return Err(self.error(format!("object {index} contains itself")));
+        }
+        if self.open.len() > MAX_DEPTH {
+            return Err(self.error(format!("nested more than {MAX_DEPTH} levels deep")));
+        }
+        self.count_values(1)?;
+
+        let entry = self.offset_table + index as usize * self.offset_size;
+        let offset = self.uint(entry, self.offset_size)? as usize;
+        let marker = *self.slice(offset, 1)?.first().unwrap();
+        let info = marker & 0x0F;
+
+        let value = match (marker >> 4, info) {
+            (0x0, 0x0) => ConfigTree::Null,
+            (0x0, 0x8) => ConfigTree::Bool(false),
+            (0x0, 0x9) => ConfigTree::Bool(true),
+            (0x1, _) => self.integer(offset + 1, info)?,
+            (0x2, 2) => {
+                let bytes = self.slice(offset + 1, 4)?;
+                ConfigTree::Float(f32::from_be_bytes(bytes.try_into().unwrap()) as f64)
+            }
+            (0x2, 3) => ConfigTree::Float(self.float(offset + 1)?),
+            (0x3, 3) => {
+                let seconds = self.float(offset + 1)?;
+                match format_date(seconds) {
+                    Some(date) => ConfigTree::Date(date),
+                    None => return Err(self.error(format!("date out of range at byte {offset}"))),
+                }
+            }
+            (0x4, _) => {
+                let (len, start) = self.length(offset, info)?;
+                ConfigTree::Data(self.slice(start, len)?.to_vec())
+            }
+            (0x5, _) => {
+                let (len, start) = self.length(offset, info)?;
+                match std::str::from_utf8(self.slice(start, len)?) {
+                    Ok(value) => ConfigTree::String(value.to_string()),
+                    Err(_) => return Err(self.error(format!("invalid string at byte {offset}"))),
+                }
+            }
+            (0x6, _) => {
+                let (len, start) = self.length(offset, info)?;
+                let units = self
+                    .slice(start, len.saturating_mul(2))?
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+                match char::decode_utf16(units).collect() {
+                    Ok(value) => ConfigTree::String(value),
+                    Err(_) => return Err(self.error(format!("invalid string at byte {offset}"))),
+                }
+            }
+            (0x8, 0..=7) => {
+                let value = self.uint(offset + 1, info as usize + 1)?;
+                ConfigTree::Integer(value as i64)
+            }
+            (0xA, _) => {
+                let (len, start) = self.length(offset, info)?;
+                let refs = self.refs(start, len)?;
+
+                self.open.push(index);
+                let value = refs
+                    .into_iter()
+                    .map(|index| self.object(index))
+                    .collect::<Result<_, _>>()?;
+                self.open.pop();
+
+                ConfigTree::Array(value)
+            }
+            (0xD, _) => {
+                let (len, start) = self.length(offset, info)?;
+                let refs = self.refs(start, len.saturating_mul(2))?;
+                let (keys, values) = refs.split_at(len);
+
+                self.open.push(index);
+                let mut value = IndexMap::with_capacity(len);
+                for (&key, &val) in keys.iter().zip(values) {
+                    let ConfigTree::String(key) = self.object(key)? else {
+                        return Err(self.error(format!("non-string dict key at byte {offset}")));
+                    };
+                    value.insert(key, self.object(val)?);
+                }
+                self.open.pop();
+
+                ConfigTree::Object(value)
+            }
+            _ => {
+                return Err(self.error(format!(
+                    "unknown object type {marker:#04x} at byte {offset}"
+                )));
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// Add to the number of values decoded, erroring if there are too many
+    fn count_values(&mut self, count: usize) -> Result<(), Error> {
+        self.values += count;
+        if self.values > MAX_VALUES {
+            return Err(self.error(format!("more than {MAX_VALUES} values")));
+        }
+
+        Ok(())
+    }
+
+    /// Read an integer object's value, stored in `2^info` bytes.  Integers of
+    /// up to 4 bytes are unsigned, 8 byte integers are signed, and 16 byte
+    /// integers are only used for unsigned values that don't fit in 8 bytes.
+    fn integer(&self, start: usize, info: u8) -> Result<ConfigTree, Error> {
+        Ok(match info {
+            0..=2 => ConfigTree::Integer(self.uint(start, 1 << info)? as i64),
+            3 => ConfigTree::Integer(self.uint(start, 8)? as i64),
+            4 => {
+                let value = self.uint(start + 8, 8)?;
+                match i64::try_from(value) {
+                    Ok(value) => ConfigTree::Integer(value),
+                    Err(_) => ConfigTree::Float(value as f64),
+                }
+            }
+            _ => return Err(self.error(format!("invalid integer size at byte {}", start - 1))),
+        })
+    }
+
+    /// Read the length of a data, string, array or dict object, returning the
+    /// length and the offset of the object's contents.  Lengths of 15 or more
+    /// are stored in an integer object following the marker.
+    fn length(&self, offset: usize, info: u8) -> Result<(usize, usize), Error> {
+        if info != 0x0F {
+            return Ok((info as usize, offset + 1));
+        }
+
+        let marker = *self.slice(offset + 1, 1)?.first().unwrap();
+        if marker >> 4 != 0x1 || marker & 0x0F > 3 {
+            return Err(self.error(format!("invalid length at byte {offset}")));
+        }
+
+        let size = 1 << (marker & 0x0F);
+        let len = self.uint(offset + 2, size)?;
+        match usize::try_from(len) {
+            Ok(len) => Ok((len, offset + 2 + size)),
+            Err(_) => Err(self.error(format!("invalid length at byte {offset}"))),
+        }
+    }
+
+    /// Read `count` object references starting at `start`
+    fn refs(&self, start: usize, count: usize) -> Result<Vec<u64>, Error> {
+        let len = count
+            .checked_mul(self.ref_size)
+            .ok_or_else(|| self.error(format!("invalid length at byte {start}")))?;
+
+        Ok(self
+            .slice(start, len)?
+            .chunks_exact(self.ref_size)
+            .map(be_uint)
+            .collect())
+    }
+
+    /// Read a big endian 8 byte float
+    fn float(&self, start: usize) -> Result<f64, Error> {
+        let bytes = self.slice(start, 8)?;
+        Ok(f64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Read a big endian unsigned integer of up to 8 bytes
+    fn uint(&self, start: usize, size: usize) -> Result<u64, Error> {
+        Ok(be_uint(self.slice(start, size)?))
+    }
+
+    /// Get `len` bytes starting at `start`, or an error if they are past the
+    /// end of the document
+    fn slice(&self, start: usize, len: usize) -> Result<&'a [u8], Error> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(start..end))
+            .ok_or_else(|| self.error(format!("unexpected end of data reading byte {start}")))
+    }
+
+    /// Create an error message
+    fn error(&self, err: impl Into<String>) -> Error {
+        Error::PlistError {
+            err: format!("invalid binary plist: {}", err.into()),
+            file_name: self.file_name.to_path_buf(),
+            location: None,
+        }
+    }
+}
+
+/// Convert big endian bytes into an integer
+fn be_uint(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+/// Format a binary plist date, in seconds since 2001-01-01, the same way as an
+/// XML plist `<date>`.  Returns None for dates outside of years 0 to 9999.
+fn format_date(seconds: f64) -> Option<String> {
+    let unix = (seconds + PLIST_EPOCH).floor();
+    if !(-62_167_219_200.0..253_402_300_800.0).contains(&unix) {
+        return None;
+    }
+
+    let unix = unix as i64;
+    let (days, time) = (unix.div_euclid(86_400), unix.rem_euclid(86_400));
+
+    // convert days since the unix epoch into a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    Some(format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assemble a binary plist from its encoded objects, with one byte offsets
+    /// and object references, and the first object as the root
+    fn bplist(objects: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"bplist00".to_vec();
+        let mut offsets = vec![];
+        for object in objects {
+            offsets.push(bytes.len() as u8);
+            bytes.extend_from_slice(object);
+        }
+
+        let offset_table = bytes.len() as u64;
+        bytes.extend_from_slice(&offsets);
+
+        bytes.extend_from_slice(&[0; 6]);
+        bytes.extend_from_slice(&[1, 1]);
+        bytes.extend_from_slice(&(objects.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&0u64.to_be_bytes());
+        bytes.extend_from_slice(&offset_table.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn decode_objects() {
+        let mut real = vec![0x23];
+        real.extend_from_slice(&1.5f64.to_be_bytes());
+        let mut date = vec![0x33];
+        date.extend_from_slice(&0f64.to_be_bytes());
+
+        let bytes = bplist(&[
+            &[0xD4, 1, 2, 3, 4, 5, 6, 7, 8],
+            b"\x54name",
+            b"\x51n",
+            b"\x54list",
+            b"\x51u",
+            b"\x54Make",
+            &[0x10, 42],
+            &[0xA3, 9, 10, 11],
+            &[0x61, 0x00, 0xE9],
+            &[0x09],
+            &real,
+            &date,
+        ]);
+        let cfg = Config::from_plist_binary("test.plist", &bytes).unwrap();
+
+        let tree = cfg.tree();
+        assert_eq!(tree["name"], ConfigTree::String("Make".to_string()));
+        assert_eq!(tree["n"], ConfigTree::Integer(42));
+        assert_eq!(tree["u"], ConfigTree::String("é".to_string()));
+        assert_eq!(
+            tree["list"],
+            ConfigTree::Array(vec![
+                ConfigTree::Bool(true),
+                ConfigTree::Float(1.5),
+                ConfigTree::Date("2001-01-01T00:00:00Z".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        let cases: [(Vec<u8>, &str); 4] = [
+            (b"bplist00".to_vec(), "missing bplist00 header"),
+            (bplist(&[&[0xA1, 0]]), "object 0 contains itself"),
+            (bplist(&[&[0xA1, 5]]), "reference to missing object 5"),
+            (
+                bplist(&[&[0x5F, 0x10, 0xFF, b'a']]),
+                "unexpected end of data",
+            ),
+        ];
+
+        for (bytes, message) in cases {
+            match Config::from_plist_binary("test.plist", &bytes) {
+                Err(Error::PlistError { err, .. }) => assert!(err.contains(message), "{err}"),
+                other => panic!("expected a plist error, got {other:?}"),
+            }
+        }
+    }
+
+    /// Assemble a binary plist like `bplist`, but with 2 byte object references
+    /// and 4 byte offsets, for when there are more objects than fit in a byte
+    fn wide_bplist(objects: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"bplist00".to_vec();
+        let mut offsets = vec![];
+        for object in objects {
+            offsets.push(bytes.len() as u32);
+            bytes.extend(object);
+        }
+
+        let offset_table = bytes.len() as u64;
+        for offset in offsets {
+            bytes.extend(offset.to_be_bytes());
+        }
+
+        bytes.extend([0; 6]);
+        bytes.extend([4, 2]);
+        bytes.extend((objects.len() as u64).to_be_bytes());
+        bytes.extend(0u64.to_be_bytes());
+        bytes.extend(offset_table.to_be_bytes());
+        bytes
+    }
+
+    /// An array object referring to the given objects
+    fn array(refs: &[u16]) -> Vec<u8> {
+        assert!(refs.len() < 15);
+        let mut object = vec![0xA0 | refs.len() as u8];
+        refs.iter().for_each(|r| object.extend(r.to_be_bytes()));
+        object
+    }
+
+    fn parse(objects: &[Vec<u8>]) -> Result<Config, Error> {
+        Config::from_plist_binary("test.plist", &wide_bplist(objects))
+    }
+
+    #[test]
+    fn shared_objects() {
+        let objects = [array(&[1, 1]), array(&[2, 2]), vec![0x51, b'x']];
+        let cfg = parse(&objects).unwrap();
+
+        let expected = Config::from_json("test.json", r#"[["x", "x"], ["x", "x"]]"#).unwrap();
+        assert_eq!(cfg.tree(), expected.tree());
+    }
+
+    #[test]
+    fn shared_objects_limited() {
+        // each array refers twice to the next, so the tree doubles in size with
+        // every level
+        let mut objects: Vec<_> = (1..64).map(|next| array(&[next, next])).collect();
+        objects.push(vec![0x09]);
+
+        let err = parse(&objects).unwrap_err().to_string();
+        assert!(err.contains("more than 1048576 values"), "{err}");
+    }
+
+    #[test]
+    fn nesting_depth_limited() {
+        let nested = |levels: u16| {
+            let mut objects: Vec<_> = (1..=levels).map(|next| array(&[next])).collect();
+            objects.push(vec![0x09]);
+            parse(&objects)
+        };
+
+        assert!(nested(MAX_DEPTH as u16).is_ok());
+
+        let err = nested(MAX_DEPTH as u16 + 1).unwrap_err().to_string();
+        assert!(err.contains("nested more than"), "{err}");
+    }
+
+    #[test]
+    fn cycle_rejected() {
+        let err = parse(&[array(&[1]), array(&[0])]).unwrap_err().to_string();
+        assert!(err.contains("object 0 contains itself"), "{err}");
+    }
+}
@@ -0,0 +1,248 @@
+//! Deserialize a parsed tree into other types, the reverse of `ConfigTree`'s own
+//! `Deserialize` impl.  This allows a document to be loaded in any format, then
+//! mapped onto the user's own structs.
+
+use std::{fmt::Display, path::PathBuf};
+
+use serde::{
+    Deserializer,
+    de::{
+        self, DeserializeOwned, IntoDeserializer, Visitor,
+        value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+    },
+    forward_to_deserialize_any,
+};
+
+use crate::Error;
+
+use super::{Config, ConfigTree};
+
+impl Config {
+    /// Deserialize the document into another type
+    pub fn deserialize_into<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        T::deserialize(&self.tree).map_err(|err| Error::DeserializeError {
+            err: err.0,
+            file_name: self.file_name.clone(),
+        })
+    }
+}
+
+impl ConfigTree {
+    /// Deserialize the tree into another type.  The tree doesn't know which
+    /// file it came from, so any error has an empty file name; use
+    /// `Config::deserialize_into` to include it.
+    pub fn deserialize_into<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        T::deserialize(self).map_err(|err| Error::DeserializeError {
+            err: err.0,
+            file_name: PathBuf::new(),
+        })
+    }
+}
+
+/// Error produced while deserializing, converted into `Error` once done
+#[derive(Debug)]
+pub struct DeserializeError(String);
+
+impl Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl de::Error for DeserializeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeserializeError> for &'de ConfigTree {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+/// Numbers written as strings are parsed if a number is asked for
+macro_rules! deserialize_number {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self {
+                    ConfigTree::String(value) => match value.parse() {
+                        Ok(num) => visitor.$visit(num),
+                        Err(_) => self.deserialize_any(visitor),
+                    },
+                    _ => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for &'de ConfigTree {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            ConfigTree::Null => visitor.visit_unit(),
+            ConfigTree::Bool(value) => visitor.visit_bool(*value),
+            ConfigTree::Integer(value) => visitor.visit_i64(*value),
+            ConfigTree::Float(value) => visitor.visit_f64(*value),
+            ConfigTree::String(value) | ConfigTree::Date(value) => {
+                visitor.visit_borrowed_str(value)
+            }
+            ConfigTree::Data(value) => visitor.visit_borrowed_bytes(value),
+            ConfigTree::Array(value) => {
+                let mut seq = SeqDeserializer::new(value.iter());
+                let res = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(res)
+            }
+            ConfigTree::Object(value) => {
+                let mut map = MapDeserializer::new(value.iter().map(|(k, v)| (k.as_str(), v)));
+                let res = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(res)
+            }
+        }
+    }
+
+    deserialize_number! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            ConfigTree::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            // unit variants are just their name, others are a single entry map
+            // from the name to the variant's content
+            ConfigTree::String(value) => visitor.visit_enum(value.as_str().into_deserializer()),
+            ConfigTree::Object(value) if value.len() == 1 => {
+                let map = MapDeserializer::new(value.iter().map(|(k, v)| (k.as_str(), v)));
+                visitor.visit_enum(MapAccessDeserializer::new(map))
+            }
+            _ => Err(de::Error::custom(
+                "expected a string or single entry map for an enum",
+            )),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i128 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[test]
+    fn deserialize_numbers_from_plist() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Settings {
+            size: u32,
+            scale: f64,
+            name: Option<String>,
+        }
+
+        let content = "<plist><dict>\
+            <key>size</key><integer>12</integer>\
+            <key>scale</key><real>1.5</real>\
+            </dict></plist>";
+        let cfg = Config::from_plist("settings.plist", content).unwrap();
+
+        let settings: Settings = cfg.deserialize_into().unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                size: 12,
+                scale: 1.5,
+                name: None,
+            }
+        );
+
+        let cfg = Config::from_json("settings.json", r#"{"size": "x", "scale": 1}"#).unwrap();
+        match cfg.deserialize_into::<Settings>() {
+            Err(Error::DeserializeError { file_name, .. }) => {
+                assert_eq!(file_name, PathBuf::from("settings.json"));
+            }
+            other => panic!("expected a deserialize error, got {other:?}"),
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Header {
+        name: String,
+        scope_name: String,
+        file_types: Vec<String>,
+        #[serde(default)]
+        hidden: bool,
+    }
+
+    #[test]
+    fn deserialize_struct() {
+        let content = r#"{"name": "Make", "scopeName": "source.makefile", "fileTypes": ["mk"]}"#;
+        let cfg = Config::from_json("make.json", content).unwrap();
+
+        let header: Header = cfg.deserialize_into().unwrap();
+        assert_eq!(
+            header,
+            Header {
+                name: "Make".to_string(),
+                scope_name: "source.makefile".to_string(),
+                file_types: vec!["mk".to_string()],
+                hidden: false,
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_error_type() {
+        let cfg = Config::from_json("make.json", r#"{"name": 3}"#).unwrap();
+
+        let err: crate::DeserializeError = Header::deserialize(cfg.tree()).unwrap_err();
+        assert!(err.to_string().contains("invalid type"), "{err}");
+
+        match cfg.deserialize_into::<Header>() {
+            Err(Error::DeserializeError { file_name, .. }) => {
+                assert_eq!(file_name, PathBuf::from("make.json"));
+            }
+            other => panic!("expected a deserialize error, got {other:?}"),
+        }
+    }
+}
@@ -1,11 +1,14 @@
-use std::path::PathBuf;
+use std::{cell::RefCell, path::PathBuf};
+
+use serde::de::DeserializeSeed;
 
 use crate::Error;
 
-use super::Config;
+use super::{Config, ConfigTreeVisitor, NodeTables, strip_bom};
 
 impl Config {
-    /// Parse a JSON string
+    /// Parse a JSON string.  Any JSON value is accepted as the document root, not
+    /// only objects, e.g. a bare `tokenColors` array.
     // (Note that the vscode version has 2 parsers, one which includes debug info
     // but I cannot be bothered to deal with the debugging versions of any of the
     // input format parsers)
@@ -13,16 +16,291 @@ impl Config {
         file_name: impl Into<PathBuf>,
         content: impl AsRef<str>,
     ) -> Result<Self, Error> {
-        let file_name = file_name.into();
+        Self::json(
+            file_name.into(),
+            content.as_ref(),
+            ConfigTreeVisitor::default(),
+        )
+    }
+
+    /// Parse a JSON string, the same as `from_json`, except that an object
+    /// containing the same key twice is an error rather than keeping the last
+    /// value.
+    pub fn from_json_strict(
+        file_name: impl Into<PathBuf>,
+        content: impl AsRef<str>,
+    ) -> Result<Self, Error> {
+        let visitor = ConfigTreeVisitor {
+            reject_duplicate_keys: true,
+            ..ConfigTreeVisitor::default()
+        };
+        Self::json(file_name.into(), content.as_ref(), visitor)
+    }
 
-        let json = serde_json::from_str(content.as_ref()).map_err(|err| Error::SerdeJson {
+    /// Parse a JSON with comments string, as used by many VSCode grammars and
+    /// themes.  `//` and `/* */` comments are allowed, as are trailing commas
+    /// after the last value in arrays and objects.  Comments and trailing commas
+    /// are replaced with spaces before parsing, so error locations match the
+    /// original text.
+    pub fn from_jsonc(
+        file_name: impl Into<PathBuf>,
+        content: impl AsRef<str>,
+    ) -> Result<Self, Error> {
+        let file_name = file_name.into();
+        let content = strip_jsonc(strip_bom(content.as_ref())).map_err(|err| Error::JSONError {
             err,
             file_name: file_name.clone(),
         })?;
+        Self::json(file_name, &content, ConfigTreeVisitor::default())
+    }
+
+    /// Write the document as compact JSON, e.g. to cache a grammar that was
+    /// loaded from another format
+    pub fn to_json_string(&self) -> Result<String, Error> {
+        serde_json::to_string(&self.tree).map_err(|err| self.serialize_error(err))
+    }
+
+    /// Write the document as indented JSON
+    pub fn to_json_string_pretty(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(&self.tree).map_err(|err| self.serialize_error(err))
+    }
+
+    fn serialize_error(&self, err: serde_json::Error) -> Error {
+        Error::SerializeJson {
+            err,
+            file_name: self.file_name.clone(),
+        }
+    }
+
+    fn json(file_name: PathBuf, content: &str, visitor: ConfigTreeVisitor) -> Result<Self, Error> {
+        let mut deserializer = serde_json::Deserializer::from_str(strip_bom(content));
+
+        let duplicates = RefCell::default();
+        let visitor = ConfigTreeVisitor {
+            duplicates: Some(&duplicates),
+            ..visitor
+        };
+
+        let json = visitor
+            .deserialize(&mut deserializer)
+            .and_then(|json| deserializer.end().map(|()| json))
+            .map_err(|err| Error::SerdeJson {
+                err,
+                file_name: file_name.clone(),
+            })?;
+
+        let tables = NodeTables {
+            duplicate_keys: duplicates.into_inner().found,
+            ..NodeTables::default()
+        };
 
         Ok(Self {
             tree: json,
             file_name,
+            tables,
+            warnings: vec![],
         })
     }
 }
+
+/// Blank out the comments and trailing commas in a JSON with comments document,
+/// leaving newlines in place so line and column numbers are unchanged.  Errors if
+/// a block comment is never closed.
+fn strip_jsonc(content: &str) -> Result<String, String> {
+    let bytes = content.as_bytes();
+    let mut out = bytes.to_vec();
+
+    // position of the last comma, if it follows a value and only whitespace and
+    // comments follow it
+    let mut comma = None;
+
+    // whether the last token was a value, so a comma after it may be trailing;
+    // any other comma is left for the JSON parser to reject, e.g. in `[,]`
+    let mut after_value = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'"', _) => {
+                comma = None;
+                after_value = true;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    // skip over the escaped character, which could be a quote
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            (b'/', Some(b'/')) => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    out[i] = b' ';
+                    i += 1;
+                }
+            }
+            (b'/', Some(b'*')) => {
+                let Some(end) = content[i + 2..].find("*/").map(|end| i + end + 4) else {
+                    let line = content[..i].matches('\n').count() + 1;
+                    let column = content[..i].rfind('\n').map_or(i, |pos| i - pos - 1) + 1;
+                    return Err(format!(
+                        "unterminated block comment at line {line} column {column}"
+                    ));
+                };
+                for byte in &mut out[i..end] {
+                    if !matches!(*byte, b'\n' | b'\r') {
+                        *byte = b' ';
+                    }
+                }
+                i = end;
+            }
+            (b',', _) => {
+                comma = after_value.then_some(i);
+                after_value = false;
+                i += 1;
+            }
+            (b'}' | b']', _) => {
+                if let Some(comma) = comma.take() {
+                    out[comma] = b' ';
+                }
+                after_value = true;
+                i += 1;
+            }
+            (b'{' | b'[' | b':', _) => {
+                comma = None;
+                after_value = false;
+                i += 1;
+            }
+            (b' ' | b'\t' | b'\r' | b'\n', _) => i += 1,
+            _ => {
+                comma = None;
+                after_value = true;
+                i += 1;
+            }
+        }
+    }
+
+    // only whole characters were replaced, each byte with a space
+    Ok(String::from_utf8(out).expect("blanking whole characters keeps the text valid utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ConfigTree;
+
+    #[test]
+    fn top_level_array() {
+        let content = r##"[{"scope": "comment", "settings": {"foreground": "#888"}}, true]"##;
+        let cfg = Config::from_json("tokenColors.json", content).unwrap();
+
+        let ConfigTree::Array(array) = cfg.tree() else {
+            panic!("expected an array, got {:?}", cfg.tree());
+        };
+        assert_eq!(array.len(), 2);
+        assert_eq!(array[0]["scope"], ConfigTree::String("comment".to_string()));
+        assert_eq!(array[1], ConfigTree::Bool(true));
+    }
+
+    #[test]
+    fn top_level_string() {
+        let cfg = Config::from_json("a.json", r#""source.makefile""#).unwrap();
+        assert_eq!(
+            cfg.tree(),
+            &ConfigTree::String("source.makefile".to_string())
+        );
+    }
+
+    #[test]
+    fn duplicate_keys_last_value_wins() {
+        let content = r#"{"name": "a", "repository": {"x": 1, "x": 2}, "name": "b"}"#;
+        let cfg = Config::from_json("make.json", content).unwrap();
+
+        assert_eq!(cfg.tree()["name"], ConfigTree::String("b".to_string()));
+        assert_eq!(cfg.tree()["repository"]["x"], ConfigTree::Integer(2));
+    }
+
+    #[test]
+    fn duplicate_keys_strict() {
+        let err = Config::from_json_strict("make.json", r#"{"repository": {"x": 1, "x": 2}}"#)
+            .unwrap_err();
+        assert!(matches!(err, Error::SerdeJson { .. }), "{err:?}");
+
+        let message = err.to_string();
+        assert!(message.contains("`make.json`"), "{message}");
+        assert!(message.contains("duplicate key `x`"), "{message}");
+
+        assert!(Config::from_json_strict("make.json", r#"{"x": {"x": 1}}"#).is_ok());
+    }
+
+    #[test]
+    fn to_json_string_round_trip() {
+        let cfg = Config::from_yaml(
+            "make.yaml",
+            "name: Makefile\npatterns:\n  - match: '#.*'\n    captures: {}\n",
+        )
+        .unwrap();
+
+        let compact = cfg.to_json_string().unwrap();
+        assert_eq!(
+            compact,
+            r##"{"name":"Makefile","patterns":[{"match":"#.*","captures":{}}]}"##
+        );
+
+        let pretty = cfg.to_json_string_pretty().unwrap();
+        assert!(pretty.contains("\n  \"name\": \"Makefile\",\n"), "{pretty}");
+
+        for json in [compact, pretty] {
+            let reparsed = Config::from_json("make.json", json).unwrap();
+            assert_eq!(reparsed.tree(), cfg.tree());
+        }
+    }
+
+    #[test]
+    fn jsonc_keeps_error_locations() {
+        let content =
+            "{\n  /* a\n  comment */ \"a\": 1, // x\n  \"b\": [\"/*\", \"\\\"//\",],\n  \"c\": }";
+        let err = Config::from_jsonc("a.jsonc", content).unwrap_err();
+        let Error::SerdeJson { err, .. } = err else {
+            panic!("expected a JSON error, got {err:?}");
+        };
+        assert_eq!((err.line(), err.column()), (5, 8));
+
+        let content = content.replace("\"c\": }", "\"c\": null }");
+        let cfg = Config::from_jsonc("a.jsonc", content).unwrap();
+        let expected = Config::from_json("a.json", r#"{"a": 1, "b": ["/*", "\"//"], "c": null}"#);
+        assert_eq!(cfg.tree(), expected.unwrap().tree());
+    }
+
+    #[test]
+    fn jsonc_comments_and_trailing_commas() {
+        let content =
+            "{\n  // line comment\n  \"a\": [1, 2, /* block */],\n  \"b\": \"//not a comment\",\n}";
+        let cfg = Config::from_jsonc("a.jsonc", content).unwrap();
+        let expected = Config::from_json("a.json", r#"{"a": [1, 2], "b": "//not a comment"}"#);
+        assert_eq!(cfg.tree(), expected.unwrap().tree());
+    }
+
+    #[test]
+    fn jsonc_unterminated_block_comment() {
+        let err = Config::from_jsonc("a.jsonc", "{\"a\": 1}\n  /* unterminated").unwrap_err();
+        match err {
+            Error::JSONError { err, .. } => {
+                assert_eq!(err, "unterminated block comment at line 2 column 3")
+            }
+            other => panic!("expected a JSON error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn jsonc_comma_without_value() {
+        for content in ["[,]", "{,}", "[1,,]", "{\"a\":,}", "[/* */,]"] {
+            assert!(
+                Config::from_jsonc("a.jsonc", content).is_err(),
+                "{content} should be rejected"
+            );
+        }
+
+        let cfg = Config::from_jsonc("a.jsonc", "[[],{},\"x\",]").unwrap();
+        assert_eq!(cfg.tree().as_array().map(<[_]>::len), Some(3));
+    }
+}
@@ -2,41 +2,806 @@
 //! central format, so the rest of the code doesn't have to deal with JSON vs
 //! plist vs YAML etc.
 
+mod bplist;
+mod de;
 mod json;
 mod plist;
 mod toml;
 mod yaml;
 
-use std::{collections::HashMap, fmt::Debug, path::PathBuf};
+pub use de::DeserializeError;
+pub use plist::{BareAmpersands, PlistEvents, PlistOptions, PlistType};
 
-use serde::{Deserialize, de::Visitor};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Debug,
+    ops::{Index, IndexMut},
+    path::{Path, PathBuf},
+};
+
+use indexmap::{IndexMap, map::MutableKeys};
+use serde::{
+    Deserialize, Serialize,
+    de::{DeserializeSeed, Visitor},
+};
+
+use crate::{Error, SourceLocation, Span};
 
 /// Document representation common to JSON/plist/XML/YAML
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     /// Contents of the file
     tree: ConfigTree,
 
     /// File name of the document
     file_name: PathBuf,
+
+    /// Extra information about nodes recorded while parsing, if requested
+    tables: NodeTables,
+
+    /// Problems found while parsing that didn't stop the document loading
+    warnings: Vec<ConfigWarning>,
+}
+
+/// A problem in a document that was worked around while parsing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigWarning {
+    pub message: String,
+    pub location: Option<SourceLocation>,
+}
+
+/// The file formats that a document can be parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConfigFormat {
+    Plist,
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Config {
+    /// Parse a string in a known file format
+    pub fn from_format(
+        format: ConfigFormat,
+        file_name: impl Into<PathBuf>,
+        content: impl AsRef<str>,
+    ) -> Result<Self, Error> {
+        match format {
+            ConfigFormat::Plist => Self::from_plist(file_name, content),
+            ConfigFormat::Json => Self::from_json(file_name, content),
+            ConfigFormat::Toml => Self::from_toml(file_name, content),
+            ConfigFormat::Yaml => Self::from_yaml(file_name, content),
+        }
+    }
+
+    /// Parse a document into a stream of events rather than a tree.  Plist
+    /// documents are parsed incrementally as the iterator is advanced; the
+    /// other formats are parsed up front by their respective libraries, then
+    /// replayed as events with object entries in document order.  If the
+    /// document is invalid, the stream ends with an error.
+    pub fn events(
+        format: ConfigFormat,
+        file_name: impl Into<PathBuf>,
+        content: &str,
+    ) -> impl Iterator<Item = Result<ParserEvent, Error>> {
+        let file_name = file_name.into();
+
+        let events = match format {
+            ConfigFormat::Plist => {
+                return Events::Plist(Box::new(PlistEvents::new(file_name, content)));
+            }
+            _ => Self::from_format(format, file_name, content).map(|cfg| {
+                let mut events = vec![];
+                cfg.tree.into_events(&mut events);
+                events
+            }),
+        };
+
+        match events {
+            Ok(events) => Events::Tree(events.into_iter()),
+            Err(err) => Events::Error(Some(err)),
+        }
+    }
+}
+
+/// Events emitted when parsing a document, in document order.  Objects start
+/// with `EnterDict`, followed by alternating `Key` and value events, and end with
+/// `CloseDict`.  Arrays are the same but without the keys.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParserEvent {
+    Value(ConfigTree),
+    EnterDict,
+    Key(String),
+    CloseDict,
+    EnterArray,
+    CloseArray,
+}
+
+/// The event iterators used for each format
+enum Events<'a> {
+    Plist(Box<PlistEvents<'a>>),
+    Tree(std::vec::IntoIter<ParserEvent>),
+    Error(Option<Error>),
+}
+
+impl Iterator for Events<'_> {
+    type Item = Result<ParserEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Plist(events) => events.next(),
+            Self::Tree(events) => events.next().map(Ok),
+            Self::Error(err) => err.take().map(Err),
+        }
+    }
+}
+
+/// Remove a leading UTF-8 byte order mark, which some editors write at the start
+/// of files but none of the format parsers accept.  Only the very start of the
+/// content is checked, so a U+FEFF later in the document is kept.
+fn strip_bom(content: &str) -> &str {
+    content.strip_prefix('\u{FEFF}').unwrap_or(content)
+}
+
+/// Optional per-node data, each table is either empty or indexed by the
+/// pre-order position of every node in the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct NodeTables {
+    /// Id of each node, if they have been remapped.  When empty, the id of each
+    /// node is its position.
+    ids: Vec<ConfigNodeID>,
+
+    /// Element type each node was read from, only for plist documents
+    plist_types: Vec<PlistType>,
+
+    /// Source text each node was read from, only for plist documents
+    spans: Vec<Span>,
+
+    /// Path of each object key that appeared more than once, where only the
+    /// last value was kept.  Only recorded for JSON and plist documents.
+    duplicate_keys: Vec<Vec<PathSegment>>,
+}
+
+impl Config {
+    /// The parsed contents of the document
+    pub fn tree(&self) -> &ConfigTree {
+        &self.tree
+    }
+
+    /// Mutable access to the parsed contents of the document, e.g. to prune or
+    /// normalise it after loading
+    pub fn tree_mut(&mut self) -> &mut ConfigTree {
+        &mut self.tree
+    }
+
+    /// File name of the document
+    pub fn file_name(&self) -> &Path {
+        &self.file_name
+    }
+
+    /// Problems found while parsing that didn't stop the document loading
+    pub fn warnings(&self) -> &[ConfigWarning] {
+        &self.warnings
+    }
+
+    /// Release any spare capacity held by the document.  Documents are rarely
+    /// modified after loading, so this can save a fair amount of memory for
+    /// large grammars at the cost of one pass over the tree.
+    pub fn shrink_to_fit(&mut self) {
+        self.tree.shrink_to_fit();
+        self.tables.ids.shrink_to_fit();
+        self.tables.plist_types.shrink_to_fit();
+        self.tables.spans.shrink_to_fit();
+        self.tables.duplicate_keys.shrink_to_fit();
+        self.warnings.shrink_to_fit();
+    }
+
+    /// The human readable `name` of a grammar, if the document has one
+    pub fn grammar_name(&self) -> Option<&str> {
+        self.top_level_string("name")
+    }
+
+    /// The `scopeName` of a grammar, e.g. `source.makefile`, which other
+    /// grammars use to include it
+    pub fn grammar_scope_name(&self) -> Option<&str> {
+        self.top_level_string("scopeName")
+    }
+
+    /// The `comment` describing a grammar, if the document has one
+    pub fn grammar_comment(&self) -> Option<&str> {
+        self.top_level_string("comment")
+    }
+
+    /// Whether the object key at the given path appeared more than once in the
+    /// document, so that earlier values were overwritten by the last one
+    pub(crate) fn is_duplicate_key(&self, path: &[PathSegment]) -> bool {
+        self.tables.duplicate_keys.iter().any(|key| key == path)
+    }
+
+    /// Find a node using a slash separated path, see `ConfigTree::pointer`
+    pub fn pointer(&self, path: &str) -> Option<&ConfigTree> {
+        self.tree.pointer(path)
+    }
+
+    /// Get a string value stored directly in the root object
+    fn top_level_string(&self, key: &str) -> Option<&str> {
+        self.tree.get(key)?.as_str()
+    }
+
+    /// Get the node with the given id.  Ids describe the tree as it was parsed,
+    /// so are invalidated by any edits made through `tree_mut`.
+    pub fn node(&self, id: ConfigNodeID) -> Option<&ConfigTree> {
+        self.tree.nodes().get(self.position_of(id)?).copied()
+    }
+
+    /// The span of source text that a node was read from.  Only available for
+    /// plist documents parsed with `PlistOptions::keep_spans` set.
+    pub fn span_of(&self, id: ConfigNodeID) -> Option<Span> {
+        self.tables.spans.get(self.position_of(id)?).copied()
+    }
+
+    /// Get the id of a node within this document.  Nodes are compared by
+    /// address, so the node must be borrowed from this config's tree.
+    pub fn node_id(&self, node: &ConfigTree) -> Option<ConfigNodeID> {
+        self.tree
+            .nodes()
+            .iter()
+            .position(|n| std::ptr::eq(*n, node))
+            .map(|pos| self.id_at(pos))
+    }
+
+    /// Reuse the ids from a previous version of this document, so references to
+    /// nodes held elsewhere stay valid across a reload.  Nodes are matched by
+    /// their path from the root; a node keeps its old id if the previous
+    /// document had a node of the same kind at that path (and, for scalars, the
+    /// same value).  Every other node gets a fresh id not used by `previous`.
+    pub fn remap_ids_from(&mut self, previous: &Config) {
+        let old: HashMap<_, _> = previous
+            .tree
+            .nodes_with_paths()
+            .into_iter()
+            .enumerate()
+            .map(|(pos, (path, node))| (path, (previous.id_at(pos), node)))
+            .collect();
+
+        let mut next = old.values().map(|(id, _)| id.0 + 1).max().unwrap_or(0);
+
+        self.tables.ids = self
+            .tree
+            .nodes_with_paths()
+            .into_iter()
+            .map(|(path, node)| match old.get(&path) {
+                Some((id, old)) if node.same_kind_or_value(old) => *id,
+                _ => {
+                    next += 1;
+                    ConfigNodeID(next - 1)
+                }
+            })
+            .collect();
+    }
+
+    /// Compare the contents of two documents, allowing for the differences that
+    /// come from parsing the same data in different formats: numbers and strings
+    /// compare equal if they represent the same number, e.g. a JSON `23.40` and a
+    /// plist `<real>23.40</real>`.  File names and any recorded node data are
+    /// ignored.
+    pub fn eq_cross_format(&self, other: &Config) -> bool {
+        self.tree.eq_cross_format(&other.tree)
+    }
+
+    /// Position in pre-order of the node with the given id
+    fn position_of(&self, id: ConfigNodeID) -> Option<usize> {
+        if self.tables.ids.is_empty() {
+            Some(id.0)
+        } else {
+            self.tables.ids.iter().position(|&i| i == id)
+        }
+    }
+
+    /// Id of the node at the given position in pre-order
+    fn id_at(&self, pos: usize) -> ConfigNodeID {
+        self.tables
+            .ids
+            .get(pos)
+            .copied()
+            .unwrap_or(ConfigNodeID(pos))
+    }
 }
 
 /// Identifier for a single node within a parsed document tree, only applies to
-/// the tree that it was parsed from
+/// the tree that it was parsed from.  Nodes are numbered in pre-order, visiting
+/// array elements in order and object entries sorted by key, so that sorting
+/// the paths of every node gives them in id order, which lets the plist parser
+/// number nodes from their paths alone.  The numbering can be replaced using
+/// `Config::remap_ids_from`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Deserialize)]
 #[serde(transparent)]
 pub struct ConfigNodeID(pub usize);
 
-/// Raw tree data within a parsed document.  The generic parameter is used for
-/// the NodeID, to allow setting it to a zero-size type if debug info is not
-/// needed, therefore making the structure smaller.
-#[derive(Clone, PartialEq, Eq)]
+/// A single step along the path from the root of a tree to one of its nodes.
+/// Sorting the paths of every node in a tree gives the nodes in id order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum PathSegment {
+    Index(usize),
+    Key(String),
+}
+
+/// Raw tree data within a parsed document.
+///
+/// Equality is strict: values of different variants are never equal, so
+/// `Integer(1) != Float(1.0)`, and strings are not compared as numbers.  Floats
+/// compare by value, so `23.4` and `23.40` are equal once parsed.  Use
+/// `Config::eq_cross_format` to compare numbers however they were stored.
+#[derive(Clone, PartialEq)]
 pub enum ConfigTree {
     Null,
     Bool(bool),
+    Integer(i64),
+    Float(f64),
     String(String),
+    Data(Vec<u8>),
+
+    /// A timestamp.  Plist dates are UTC, written as `YYYY-MM-DDTHH:MM:SSZ`;
+    /// TOML datetimes are kept as written in the source, so may also be an
+    /// offset, local, date only or time only value.
+    Date(String),
     Array(Vec<ConfigTree>),
-    Object(HashMap<String, ConfigTree>),
+    Object(IndexMap<String, ConfigTree>),
+}
+
+impl ConfigTree {
+    /// Get the value stored under a key, or None if the tree is not an object
+    /// or doesn't contain the key
+    pub fn get(&self, key: &str) -> Option<&ConfigTree> {
+        match self {
+            Self::Object(value) => value.get(key),
+            _ => None,
+        }
+    }
+
+    /// Mutable version of `get`
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut ConfigTree> {
+        match self {
+            Self::Object(value) => value.get_mut(key),
+            _ => None,
+        }
+    }
+
+    /// Get an array element, or None if the tree is not an array or the index
+    /// is out of bounds
+    pub fn get_index(&self, index: usize) -> Option<&ConfigTree> {
+        match self {
+            Self::Array(value) => value.get(index),
+            _ => None,
+        }
+    }
+
+    /// Mutable version of `get_index`
+    pub fn get_index_mut(&mut self, index: usize) -> Option<&mut ConfigTree> {
+        match self {
+            Self::Array(value) => value.get_mut(index),
+            _ => None,
+        }
+    }
+
+    /// The value of a string, or None for any other kind of node
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value of a boolean, or None for any other kind of node
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value of an integer, or None for any other kind of node
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value of a float or integer, or None for any other kind of node.
+    /// Integers that can't be represented exactly are rounded.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Integer(value) => Some(*value as f64),
+            Self::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The contents of a binary data node, or None for any other kind of node
+    pub fn as_data(&self) -> Option<&[u8]> {
+        match self {
+            Self::Data(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The text of a date, or None for any other kind of node
+    pub fn as_date(&self) -> Option<&str> {
+        match self {
+            Self::Date(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The elements of an array, or None for any other kind of node
+    pub fn as_array(&self) -> Option<&[ConfigTree]> {
+        match self {
+            Self::Array(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The entries of an object, or None for any other kind of node
+    pub fn as_object(&self) -> Option<&IndexMap<String, ConfigTree>> {
+        match self {
+            Self::Object(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Find a node using a slash separated path, e.g.
+    /// `repository/string/patterns/0/match`.  Objects are descended into by key
+    /// and arrays by index; a leading `/` is optional and an empty path is the
+    /// root.  As in JSON pointers, `~1` within a key stands for `/` and `~0`
+    /// for `~`.  Returns None if any part of the path doesn't exist.
+    pub fn pointer(&self, path: &str) -> Option<&ConfigTree> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        let mut node = self;
+        for segment in path.split('/') {
+            node = match node {
+                Self::Object(value) => value.get(&segment.replace("~1", "/").replace("~0", "~"))?,
+                Self::Array(value) => value.get(segment.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+
+        Some(node)
+    }
+
+    /// Mutable version of `pointer`
+    pub fn pointer_mut(&mut self, path: &str) -> Option<&mut ConfigTree> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        let mut node = self;
+        for segment in path.split('/') {
+            node = match node {
+                Self::Object(value) => {
+                    value.get_mut(&segment.replace("~1", "/").replace("~0", "~"))?
+                }
+                Self::Array(value) => value.get_mut(segment.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+
+        Some(node)
+    }
+
+    /// Retain only the children of an object or array for which the predicate
+    /// returns true.  The predicate is given the key of object entries, or
+    /// `None` for array elements.  Only direct children are visited, the same
+    /// as `Vec::retain`, and scalars are left untouched.
+    pub fn retain(&mut self, mut predicate: impl FnMut(Option<&str>, &ConfigTree) -> bool) {
+        match self {
+            Self::Array(value) => value.retain(|v| predicate(None, v)),
+            Self::Object(value) => value.retain(|k, v| predicate(Some(k), v)),
+            Self::Null
+            | Self::Bool(_)
+            | Self::Integer(_)
+            | Self::Float(_)
+            | Self::String(_)
+            | Self::Data(_)
+            | Self::Date(_) => (),
+        }
+    }
+
+    /// List every scalar in the tree along with its path from the root, e.g.
+    /// `repository.string.patterns[0].match`.  Object keys are joined with `.`
+    /// and written as-is, array indices are written as `[0]`.  Entries are in
+    /// document order, and empty objects and arrays are not included as they
+    /// contain no scalars.
+    pub fn flatten(&self) -> Vec<(String, &ConfigTree)> {
+        let mut leaves = vec![];
+        self.flatten_into(String::new(), &mut leaves);
+        leaves
+    }
+
+    fn flatten_into<'a>(&'a self, path: String, leaves: &mut Vec<(String, &'a ConfigTree)>) {
+        match self {
+            Self::Array(value) => {
+                for (i, value) in value.iter().enumerate() {
+                    value.flatten_into(format!("{path}[{i}]"), leaves);
+                }
+            }
+            Self::Object(value) => {
+                for (key, value) in value {
+                    let path = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{path}.{key}")
+                    };
+                    value.flatten_into(path, leaves);
+                }
+            }
+            Self::Null
+            | Self::Bool(_)
+            | Self::Integer(_)
+            | Self::Float(_)
+            | Self::String(_)
+            | Self::Data(_)
+            | Self::Date(_) => leaves.push((path, self)),
+        }
+    }
+
+    /// Sort the array at `path`, given in the same form as `pointer`, e.g.
+    /// `fileTypes` or `colors/editor.background`.  If `key` is given, object
+    /// elements are ordered by the value stored under that key; otherwise
+    /// elements are ordered by their own value.  Null sorts before booleans,
+    /// which sort before numbers, then strings, then binary data, then dates;
+    /// anything else keeps its relative order.
+    /// Useful for canonicalising arrays whose order doesn't matter.  Arrays
+    /// stored under a `patterns` key, where order does matter, are never
+    /// sorted.  Returns whether an array was sorted.
+    pub fn sort_arrays_by(&mut self, path: &str, key: Option<&str>) -> bool {
+        let last = path.trim_end_matches('/').rsplit('/').next();
+        if last == Some("patterns") {
+            return false;
+        }
+
+        let Some(Self::Array(value)) = self.pointer_mut(path) else {
+            return false;
+        };
+
+        value.sort_by(|a, b| match key {
+            Some(key) => match (a.get(key), b.get(key)) {
+                (Some(a), Some(b)) => a.scalar_cmp(b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            },
+            None => a.scalar_cmp(b),
+        });
+
+        true
+    }
+
+    /// Ordering used for sorting arrays, only compares scalars
+    fn scalar_cmp(&self, other: &ConfigTree) -> Ordering {
+        let rank = |tree: &ConfigTree| match tree {
+            Self::Null => 0,
+            Self::Bool(_) => 1,
+            Self::Integer(_) | Self::Float(_) => 2,
+            Self::String(_) => 3,
+            Self::Data(_) => 4,
+            Self::Date(_) => 5,
+            Self::Array(_) | Self::Object(_) => 6,
+        };
+
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (Self::Data(a), Self::Data(b)) => a.cmp(b),
+            (Self::Date(a), Self::Date(b)) => a.cmp(b),
+            (Self::Integer(_) | Self::Float(_), Self::Integer(_) | Self::Float(_)) => {
+                let (a, b) = (self.numeric_value(), other.numeric_value());
+                a.zip(b).map_or(Ordering::Equal, |(a, b)| a.total_cmp(&b))
+            }
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+
+    /// Value of a number, or of a string containing a number
+    fn numeric_value(&self) -> Option<f64> {
+        match self {
+            Self::String(value) => value.trim().parse().ok(),
+            _ => self.as_f64(),
+        }
+    }
+
+    /// Release any spare capacity held by strings and containers in the tree
+    pub fn shrink_to_fit(&mut self) {
+        match self {
+            Self::String(value) => value.shrink_to_fit(),
+            Self::Array(value) => {
+                value.iter_mut().for_each(Self::shrink_to_fit);
+                value.shrink_to_fit();
+            }
+            Self::Object(value) => {
+                // shrinking a key doesn't change its hash, so is safe in place
+                for (key, value) in value.iter_mut2() {
+                    key.shrink_to_fit();
+                    value.shrink_to_fit();
+                }
+                value.shrink_to_fit();
+            }
+            Self::Data(value) => value.shrink_to_fit(),
+            Self::Date(value) => value.shrink_to_fit(),
+            Self::Null | Self::Bool(_) | Self::Integer(_) | Self::Float(_) => (),
+        }
+    }
+
+    /// Every node in the tree, in pre-order
+    fn nodes(&self) -> Vec<&ConfigTree> {
+        self.nodes_with_paths()
+            .into_iter()
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    /// Every node in the tree in pre-order, along with its path from the root.
+    /// Object entries are visited sorted by key, see `ConfigNodeID`.
+    fn nodes_with_paths(&self) -> Vec<(Vec<PathSegment>, &ConfigTree)> {
+        let mut nodes = vec![];
+        let mut stack = vec![(vec![], self)];
+
+        while let Some((path, node)) = stack.pop() {
+            // children are pushed in reverse so they are popped in order
+            match node {
+                Self::Array(value) => stack.extend(value.iter().enumerate().rev().map(|(i, v)| {
+                    let mut path = path.clone();
+                    path.push(PathSegment::Index(i));
+                    (path, v)
+                })),
+                Self::Object(value) => {
+                    let mut entries: Vec<_> = value.iter().collect();
+                    entries.sort_by(|a, b| b.0.cmp(a.0));
+                    stack.extend(entries.into_iter().map(|(k, v)| {
+                        let mut path = path.clone();
+                        path.push(PathSegment::Key(k.clone()));
+                        (path, v)
+                    }));
+                }
+                Self::Null
+                | Self::Bool(_)
+                | Self::Integer(_)
+                | Self::Float(_)
+                | Self::String(_)
+                | Self::Data(_)
+                | Self::Date(_) => (),
+            }
+
+            nodes.push((path, node));
+        }
+
+        nodes
+    }
+
+    /// Convert the tree into the events a parser would emit for it
+    fn into_events(self, events: &mut Vec<ParserEvent>) {
+        match self {
+            Self::Array(value) => {
+                events.push(ParserEvent::EnterArray);
+                for value in value {
+                    value.into_events(events);
+                }
+                events.push(ParserEvent::CloseArray);
+            }
+            Self::Object(value) => {
+                events.push(ParserEvent::EnterDict);
+                for (key, value) in value {
+                    events.push(ParserEvent::Key(key));
+                    value.into_events(events);
+                }
+                events.push(ParserEvent::CloseDict);
+            }
+            value @ (Self::Null
+            | Self::Bool(_)
+            | Self::Integer(_)
+            | Self::Float(_)
+            | Self::String(_)
+            | Self::Data(_)
+            | Self::Date(_)) => events.push(ParserEvent::Value(value)),
+        }
+    }
+
+    /// Structural equality where numbers, and strings that parse to a number,
+    /// are compared by value, and dates equal strings with the same text
+    fn eq_cross_format(&self, other: &ConfigTree) -> bool {
+        match (self, other) {
+            (Self::Date(a), Self::String(b)) | (Self::String(a), Self::Date(b)) => a == b,
+            (Self::Array(a), Self::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_cross_format(b))
+            }
+            (Self::Object(a), Self::Object(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .all(|(k, a)| b.get(k).is_some_and(|b| a.eq_cross_format(b)))
+            }
+            _ => {
+                self == other
+                    || matches!(
+                        (self.numeric_value(), other.numeric_value()),
+                        (Some(a), Some(b)) if a == b
+                    )
+            }
+        }
+    }
+
+    /// Whether two nodes are the same kind of container, or equal scalars
+    fn same_kind_or_value(&self, other: &ConfigTree) -> bool {
+        match (self, other) {
+            (Self::Array(_), Self::Array(_)) | (Self::Object(_), Self::Object(_)) => true,
+            _ => self == other,
+        }
+    }
+}
+
+/// Look up an object entry by key.
+///
+/// # Panics
+/// Panics if the tree is not an object or doesn't contain the key, the same as
+/// indexing an `IndexMap`.
+impl Index<&str> for ConfigTree {
+    type Output = ConfigTree;
+
+    fn index(&self, key: &str) -> &Self::Output {
+        match self {
+            Self::Object(value) => &value[key],
+            _ => panic!("cannot index into a non-object with key `{key}`"),
+        }
+    }
+}
+
+/// Mutably look up an object entry by key.
+///
+/// # Panics
+/// Panics if the tree is not an object or doesn't contain the key.
+impl IndexMut<&str> for ConfigTree {
+    fn index_mut(&mut self, key: &str) -> &mut Self::Output {
+        match self {
+            Self::Object(value) => value
+                .get_mut(key)
+                .unwrap_or_else(|| panic!("key `{key}` not found in object")),
+            _ => panic!("cannot index into a non-object with key `{key}`"),
+        }
+    }
+}
+
+/// Look up an array element by position.
+///
+/// # Panics
+/// Panics if the tree is not an array or the index is out of bounds, the same
+/// as indexing a `Vec`.
+impl Index<usize> for ConfigTree {
+    type Output = ConfigTree;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        match self {
+            Self::Array(value) => &value[index],
+            _ => panic!("cannot index into a non-array with index {index}"),
+        }
+    }
+}
+
+/// Mutably look up an array element by position.
+///
+/// # Panics
+/// Panics if the tree is not an array or the index is out of bounds.
+impl IndexMut<usize> for ConfigTree {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        match self {
+            Self::Array(value) => &mut value[index],
+            _ => panic!("cannot index into a non-array with index {index}"),
+        }
+    }
 }
 
 /// Serde derive didn't really do what I wanted for deserializing into this format
@@ -47,13 +812,58 @@ impl<'de> Deserialize<'de> for ConfigTree {
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer.deserialize_any(ConfigTreeVisitor)
+        deserializer.deserialize_any(ConfigTreeVisitor::default())
     }
 }
 
-struct ConfigTreeVisitor;
+/// Visitor building a `ConfigTree`.  It is also used as the seed for nested
+/// values, so its options apply to the whole document.
+#[derive(Debug, Clone, Copy, Default)]
+struct ConfigTreeVisitor<'a> {
+    /// Error if an object contains the same key twice, rather than keeping the
+    /// last value
+    reject_duplicate_keys: bool,
+
+    /// Where to record the keys that are kept for their last value, if wanted
+    duplicates: Option<&'a RefCell<DuplicateKeys>>,
+}
+
+/// Paths of the keys found more than once while building a tree
+#[derive(Debug, Default)]
+struct DuplicateKeys {
+    /// Path to the value currently being parsed
+    path: Vec<PathSegment>,
+
+    found: Vec<Vec<PathSegment>>,
+}
+
+impl ConfigTreeVisitor<'_> {
+    /// Run a parse with the given segment added to the current path, if paths
+    /// are being tracked
+    fn within<T>(self, segment: PathSegment, parse: impl FnOnce(Self) -> T) -> T {
+        let Some(duplicates) = self.duplicates else {
+            return parse(self);
+        };
 
-impl<'de> Visitor<'de> for ConfigTreeVisitor {
+        duplicates.borrow_mut().path.push(segment);
+        let value = parse(self);
+        duplicates.borrow_mut().path.pop();
+        value
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ConfigTreeVisitor<'_> {
+    type Value = ConfigTree;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ConfigTreeVisitor<'_> {
     type Value = ConfigTree;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -71,21 +881,25 @@ impl<'de> Visitor<'de> for ConfigTreeVisitor {
     where
         E: serde::de::Error,
     {
-        self.visit_string(v.to_string())
+        Ok(ConfigTree::Integer(v))
     }
 
     fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        self.visit_string(v.to_string())
+        // values too large for an i64 keep their magnitude at least
+        match i64::try_from(v) {
+            Ok(v) => Ok(ConfigTree::Integer(v)),
+            Err(_) => Ok(ConfigTree::Float(v as f64)),
+        }
     }
 
     fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        self.visit_string(v.to_string())
+        Ok(ConfigTree::Float(v))
     }
 
     fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
@@ -102,6 +916,20 @@ impl<'de> Visitor<'de> for ConfigTreeVisitor {
         self.visit_string(v.to_string())
     }
 
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(ConfigTree::Data(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.visit_byte_buf(v.to_vec())
+    }
+
     fn visit_none<E>(self) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
@@ -113,7 +941,7 @@ impl<'de> Visitor<'de> for ConfigTreeVisitor {
     where
         D: serde::Deserializer<'de>,
     {
-        Deserialize::deserialize(deserializer)
+        DeserializeSeed::deserialize(self, deserializer)
     }
 
     fn visit_unit<E>(self) -> Result<Self::Value, E>
@@ -129,7 +957,9 @@ impl<'de> Visitor<'de> for ConfigTreeVisitor {
     {
         let mut array = Vec::with_capacity(access.size_hint().unwrap_or(0));
 
-        while let Some(value) = access.next_element()? {
+        while let Some(value) = self.within(PathSegment::Index(array.len()), |seed| {
+            access.next_element_seed(seed)
+        })? {
             array.push(value);
         }
 
@@ -140,9 +970,24 @@ impl<'de> Visitor<'de> for ConfigTreeVisitor {
     where
         A: serde::de::MapAccess<'de>,
     {
-        let mut map = HashMap::with_capacity(access.size_hint().unwrap_or(0));
+        let mut map = IndexMap::with_capacity(access.size_hint().unwrap_or(0));
+
+        while let Some(key) = access.next_key::<String>()? {
+            if map.contains_key(&key) {
+                if self.reject_duplicate_keys {
+                    return Err(serde::de::Error::custom(format!("duplicate key `{key}`")));
+                }
+                if let Some(duplicates) = self.duplicates {
+                    let mut duplicates = duplicates.borrow_mut();
+                    let mut path = duplicates.path.clone();
+                    path.push(PathSegment::Key(key.clone()));
+                    duplicates.found.push(path);
+                }
+            }
 
-        while let Some((key, value)) = access.next_entry()? {
+            let value = self.within(PathSegment::Key(key.clone()), |seed| {
+                access.next_value_seed(seed)
+            })?;
             map.insert(key, value);
         }
 
@@ -150,15 +995,567 @@ impl<'de> Visitor<'de> for ConfigTreeVisitor {
     }
 }
 
+/// Serializes the tree as the equivalent serde data model values, so it can be
+/// written out with any serde format, e.g. to convert a plist grammar to JSON.
+impl Serialize for ConfigTree {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::Null => serializer.serialize_unit(),
+            Self::Bool(value) => serializer.serialize_bool(*value),
+            Self::Integer(value) => serializer.serialize_i64(*value),
+            Self::Float(value) => serializer.serialize_f64(*value),
+            Self::String(value) => serializer.serialize_str(value),
+            Self::Data(value) => serializer.serialize_bytes(value),
+            Self::Date(value) => serializer.serialize_str(value),
+            Self::Array(value) => serializer.collect_seq(value),
+            Self::Object(value) => serializer.collect_map(value),
+        }
+    }
+}
+
 /// Custom debug impl of the tree which flattens it if debug info is zero-size.
 impl Debug for ConfigTree {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Null => f.debug_struct("Null").finish(),
             Self::Bool(value) => Debug::fmt(value, f),
+            Self::Integer(value) => Debug::fmt(value, f),
+            Self::Float(value) => Debug::fmt(value, f),
             Self::String(value) => Debug::fmt(value, f),
+            Self::Data(value) => f.debug_tuple("Data").field(value).finish(),
+            Self::Date(value) => f.debug_tuple("Date").field(value).finish(),
             Self::Array(value) => f.debug_list().entries(value).finish(),
             Self::Object(value) => f.debug_map().entries(value).finish(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(content: &str) -> Config {
+        Config::from_json("test.json", content).unwrap()
+    }
+
+    #[test]
+    fn retain_array_elements() {
+        let mut cfg = json(r#"[true, "a", false, "b", null]"#);
+        cfg.tree_mut()
+            .retain(|_, v| !matches!(v, ConfigTree::String(_)));
+
+        assert_eq!(cfg.tree(), json("[true, false, null]").tree());
+    }
+
+    #[test]
+    fn retain_object_keys() {
+        let mut cfg = json(r#"{"name": "x", "comment": "y", "patterns": []}"#);
+        cfg.tree_mut()
+            .retain(|key, _| key.is_some_and(|key| key != "comment"));
+
+        assert_eq!(cfg.tree(), json(r#"{"name": "x", "patterns": []}"#).tree());
+    }
+
+    #[test]
+    fn retain_scalar() {
+        let mut tree = ConfigTree::String("x".to_string());
+        tree.retain(|_, _| false);
+
+        assert_eq!(tree, ConfigTree::String("x".to_string()));
+    }
+
+    #[test]
+    fn index_by_key_and_position() {
+        let mut cfg = json(r#"{"scopeName": "source.x", "patterns": [{"match": "a"}]}"#);
+
+        let string = |value: &str| ConfigTree::String(value.to_string());
+        assert_eq!(cfg.tree()["scopeName"], string("source.x"));
+        assert_eq!(cfg.tree()["patterns"][0]["match"], string("a"));
+
+        cfg.tree_mut()["patterns"][0]["match"] = string("b");
+        assert_eq!(
+            cfg.tree(),
+            json(r#"{"scopeName": "source.x", "patterns": [{"match": "b"}]}"#).tree()
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_missing_key() {
+        let _ = &json("{}").tree()["scopeName"];
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_out_of_bounds() {
+        let _ = &json("[1]").tree()[1];
+    }
+
+    #[test]
+    fn leading_bom_is_stripped() {
+        let expected = json(r#"{"name": "x"}"#);
+
+        let cases = [
+            Config::from_json("test.json", "\u{FEFF}{\"name\": \"x\"}"),
+            Config::from_toml("test.toml", "\u{FEFF}name = \"x\""),
+            Config::from_yaml("test.yaml", "\u{FEFF}name: x"),
+            Config::from_plist(
+                "test.plist",
+                "\u{FEFF}<plist><dict><key>name</key><string>x</string></dict></plist>",
+            ),
+        ];
+        for cfg in cases {
+            assert_eq!(cfg.unwrap().tree(), expected.tree());
+        }
+    }
+
+    #[test]
+    fn bom_within_content_is_kept() {
+        let cfg = json("{\"name\": \"\u{FEFF}x\"}");
+        assert_eq!(
+            cfg.tree()["name"],
+            ConfigTree::String("\u{FEFF}x".to_string())
+        );
+    }
+
+    #[test]
+    fn grammar_metadata() {
+        let cfg =
+            json(r#"{"name": "Makefile", "comment": "GNU make", "scopeName": "source.makefile"}"#);
+        assert_eq!(cfg.grammar_name(), Some("Makefile"));
+        assert_eq!(cfg.grammar_comment(), Some("GNU make"));
+
+        let cfg = json(r#"{"name": true, "scopeName": "source.x"}"#);
+        assert_eq!(cfg.grammar_name(), None);
+        assert_eq!(cfg.grammar_comment(), None);
+
+        assert_eq!(json(r#"["Makefile"]"#).grammar_name(), None);
+    }
+
+    #[test]
+    fn remap_ids_keeps_unchanged_nodes() {
+        let old = json(r#"{"name": "x", "patterns": [{"match": "a"}, {"match": "b"}]}"#);
+        let mut new = json(r#"{"name": "y", "patterns": [{"match": "a"}, {"match": "b"}]}"#);
+        new.remap_ids_from(&old);
+
+        let nodes = |config: &Config| {
+            let tree = config.tree();
+            [
+                tree,
+                &tree["patterns"],
+                &tree["patterns"][0]["match"],
+                &tree["patterns"][1]["match"],
+            ]
+            .map(|node| config.node_id(node))
+        };
+        assert_eq!(nodes(&old), nodes(&new));
+
+        let old_name = old.node_id(&old.tree()["name"]).unwrap();
+        let new_name = new.node_id(&new.tree()["name"]).unwrap();
+        assert_ne!(old_name, new_name);
+        assert!(old.node(new_name).is_none());
+        assert_eq!(
+            new.node(new_name),
+            Some(&ConfigTree::String("y".to_string()))
+        );
+    }
+
+    #[test]
+    fn events_equal_across_formats() {
+        let events = |format, content| {
+            Config::events(format, "test", content)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let json = events(
+            ConfigFormat::Json,
+            r#"{"name": "x", "patterns": [{"match": "a"}]}"#,
+        );
+        let plist = events(
+            ConfigFormat::Plist,
+            "<plist><dict><key>name</key><string>x</string>\
+            <key>patterns</key><array><dict><key>match</key><string>a</string></dict></array>\
+            </dict></plist>",
+        );
+
+        let string = |s: &str| ParserEvent::Value(ConfigTree::String(s.to_string()));
+        assert_eq!(
+            json,
+            [
+                ParserEvent::EnterDict,
+                ParserEvent::Key("name".to_string()),
+                string("x"),
+                ParserEvent::Key("patterns".to_string()),
+                ParserEvent::EnterArray,
+                ParserEvent::EnterDict,
+                ParserEvent::Key("match".to_string()),
+                string("a"),
+                ParserEvent::CloseDict,
+                ParserEvent::CloseArray,
+                ParserEvent::CloseDict,
+            ]
+        );
+        assert_eq!(json, plist);
+    }
+
+    #[test]
+    fn events_end_with_error() {
+        let events: Vec<_> = Config::events(ConfigFormat::Json, "test.json", "{").collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_err());
+    }
+
+    #[test]
+    fn eq_cross_format_numbers() {
+        let plist = |value: &str| {
+            Config::from_plist(
+                "test.plist",
+                format!("<plist><dict><key>n</key>{value}</dict></plist>"),
+            )
+            .unwrap()
+        };
+
+        assert!(json(r#"{"n": 57}"#).eq_cross_format(&plist("<integer>57</integer>")));
+        assert!(json(r#"{"n": "57"}"#).eq_cross_format(&plist("<integer>57</integer>")));
+        assert!(json(r#"{"n": 23.40}"#).eq_cross_format(&plist("<real>23.4</real>")));
+
+        assert!(!json(r#"{"n": 58}"#).eq_cross_format(&plist("<integer>57</integer>")));
+        assert!(!json(r#"{"n": "x"}"#).eq_cross_format(&plist("<string>y</string>")));
+        assert!(!json(r#"{"m": 57}"#).eq_cross_format(&plist("<integer>57</integer>")));
+    }
+
+    #[test]
+    fn flatten_leaf_paths() {
+        let cfg = json(
+            r##"{
+                "scopeName": "source.makefile",
+                "fileTypes": ["Makefile", "mk"],
+                "repository": {"comment": {"patterns": [{"match": "#.*", "name": "comment.line"}]}},
+                "empty": {}
+            }"##,
+        );
+
+        let leaves: Vec<_> = cfg
+            .tree()
+            .flatten()
+            .into_iter()
+            .map(|(path, value)| match value {
+                ConfigTree::String(value) => (path, value.as_str()),
+                _ => panic!("`{path}` is not a string"),
+            })
+            .collect();
+        assert_eq!(
+            leaves,
+            [
+                ("scopeName".to_string(), "source.makefile"),
+                ("fileTypes[0]".to_string(), "Makefile"),
+                ("fileTypes[1]".to_string(), "mk"),
+                ("repository.comment.patterns[0].match".to_string(), "#.*"),
+                (
+                    "repository.comment.patterns[0].name".to_string(),
+                    "comment.line"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_arrays_by_value_and_key() {
+        let mut cfg = json(
+            r#"{
+                "fileTypes": ["mk", null, "Makefile", true],
+                "repository": {"a": {"list": [{"name": "z"}, {"match": "x"}, {"name": "b"}]}}
+            }"#,
+        );
+        let expected = json(
+            r#"{
+                "fileTypes": [null, true, "Makefile", "mk"],
+                "repository": {"a": {"list": [{"match": "x"}, {"name": "b"}, {"name": "z"}]}}
+            }"#,
+        );
+
+        let tree = &mut cfg.tree;
+        assert!(tree.sort_arrays_by("fileTypes", None));
+        assert!(tree.sort_arrays_by("repository/a/list", Some("name")));
+        assert_eq!(cfg.tree(), expected.tree());
+
+        assert!(!cfg.tree.sort_arrays_by("repository/a", None));
+        assert!(!cfg.tree.sort_arrays_by("fileTypes/0", None));
+        assert!(!cfg.tree.sort_arrays_by("missing", None));
+    }
+
+    #[test]
+    fn shrink_to_fit_releases_capacity() {
+        let spare = |s: &str| {
+            let mut value = String::with_capacity(64);
+            value.push_str(s);
+            value
+        };
+
+        let mut array = Vec::with_capacity(16);
+        array.push(ConfigTree::String(spare("comment.line")));
+        let object = [(spare("name"), ConfigTree::String(spare("Makefile")))];
+        array.push(ConfigTree::Object(object.into_iter().collect()));
+
+        let mut tree = ConfigTree::Array(array);
+        tree.shrink_to_fit();
+
+        let ConfigTree::Array(array) = &tree else {
+            unreachable!()
+        };
+        assert_eq!(array.capacity(), array.len());
+
+        let ConfigTree::Object(object) = &array[1] else {
+            unreachable!()
+        };
+        let key = object.keys().next().unwrap();
+        assert_eq!(key.capacity(), key.len());
+
+        for value in [&array[0], &object["name"]] {
+            let ConfigTree::String(value) = value else {
+                unreachable!()
+            };
+            assert_eq!(value.capacity(), value.len());
+        }
+    }
+
+    #[test]
+    fn numbers_across_formats() {
+        let documents = [
+            (
+                ConfigFormat::Plist,
+                "<plist><dict><key>int</key><integer>42</integer>\
+                <key>real</key><real>1.5</real></dict></plist>",
+            ),
+            (ConfigFormat::Json, r#"{"int": 42, "real": 1.5}"#),
+            (ConfigFormat::Toml, "int = 42\nreal = 1.5\n"),
+            (ConfigFormat::Yaml, "int: 42\nreal: 1.5\n"),
+        ];
+
+        for (format, content) in documents {
+            let cfg = Config::from_format(format, "test", content).unwrap();
+            assert_eq!(cfg.tree()["int"], ConfigTree::Integer(42), "{format:?}");
+            assert_eq!(cfg.tree()["real"], ConfigTree::Float(1.5), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn get_and_get_index() {
+        let mut cfg = json(r#"{"name": "x", "fileTypes": ["mk"]}"#);
+        let tree = &mut cfg.tree;
+
+        assert_eq!(tree.get("name"), Some(&ConfigTree::String("x".to_string())));
+        assert_eq!(tree.get("missing"), None);
+        assert_eq!(tree.get_index(0), None);
+
+        let file_types = tree.get_mut("fileTypes").unwrap();
+        assert_eq!(file_types.get("mk"), None);
+        assert_eq!(file_types.get_index(1), None);
+
+        *file_types.get_index_mut(0).unwrap() = ConfigTree::Bool(true);
+        assert_eq!(tree["fileTypes"][0], ConfigTree::Bool(true));
+    }
+
+    #[test]
+    fn typed_accessors() {
+        let cfg = json(r#"{"s": "x", "b": true, "i": 3, "f": 1.5, "a": [null], "o": {}}"#);
+        let tree = cfg.tree();
+
+        assert_eq!(tree["s"].as_str(), Some("x"));
+        assert_eq!(tree["b"].as_bool(), Some(true));
+        assert_eq!(tree["i"].as_i64(), Some(3));
+        assert_eq!(tree["i"].as_f64(), Some(3.0));
+        assert_eq!(tree["f"].as_f64(), Some(1.5));
+        assert_eq!(tree["a"].as_array(), Some(&[ConfigTree::Null][..]));
+        assert!(tree["o"].as_object().unwrap().is_empty());
+
+        assert_eq!(tree["s"].as_bool(), None);
+        assert_eq!(tree["f"].as_i64(), None);
+        assert_eq!(tree["s"].as_f64(), None);
+        assert_eq!(tree["b"].as_str(), None);
+        assert!(tree["o"].as_array().is_none());
+        assert!(tree["a"].as_object().is_none());
+    }
+
+    #[test]
+    fn pointer_lookups() {
+        let cfg =
+            json(r#"{"repository": {"string": {"patterns": [{"match": "\"", "a/b~c": 1}]}}}"#);
+
+        let first = cfg.pointer("repository/string/patterns/0").unwrap();
+        assert_eq!(cfg.pointer("/repository/string/patterns/0"), Some(first));
+        assert_eq!(
+            cfg.pointer("repository/string/patterns/0/match"),
+            Some(&ConfigTree::String("\"".to_string()))
+        );
+        assert_eq!(first.pointer("a~1b~0c"), Some(&ConfigTree::Integer(1)));
+        assert_eq!(cfg.pointer(""), Some(cfg.tree()));
+
+        assert_eq!(cfg.pointer("repository/string/patterns/1"), None);
+        assert_eq!(cfg.pointer("repository/string/patterns/x"), None);
+        assert_eq!(cfg.pointer("repository/missing"), None);
+        assert_eq!(cfg.pointer("repository/string/patterns/0/match/0"), None);
+    }
+
+    #[test]
+    fn keys_in_document_order() {
+        let documents = [
+            (
+                ConfigFormat::Plist,
+                "<plist><dict><key>z</key><true/><key>a</key><true/>\
+                <key>m</key><true/></dict></plist>",
+            ),
+            (ConfigFormat::Json, r#"{"z": true, "a": true, "m": true}"#),
+            (ConfigFormat::Toml, "z = true\na = true\nm = true\n"),
+            (ConfigFormat::Yaml, "z: true\na: true\nm: true\n"),
+        ];
+
+        for (format, content) in documents {
+            let cfg = Config::from_format(format, "test", content).unwrap();
+            let keys: Vec<_> = cfg.tree().as_object().unwrap().keys().collect();
+            assert_eq!(keys, ["z", "a", "m"], "{format:?}");
+        }
+
+        assert_eq!(
+            json(r#"{"a": 1, "b": 2}"#).tree(),
+            json(r#"{"b": 2, "a": 1}"#).tree()
+        );
+    }
+
+    #[test]
+    fn serialize_plist_as_json() {
+        let content = "<plist><dict>\
+            <key>name</key><string>Makefile</string>\
+            <key>fileTypes</key><array><string>mk</string></array>\
+            <key>version</key><integer>2</integer>\
+            <key>scale</key><real>0.5</real>\
+            <key>hidden</key><false/>\
+            </dict></plist>";
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+
+        assert_eq!(
+            serde_json::to_string(cfg.tree()).unwrap(),
+            r#"{"name":"Makefile","fileTypes":["mk"],"version":2,"scale":0.5,"hidden":false}"#
+        );
+    }
+
+    fn events(format: ConfigFormat, content: &str) -> Vec<ParserEvent> {
+        Config::events(format, "test", content)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn events_match_across_formats() {
+        use ParserEvent::*;
+
+        let expected = vec![
+            EnterDict,
+            Key("scopeName".to_string()),
+            Value(ConfigTree::String("source.x".to_string())),
+            Key("fileTypes".to_string()),
+            EnterArray,
+            Value(ConfigTree::String("x".to_string())),
+            CloseArray,
+            Key("repository".to_string()),
+            EnterDict,
+            CloseDict,
+            CloseDict,
+        ];
+
+        let documents = [
+            (
+                ConfigFormat::Plist,
+                "<plist><dict><key>scopeName</key><string>source.x</string>\
+                <key>fileTypes</key><array><string>x</string></array>\
+                <key>repository</key><dict/></dict></plist>",
+            ),
+            (
+                ConfigFormat::Json,
+                r#"{"scopeName": "source.x", "fileTypes": ["x"], "repository": {}}"#,
+            ),
+            (
+                ConfigFormat::Toml,
+                "scopeName = \"source.x\"\nfileTypes = [\"x\"]\n[repository]\n",
+            ),
+            (
+                ConfigFormat::Yaml,
+                "scopeName: source.x\nfileTypes: [x]\nrepository: {}\n",
+            ),
+        ];
+
+        for (format, content) in documents {
+            assert_eq!(events(format, content), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn events_for_self_closing_plist_containers() {
+        use ParserEvent::*;
+
+        let content = "<plist><array><array/><dict/></array></plist>";
+        assert_eq!(
+            events(ConfigFormat::Plist, content),
+            vec![
+                EnterArray, EnterArray, CloseArray, EnterDict, CloseDict, CloseArray
+            ]
+        );
+
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+        assert_eq!(cfg.tree(), json("[[], {}]").tree());
+    }
+
+    #[test]
+    fn sort_arrays_by_pointer() {
+        let content = r##"{
+            "fileTypes": ["mk", "Makefile", "GNUmakefile"],
+            "patterns": [{"include": "#b"}, {"include": "#a"}],
+            "sorted.by": {"list": [{"n": 2}, {"n": 1}, {}]}
+        }"##;
+        let mut cfg = Config::from_json("a.json", content).unwrap();
+        let tree = cfg.tree_mut();
+
+        assert!(tree.sort_arrays_by("fileTypes", None));
+        let file_types: Vec<_> = tree
+            .pointer("fileTypes")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        assert_eq!(file_types, ["GNUmakefile", "Makefile", "mk"]);
+
+        assert!(tree.sort_arrays_by("/sorted.by/list", Some("n")));
+        assert_eq!(
+            tree.pointer("sorted.by/list/0"),
+            Some(&ConfigTree::Object(IndexMap::new()))
+        );
+        assert_eq!(
+            tree.pointer("sorted.by/list/1/n"),
+            Some(&ConfigTree::Integer(1))
+        );
+
+        assert!(!tree.sort_arrays_by("patterns", Some("include")));
+        assert_eq!(
+            tree.pointer("patterns/0/include").unwrap().as_str(),
+            Some("#b")
+        );
+
+        assert!(!tree.sort_arrays_by("missing", None));
+    }
+
+    #[test]
+    fn numeric_equality_is_strict() {
+        assert_eq!(json("23.4").tree(), json("23.40").tree());
+        assert_eq!(json("23.4").tree(), &ConfigTree::Float(23.4));
+
+        assert_ne!(ConfigTree::Integer(1), ConfigTree::Float(1.0));
+        assert_ne!(json("1").tree(), json("1.0").tree());
+        assert!(json("1").eq_cross_format(&json("1.0")));
+    }
+}
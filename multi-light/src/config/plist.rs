@@ -1,15 +1,14 @@
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-    str::CharIndices,
-    sync::LazyLock,
-};
+use std::{path::PathBuf, str::Chars, sync::LazyLock};
+
+use indexmap::IndexMap;
 
-use onig::{Captures, Regex};
+use onig::Regex;
 
-use crate::Error;
+use crate::{CharCursor, Error, SourceLocation, Span};
 
-use super::{Config, ConfigTree};
+use super::{
+    Config, ConfigNodeID, ConfigTree, ConfigWarning, NodeTables, ParserEvent, PathSegment,
+};
 
 impl Config {
     /// Parse a plist string
@@ -17,59 +16,295 @@ impl Config {
     pub fn from_plist(
         file_name: impl Into<PathBuf>,
         content: impl AsRef<str>,
+    ) -> Result<Self, Error> {
+        Self::from_plist_with_options(file_name, content, PlistOptions::default())
+    }
+
+    /// Parse a plist string, with extra control over what is recorded
+    pub fn from_plist_with_options(
+        file_name: impl Into<PathBuf>,
+        content: impl AsRef<str>,
+        options: PlistOptions,
+    ) -> Result<Self, Error> {
+        Self::from_plist_chars(file_name, content.as_ref().chars(), options)
+    }
+
+    /// Parse a plist document from a stream of characters.  Only the text of
+    /// the tag currently being parsed is buffered, so the whole document never
+    /// has to be held in memory at once.
+    pub fn from_plist_chars(
+        file_name: impl Into<PathBuf>,
+        content: impl IntoIterator<Item = char>,
+        options: PlistOptions,
     ) -> Result<Self, Error> {
         let file_name = file_name.into();
 
-        let tree = PlistParser::new(content.as_ref(), &file_name);
+        let parser = PlistParser::new(content.into_iter(), file_name.clone(), options);
+        let (tree, tables, warnings) = parser.parse()?;
 
         Ok(Self {
-            tree: tree.parse()?,
+            tree,
             file_name,
+            tables,
+            warnings,
+        })
+    }
+
+    /// Parse a plist that may be either binary or XML, telling them apart by
+    /// the `bplist00` header of binary plists.  XML plists must be UTF-8.
+    pub fn from_plist_bytes(file_name: impl Into<PathBuf>, bytes: &[u8]) -> Result<Self, Error> {
+        let file_name = file_name.into();
+
+        if bytes.starts_with(b"bplist") {
+            return Self::from_plist_binary(file_name, bytes);
+        }
+
+        match std::str::from_utf8(bytes) {
+            Ok(content) => Self::from_plist(file_name, content),
+            Err(err) => Err(Error::PlistError {
+                err: format!("plist is not valid UTF-8: {err}"),
+                file_name,
+                location: None,
+            }),
+        }
+    }
+
+    /// Parse a plist string into a stream of events, see `PlistEvents`
+    pub fn plist_events(file_name: impl Into<PathBuf>, content: &str) -> PlistEvents<'_> {
+        PlistEvents::new(file_name.into(), content)
+    }
+
+    /// The plist element that a node was read from.  Only available if the
+    /// document was parsed with `PlistOptions::keep_types` set.
+    pub fn plist_type_of(&self, id: ConfigNodeID) -> Option<PlistType> {
+        self.tables.plist_types.get(self.position_of(id)?).copied()
+    }
+}
+
+/// Options for parsing plist documents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlistOptions {
+    /// Record which element type each value was read from, e.g. to tell a
+    /// `<real>` apart from a `<string>` when re-writing the document.
+    pub keep_types: bool,
+
+    /// Record the span of source text that each value was read from, from the
+    /// start of its opening tag to the end of its closing tag, see
+    /// `Config::span_of`.
+    pub keep_spans: bool,
+
+    /// Trim surrounding whitespace from strings stored under keys that hold
+    /// names or scopes (see `NAME_KEYS`), where stray whitespace would stop
+    /// selectors from matching.  All other strings, such as regex patterns, are
+    /// always kept exactly as written.  Defaults to false.
+    pub trim_names: bool,
+
+    /// What to do with an `&` that doesn't start an entity, which is invalid XML
+    /// but often found in hand-written grammars.  Defaults to a warning.
+    pub bare_ampersands: BareAmpersands,
+
+    /// Reject a `<dict>` containing the same `<key>` twice, rather than keeping
+    /// the last value.  Defaults to false.
+    pub reject_duplicate_keys: bool,
+}
+
+/// How to handle an `&` that isn't part of an entity such as `&amp;`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BareAmpersands {
+    /// Keep the `&` as written, adding a warning to `Config::warnings`
+    #[default]
+    Warn,
+
+    /// Reject the document, as an XML parser would
+    Reject,
+}
+
+/// Dictionary keys whose string values are names or scopes, not free text
+const NAME_KEYS: &[&str] = &["name", "scopeName", "contentName", "scope", "include"];
+
+/// The element type a plist value was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlistType {
+    Dict,
+    Array,
+    String,
+    Real,
+    Integer,
+    Date,
+    Data,
+    Bool,
+}
+
+impl PlistType {
+    /// Get the type of a value-containing tag
+    fn from_tag(name: &str) -> Option<Self> {
+        Some(match name {
+            "dict" => Self::Dict,
+            "array" => Self::Array,
+            "string" => Self::String,
+            "real" => Self::Real,
+            "integer" => Self::Integer,
+            "date" => Self::Date,
+            "data" => Self::Data,
+            "true" | "false" => Self::Bool,
+            _ => return None,
         })
     }
 }
 
 /// Simple plist parser.  Plist is roughly XML files with much of the complexity
 /// of XML skipped.
-struct PlistParser<'a> {
-    chars: CharIndices<'a>,
-    file_name: &'a Path,
+struct PlistParser<I> {
+    cursor: CharCursor<I>,
+
+    file_name: PathBuf,
+    options: PlistOptions,
+
+    /// Path to the value currently being parsed
+    path: Vec<PathSegment>,
+
+    /// Information about each value parsed so far, if `options.keep_types` or
+    /// `options.keep_spans` is set
+    nodes: Vec<NodeInfo>,
+
+    /// Index in `nodes` of each dict or array that hasn't been closed yet
+    open: Vec<usize>,
+
+    /// Close event for a self closing `<dict/>` or `<array/>`, returned by the
+    /// next call to `parse_value` after its open event
+    pending_close: Option<ParserEvent>,
+
+    /// Location of the tag that the last event returned by `parse_value` was
+    /// read from, for errors about where the event is in the document
+    event_start: SourceLocation,
+
+    /// Path of each key found more than once, see `NodeTables::duplicate_keys`
+    duplicate_keys: Vec<Vec<PathSegment>>,
+
+    warnings: Vec<ConfigWarning>,
 }
 
-/// A single XML tag.  if is_closed, the tag counts as self closing
-struct Tag<'a> {
-    name: &'a str,
-    is_closed: bool,
+/// Iterator over the events emitted while parsing a plist document, without
+/// assembling them into a tree.  The document is parsed incrementally as the
+/// iterator is advanced, and iteration stops after the first error.
+pub struct PlistEvents<'a> {
+    parser: PlistParser<Chars<'a>>,
+    done: bool,
 }
 
-/// Events that can be emitted by a file format parser
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum ParserEvent {
-    Value(ConfigTree),
-    EnterDict,
-    Key(String),
-    CloseDict,
-    EnterArray,
-    CloseArray,
-    Eof,
+impl<'a> PlistEvents<'a> {
+    pub(super) fn new(file_name: PathBuf, content: &'a str) -> Self {
+        Self {
+            parser: PlistParser::new(content.chars(), file_name, PlistOptions::default()),
+            done: false,
+        }
+    }
 }
 
-impl<'a> PlistParser<'a> {
+impl Iterator for PlistEvents<'_> {
+    type Item = Result<ParserEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let event = self.parser.parse_value().transpose();
+        if !matches!(event, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        event
+    }
+}
+
+/// Information recorded about a single value while parsing
+struct NodeInfo {
+    path: Vec<PathSegment>,
+    ty: PlistType,
+    span: Span,
+}
+
+/// A single XML tag.  if is_closed, the tag counts as self closing
+struct Tag {
+    name: String,
+    is_closed: bool,
+}
+
+impl<I: Iterator<Item = char>> PlistParser<I> {
     /// Create a new parser
-    fn new(input: &'a str, file_name: &'a Path) -> Self {
-        Self {
-            chars: input.char_indices(),
+    fn new(input: I, file_name: PathBuf, options: PlistOptions) -> Self {
+        let mut parser = Self {
+            cursor: CharCursor::new(input),
             file_name,
+            options,
+            path: vec![],
+            nodes: vec![],
+            open: vec![],
+            pending_close: None,
+            event_start: SourceLocation::default(),
+            duplicate_keys: vec![],
+            warnings: vec![],
+        };
+
+        if parser.cursor.peek() == Some('\u{FEFF}') {
+            parser.cursor.next_char();
         }
+
+        parser
     }
 
     /// Run the parser over the input code
-    fn parse(mut self) -> Result<ConfigTree, Error> {
-        if self.peek() == Some('\u{65279}') {
-            self.chars.next();
+    fn parse(mut self) -> Result<(ConfigTree, NodeTables, Vec<ConfigWarning>), Error> {
+        let tree = self.event_to_value_root()?;
+
+        // sorting by path puts the nodes into node id order
+        self.nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut tables = NodeTables {
+            duplicate_keys: self.duplicate_keys,
+            ..NodeTables::default()
+        };
+        if self.options.keep_types {
+            tables.plist_types = self.nodes.iter().map(|node| node.ty).collect();
         }
+        if self.options.keep_spans {
+            tables.spans = self.nodes.iter().map(|node| node.span).collect();
+        }
+
+        Ok((tree, tables, self.warnings))
+    }
+
+    /// Record the type and start of the value about to be parsed, returning
+    /// its index in `nodes` if anything is being recorded
+    fn record_node(&mut self, ty: PlistType, start: SourceLocation) -> Option<usize> {
+        if !self.options.keep_types && !self.options.keep_spans {
+            return None;
+        }
+
+        self.nodes.push(NodeInfo {
+            path: self.path.clone(),
+            ty,
+            span: Span { start, end: start },
+        });
+        Some(self.nodes.len() - 1)
+    }
+
+    /// Mark the innermost open dict or array as ending at the current location
+    fn close_node(&mut self) {
+        if let Some(node) = self.open.pop() {
+            self.nodes[node].span.end = self.cursor.location();
+        }
+    }
 
-        self.event_to_value_root()
+    /// Forget the nodes recorded before `before` for the value at the current
+    /// path, as it has been overwritten by a later value.
+    fn forget_nodes(&mut self, before: usize) {
+        let mut idx = 0;
+        self.nodes.retain(|node| {
+            idx += 1;
+            idx > before || !node.path.starts_with(&self.path)
+        });
     }
 
     /// Convert the event stream from the parser to a config tree
@@ -77,31 +312,30 @@ impl<'a> PlistParser<'a> {
         let mut val = ConfigTree::Null;
 
         loop {
-            match self.parse_value()? {
-                ParserEvent::Value(tree) => val = tree,
-                ParserEvent::EnterDict => val = self.event_to_value_dict()?,
-                ParserEvent::EnterArray => val = self.event_to_value_array()?,
-
-                ParserEvent::Key(_) => {
-                    return Err(Error::PlistError {
-                        err: "unexpected <key>".into(),
-                        file_name: self.file_name.to_path_buf(),
-                    });
+            let start = self.nodes.len();
+            let event = self.parse_value()?;
+            let event_start = self.event_start;
+
+            let next = match event {
+                Some(ParserEvent::Value(tree)) => tree,
+                Some(ParserEvent::EnterDict) => self.event_to_value_dict()?,
+                Some(ParserEvent::EnterArray) => self.event_to_value_array()?,
+
+                Some(ParserEvent::Key(_)) => {
+                    return Err(self.error_at("unexpected <key>", event_start));
                 }
-                ParserEvent::CloseArray => {
-                    return Err(Error::PlistError {
-                        err: "unexpected </array>".into(),
-                        file_name: self.file_name.to_path_buf(),
-                    });
+                Some(ParserEvent::CloseArray) => {
+                    return Err(self.error_at("unexpected </array>", event_start));
                 }
-                ParserEvent::CloseDict => {
-                    return Err(Error::PlistError {
-                        err: "unexpected </dict>".into(),
-                        file_name: self.file_name.to_path_buf(),
-                    });
+                Some(ParserEvent::CloseDict) => {
+                    return Err(self.error_at("unexpected </dict>", event_start));
                 }
-                ParserEvent::Eof => break,
-            }
+                None => break,
+            };
+
+            // a later root value replaces any earlier ones
+            self.forget_nodes(start);
+            val = next;
         }
 
         Ok(val)
@@ -109,41 +343,60 @@ impl<'a> PlistParser<'a> {
 
     /// Convert the event stream from the parser to a config dictionary
     fn event_to_value_dict(&mut self) -> Result<ConfigTree, Error> {
-        let mut val = HashMap::new();
+        let mut val = IndexMap::new();
         let mut key = None;
+        let mut key_start = SourceLocation::default();
+        let mut start = 0;
 
         loop {
-            let next = match self.parse_value()? {
-                ParserEvent::Value(tree) => tree,
-                ParserEvent::EnterDict => self.event_to_value_dict()?,
-                ParserEvent::EnterArray => self.event_to_value_array()?,
+            let event = self.parse_value()?;
+            let event_start = self.event_start;
+
+            let mut next = match event {
+                Some(ParserEvent::Value(tree)) => tree,
+                Some(ParserEvent::EnterDict) => self.event_to_value_dict()?,
+                Some(ParserEvent::EnterArray) => self.event_to_value_array()?,
 
-                ParserEvent::Key(s) => {
+                Some(ParserEvent::Key(s)) => {
                     if key.is_some() {
-                        return Err(Error::PlistError {
-                            err: "too many <key>".into(),
-                            file_name: self.file_name.to_path_buf(),
-                        });
+                        return Err(self.error_at("too many <key>", event_start));
                     }
+                    start = self.nodes.len();
+                    key_start = event_start;
+                    self.path.push(PathSegment::Key(s.clone()));
                     key = Some(s);
                     continue;
                 }
-                ParserEvent::CloseArray => {
-                    return Err(Error::PlistError {
-                        err: "unexpected </array>".into(),
-                        file_name: self.file_name.to_path_buf(),
-                    });
+                Some(ParserEvent::CloseArray) => {
+                    return Err(self.error_at("unexpected </array>", event_start));
                 }
-                ParserEvent::CloseDict | ParserEvent::Eof => break,
+                Some(ParserEvent::CloseDict) | None => break,
             };
 
-            val.insert(
-                key.take().ok_or_else(|| Error::PlistError {
-                    err: "missing <key>".into(),
-                    file_name: self.file_name.to_path_buf(),
-                })?,
-                next,
-            );
+            let Some(key) = key.take() else {
+                return Err(self.error_at("missing <key>", event_start));
+            };
+
+            if let ConfigTree::String(value) = &mut next
+                && self.options.trim_names
+                && NAME_KEYS.contains(&key.as_str())
+            {
+                *value = value.trim().to_string();
+            }
+
+            if val.contains_key(&key) {
+                if self.options.reject_duplicate_keys {
+                    return Err(self.error_at(format!("duplicate key `{key}`"), key_start));
+                }
+                self.forget_nodes(start);
+                self.duplicate_keys.push(self.path.clone());
+            }
+            self.path.pop();
+            val.insert(key, next);
+        }
+
+        if key.is_some() {
+            self.path.pop();
         }
 
         Ok(ConfigTree::Object(val))
@@ -154,44 +407,57 @@ impl<'a> PlistParser<'a> {
         let mut val = vec![];
 
         loop {
-            match self.parse_value()? {
-                ParserEvent::Value(tree) => val.push(tree),
-                ParserEvent::EnterDict => val.push(self.event_to_value_dict()?),
-                ParserEvent::EnterArray => val.push(self.event_to_value_array()?),
-
-                ParserEvent::Key(_) => {
-                    return Err(Error::PlistError {
-                        err: "unexpected <key>".into(),
-                        file_name: self.file_name.to_path_buf(),
-                    });
+            // the index stays on the path until any nested dict or array has
+            // been parsed, so that the values within it get the right paths
+            self.path.push(PathSegment::Index(val.len()));
+
+            let event = self.parse_value()?;
+            let event_start = self.event_start;
+
+            let next = match event {
+                Some(ParserEvent::Value(tree)) => tree,
+                Some(ParserEvent::EnterDict) => self.event_to_value_dict()?,
+                Some(ParserEvent::EnterArray) => self.event_to_value_array()?,
+
+                Some(ParserEvent::Key(_)) => {
+                    return Err(self.error_at("unexpected <key>", event_start));
                 }
-                ParserEvent::CloseDict => {
-                    return Err(Error::PlistError {
-                        err: "unexpected </dict>".into(),
-                        file_name: self.file_name.to_path_buf(),
-                    });
+                Some(ParserEvent::CloseDict) => {
+                    return Err(self.error_at("unexpected </dict>", event_start));
                 }
-                ParserEvent::CloseArray | ParserEvent::Eof => break,
-            }
+                Some(ParserEvent::CloseArray) | None => {
+                    self.path.pop();
+                    break;
+                }
+            };
+
+            self.path.pop();
+            val.push(next);
         }
 
         Ok(ConfigTree::Array(val))
     }
 
-    /// Accept any single value from the input
-    fn parse_value(&mut self) -> Result<ParserEvent, Error> {
+    /// Accept any single value from the input, returns None at the end of input
+    fn parse_value(&mut self) -> Result<Option<ParserEvent>, Error> {
+        if let Some(event) = self.pending_close.take() {
+            self.close_node();
+            return Ok(Some(event));
+        }
+
         loop {
-            self.skip_whitespace();
+            self.cursor.skip_whitespace();
 
-            let Some((_, next)) = self.chars.next() else {
-                return Ok(ParserEvent::Eof);
+            let start = self.cursor.location();
+            let Some(next) = self.cursor.next_char() else {
+                return Ok(None);
             };
 
             if next != '<' {
-                return Err(self.error("expected <"));
+                return Err(self.error_at("expected <", start));
             }
 
-            let Some(peek) = self.peek() else {
+            let Some(peek) = self.cursor.peek() else {
                 return Err(self.error("unexpected end of input"));
             };
 
@@ -200,210 +466,1010 @@ impl<'a> PlistParser<'a> {
                 // once the comment-like is matched then it goes back to the start
                 // of the loop and tries to match a new piece of syntax.
                 '?' => {
-                    self.chars.next();
-                    self.advance_until("?>");
+                    self.cursor.next_char();
+                    self.cursor.advance_until("?>");
                 }
                 '!' => {
-                    self.chars.next();
-                    if self.chars.as_str().starts_with("--") {
-                        self.advance_until("-->");
+                    self.cursor.next_char();
+                    if self.cursor.starts_with("--") {
+                        self.cursor.advance_until("-->");
+                    } else if self.cursor.starts_with("[CDATA[") {
+                        self.cursor.advance_until("]]>");
                     } else {
-                        self.advance_until(">");
+                        self.cursor.advance_until(">");
                     }
                 }
                 '/' => {
-                    self.chars.next();
-                    self.skip_whitespace();
-                    if self.chars.as_str().starts_with("plist") {
-                        self.advance_until(">");
-                    } else if self.chars.as_str().starts_with("dict") {
-                        self.advance_until(">");
-                        return Ok(ParserEvent::CloseDict);
-                    } else if self.chars.as_str().starts_with("array") {
-                        self.advance_until(">");
-                        return Ok(ParserEvent::CloseArray);
+                    self.cursor.next_char();
+                    self.cursor.skip_whitespace();
+                    if self.cursor.starts_with("plist") {
+                        self.cursor.advance_until(">");
+                    } else if self.cursor.starts_with("dict") {
+                        self.cursor.advance_until(">");
+                        self.close_node();
+                        self.event_start = start;
+                        return Ok(Some(ParserEvent::CloseDict));
+                    } else if self.cursor.starts_with("array") {
+                        self.cursor.advance_until(">");
+                        self.close_node();
+                        self.event_start = start;
+                        return Ok(Some(ParserEvent::CloseArray));
                     } else {
-                        return Err(self.error("unexpected closed tag"));
+                        return Err(self.error_at("unexpected closed tag", start));
                     }
                 }
                 // parse actual tags
-                _ => return self.parse_tag(),
+                _ => return self.parse_tag(start),
             }
         }
     }
 
-    /// Parse the content of an actual value-containing XML tag
-    fn parse_tag(&mut self) -> Result<ParserEvent, Error> {
+    /// Parse the content of an actual value-containing XML tag, whose opening
+    /// `<` was at `start`
+    fn parse_tag(&mut self, start: SourceLocation) -> Result<Option<ParserEvent>, Error> {
+        self.event_start = start;
         let tag = self.parse_open_tag();
 
-        match tag.name {
-            "dict" => Ok(ParserEvent::EnterDict),
-            "array" => Ok(ParserEvent::EnterArray),
-            "key" => Ok(ParserEvent::Key(self.parse_tag_value(tag)?)),
+        let node = PlistType::from_tag(&tag.name).and_then(|ty| self.record_node(ty, start));
+
+        let event = match tag.name.as_str() {
+            // self closing containers are closed straight after being opened
+            "dict" if tag.is_closed => {
+                self.pending_close = Some(ParserEvent::CloseDict);
+                Ok(Some(ParserEvent::EnterDict))
+            }
+            "array" if tag.is_closed => {
+                self.pending_close = Some(ParserEvent::CloseArray);
+                Ok(Some(ParserEvent::EnterArray))
+            }
+            "dict" => Ok(Some(ParserEvent::EnterDict)),
+            "array" => Ok(Some(ParserEvent::EnterArray)),
+            "key" => Ok(Some(ParserEvent::Key(self.parse_tag_value(tag)?))),
 
-            "string" | "real" | "integer" | "date" | "data" => Ok(ParserEvent::Value(
-                ConfigTree::String(self.parse_tag_value(tag)?),
-            )),
-            "true" => Ok(ParserEvent::Value(ConfigTree::Bool(true))),
-            "false" => Ok(ParserEvent::Value(ConfigTree::Bool(false))),
+            "integer" => {
+                let value = self.parse_tag_value(tag)?;
+                match value.trim().parse() {
+                    Ok(value) => Ok(Some(ParserEvent::Value(ConfigTree::Integer(value)))),
+                    Err(_) => Err(self.error_at(format!("invalid integer {value:?}"), start)),
+                }
+            }
+            "real" => {
+                let value = self.parse_tag_value(tag)?;
+                match value.trim().parse() {
+                    Ok(value) => Ok(Some(ParserEvent::Value(ConfigTree::Float(value)))),
+                    Err(_) => Err(self.error_at(format!("invalid real {value:?}"), start)),
+                }
+            }
+            "data" => {
+                let value = self.parse_tag_value(tag)?;
+                match decode_base64(&value) {
+                    Ok(value) => Ok(Some(ParserEvent::Value(ConfigTree::Data(value)))),
+                    Err(err) => Err(self.error_at(err, start)),
+                }
+            }
+            "date" => {
+                let value = self.parse_tag_value(tag)?;
+                match check_date(value.trim()) {
+                    Ok(()) => Ok(Some(ParserEvent::Value(ConfigTree::Date(
+                        value.trim().to_string(),
+                    )))),
+                    Err(err) => Err(self.error_at(err, start)),
+                }
+            }
+            "string" => Ok(Some(ParserEvent::Value(ConfigTree::String(
+                self.parse_tag_value(tag)?,
+            )))),
+            "true" => Ok(Some(ParserEvent::Value(ConfigTree::Bool(true)))),
+            "false" => Ok(Some(ParserEvent::Value(ConfigTree::Bool(false)))),
             _ if tag.name.starts_with("plist") => self.parse_value(),
             tag => {
                 let tag = tag.to_string();
-                Err(self.error(format!("unexpected opened tag {tag}")))
+                Err(self.error_at(format!("unexpected opened tag {tag}"), start))
+            }
+        };
+
+        // containers end at their close tag, everything else ends here
+        if let Some(node) = node {
+            match event {
+                Ok(Some(ParserEvent::EnterDict | ParserEvent::EnterArray)) => self.open.push(node),
+                _ => self.nodes[node].span.end = self.cursor.location(),
             }
         }
+
+        event
     }
 
     /// Parse an XML tag starting directly after the opening `<`.
-    fn parse_open_tag(&mut self) -> Tag<'a> {
-        let mut res = self.capture_until(">");
+    fn parse_open_tag(&mut self) -> Tag {
+        let mut name = self.cursor.capture_until(">");
 
-        let mut is_closed = false;
-        if res.ends_with('/') {
-            res = &res[0..res.len() - 1];
-            is_closed = true;
+        let is_closed = name.ends_with('/');
+        if is_closed {
+            name.pop();
         }
 
-        Tag {
-            name: res,
-            is_closed,
-        }
+        Tag { name, is_closed }
     }
 
-    /// Parse the text value within a single XML tag
-    fn parse_tag_value(&mut self, tag: Tag<'a>) -> Result<String, Error> {
+    /// Parse the text value within a single XML tag.  The text may contain
+    /// CDATA sections, whose content is kept exactly as written, without
+    /// decoding entities.
+    fn parse_tag_value(&mut self, tag: Tag) -> Result<String, Error> {
         if tag.is_closed {
             return Ok(String::new());
         }
 
-        let val = self.capture_until("</");
-        self.advance_until(">");
+        let mut value = String::new();
+        let mut text = String::new();
+        let mut start = self.cursor.location();
+
+        loop {
+            text.push_str(&self.cursor.capture_until("<"));
+
+            if self.cursor.starts_with("![CDATA[") {
+                self.append_text(&mut value, &text, start)?;
+                text.clear();
+
+                self.cursor.advance_until("![CDATA[");
+                value.push_str(&self.cursor.capture_until("]]>"));
+                start = self.cursor.location();
+            } else if self.cursor.starts_with("/") || self.cursor.peek().is_none() {
+                break;
+            } else {
+                // a stray `<` within the text, kept as written
+                text.push('<');
+            }
+        }
+
+        self.cursor.advance_until(">");
+        self.append_text(&mut value, &text, start)?;
 
-        self.escape_value(val)
+        Ok(value)
     }
 
-    fn escape_value(&self, input: &str) -> Result<String, Error> {
-        static DECIMAL: LazyLock<Regex> = LazyLock::new(|| Regex::new("&#([0-9]+);").unwrap());
-        static HEX: LazyLock<Regex> = LazyLock::new(|| Regex::new("&#x([0-9a-f]+);").unwrap());
-        static OTHER: LazyLock<Regex> =
-            LazyLock::new(|| Regex::new("&amp;|&lt;|&gt;|&quot;|&apos;").unwrap());
+    /// Decode the raw text of a tag, which started at `start` in the input, and
+    /// add it to the tag's value
+    fn append_text(
+        &mut self,
+        value: &mut String,
+        text: &str,
+        start: SourceLocation,
+    ) -> Result<(), Error> {
+        self.check_ampersands(text, start)?;
 
-        let input = replace_all(&DECIMAL, input, |cap: &Captures<'_>| {
-            let cap = cap.at(1).unwrap_or("");
-            let num = cap
-                .parse::<u32>()
-                .map_err(|_| self.error("unable to parse decimal number"))?;
-            Ok(String::from(
-                char::from_u32(num).ok_or_else(|| self.error("invalid decimal escape"))?,
-            ))
+        let decoded = escape_value(text).map_err(|(pos, err)| {
+            let mut location = start;
+            text[..pos].chars().for_each(|c| location.advance(c));
+            self.error_at(err, location)
         })?;
+        value.push_str(&decoded);
+        Ok(())
+    }
 
-        let input = replace_all(&HEX, &input, |cap: &Captures<'_>| {
-            let cap = cap.at(1).unwrap_or("");
-            let num = u32::from_str_radix(cap, 16)
-                .map_err(|_| self.error("unable to parse hex number"))?;
-            Ok(String::from(
-                char::from_u32(num).ok_or_else(|| self.error("invalid hex escape"))?,
-            ))
-        })?;
+    /// Look for an `&` that doesn't start an entity within the raw text of a
+    /// tag, which started at `start` in the input.
+    fn check_ampersands(&mut self, val: &str, start: SourceLocation) -> Result<(), Error> {
+        static BARE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new("&(?!#[0-9]+;|#x[0-9a-fA-F]+;|amp;|lt;|gt;|quot;|apos;)").unwrap()
+        });
 
-        replace_all(&OTHER, &input, |cap: &Captures<'_>| {
-            let item = match cap.at(1).unwrap_or("") {
-                "&amp;" => "&",
-                "&lt;" => "<",
-                "&gt;" => ">",
-                "&quot;" => "\"",
-                "&apos;" => "\\",
-                _ => return Err(self.error("internal regex error")),
-            };
+        let Some((pos, _)) = BARE.find(val) else {
+            return Ok(());
+        };
 
-            Ok(String::from(item))
-        })
-    }
+        let mut location = start;
+        val[..pos].chars().for_each(|c| location.advance(c));
 
-    /// Skip any whitespace characters
-    fn skip_whitespace(&mut self) {
-        while let Some(peek) = self.peek() {
-            if !" \t\r\n".contains(peek) {
-                break;
+        match self.options.bare_ampersands {
+            BareAmpersands::Warn => {
+                self.warnings.push(ConfigWarning {
+                    message: "unescaped `&`, it should be written as `&amp;`".into(),
+                    location: Some(location),
+                });
+                Ok(())
             }
+            BareAmpersands::Reject => Err(Error::PlistError {
+                err: "unescaped `&`, it should be written as `&amp;`".into(),
+                file_name: self.file_name.clone(),
+                location: Some(location),
+            }),
+        }
+    }
+
+    /// Create an error message for a problem at the current location
+    fn error(&mut self, err: impl Into<String>) -> Error {
+        let location = self.cursor.location();
+        self.error_at(err, location)
+    }
 
-            self.chars.next();
+    /// Create an error message for a problem at an earlier location, such as
+    /// the start of an invalid tag.  The message still shows the text following
+    /// the current location.
+    fn error_at(&mut self, err: impl Into<String>, location: SourceLocation) -> Error {
+        let sample = self.cursor.sample(50);
+
+        let err = if sample.is_empty() {
+            format!("at file end: {}", err.into())
+        } else {
+            format!("{} ~~~ {} ~~~", err.into(), sample)
+        };
+
+        Error::PlistError {
+            err,
+            file_name: self.file_name.clone(),
+            location: Some(location),
         }
     }
+}
 
-    /// advance until the next occurrence of the provided string, including the
-    /// provided input.
-    fn advance_until(&mut self, search: &str) {
-        loop {
-            if self.chars.as_str().starts_with(search) {
-                break;
+/// Decode the XML entities within a tag's text.  All entities are decoded in a
+/// single pass, so the output of one (`&#38;` is `&`) never starts another.
+/// Characters outside the basic multilingual plane can be written either as a
+/// single reference (`&#128512;`) or as the two halves of a UTF-16 surrogate
+/// pair (`&#55357;&#56832;`), which must directly follow each other.  Errors
+/// give the byte offset within `input` of the reference that was invalid.
+fn escape_value(input: &str) -> Result<String, (usize, &'static str)> {
+    static ENTITY: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new("&#([0-9]+);|&#x([0-9a-fA-F]+);|&(amp|lt|gt|quot|apos);").unwrap()
+    });
+
+    let mut out = String::with_capacity(input.len());
+    let mut last_match = 0;
+
+    // first half of a surrogate pair, and where its reference started, waiting
+    // for the second half
+    let mut high: Option<(u32, usize)> = None;
+
+    for cap in ENTITY.captures_iter(input) {
+        let Some((start, end)) = cap.pos(0) else {
+            continue;
+        };
+
+        if let Some((_, high_start)) = high
+            && start != last_match
+        {
+            return Err((high_start, UNPAIRED_SURROGATE));
+        }
+
+        out.push_str(&input[last_match..start]);
+        last_match = end;
+
+        let num = if let Some(decimal) = cap.at(1) {
+            decimal
+                .parse::<u32>()
+                .map_err(|_| (start, "unable to parse decimal number"))?
+        } else if let Some(hex) = cap.at(2) {
+            u32::from_str_radix(hex, 16).map_err(|_| (start, "unable to parse hex number"))?
+        } else {
+            if let Some((_, high_start)) = high {
+                return Err((high_start, UNPAIRED_SURROGATE));
             }
 
-            if self.chars.next().is_none() {
-                return;
+            out.push(match cap.at(3).unwrap_or("") {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                _ => return Err((start, "internal regex error")),
+            });
+            continue;
+        };
+
+        match (high.take(), num) {
+            (None, 0xD800..=0xDBFF) => high = Some((num, start)),
+            (Some((high, high_start)), 0xDC00..=0xDFFF) => {
+                let num = 0x10000 + ((high - 0xD800) << 10) + (num - 0xDC00);
+                out.push(char_reference(num).map_err(|err| (high_start, err))?);
             }
+            (Some((_, high_start)), _) => return Err((high_start, UNPAIRED_SURROGATE)),
+            (None, _) => out.push(char_reference(num).map_err(|err| (start, err))?),
         }
+    }
 
-        self.chars.nth(search.len() - 1);
+    if let Some((_, high_start)) = high {
+        return Err((high_start, UNPAIRED_SURROGATE));
     }
 
-    /// Same as advance_until, but returns the text content that was advanced over
-    fn capture_until(&mut self, search: &str) -> &'a str {
-        let res = self.chars.as_str();
-        let start_offset = self.chars.offset();
-        self.advance_until(search);
-        let end_offset = self.chars.offset() - search.len();
+    out.push_str(&input[last_match..]);
+    Ok(out)
+}
 
-        &res[0..end_offset - start_offset]
+const UNPAIRED_SURROGATE: &str = "character reference to an unpaired UTF-16 surrogate";
+
+/// Check that the contents of a `<date>` tag are a UTC timestamp in the form
+/// `YYYY-MM-DDTHH:MM:SSZ`, the only form that plists use
+fn check_date(input: &str) -> Result<(), &'static str> {
+    const INVALID: &str = "invalid date, expected YYYY-MM-DDTHH:MM:SSZ";
+
+    let bytes = input.as_bytes();
+    if bytes.len() != 20 {
+        return Err(INVALID);
     }
 
-    /// Return the next character in the stream
-    fn peek(&self) -> Option<char> {
-        self.chars.clone().next().map(|(_, c)| c)
+    for (i, &byte) in bytes.iter().enumerate() {
+        let ok = match i {
+            4 | 7 => byte == b'-',
+            10 => byte == b'T',
+            13 | 16 => byte == b':',
+            19 => byte == b'Z',
+            _ => byte.is_ascii_digit(),
+        };
+        if !ok {
+            return Err(INVALID);
+        }
     }
 
-    /// Create an error message
-    fn error(&self, err: impl Into<String>) -> Error {
-        let sample: String = self.chars.clone().take(50).map(|(_, c)| c).collect();
+    // all fields are digits, checked above
+    let field = |range: std::ops::Range<usize>| input[range].parse::<u32>().unwrap();
+    let (year, month, day) = (field(0..4), field(5..7), field(8..10));
+    let (hour, minute, second) = (field(11..13), field(14..16), field(17..19));
 
-        let err = if sample.is_empty() {
-            format!("at file end: {} ", err.into())
-        } else {
-            format!(
-                "near offset {}: {} ~~~ {} ~~~",
-                self.chars.offset(),
-                err.into(),
-                sample
-            )
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err("invalid month in date"),
+    };
+
+    if day == 0 || day > days {
+        return Err("invalid day in date");
+    }
+
+    // allow for leap seconds
+    if hour > 23 || minute > 59 || second > 60 {
+        return Err("invalid time in date");
+    }
+
+    Ok(())
+}
+
+/// Decode the base64 contents of a `<data>` tag.  Whitespace is ignored
+/// anywhere in the input, as plists wrap long data over many lines, and the
+/// trailing `=` padding is optional.
+fn decode_base64(input: &str) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    let mut padding = 0;
+
+    for c in input.chars().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            '=' => {
+                padding += 1;
+                continue;
+            }
+            _ => return Err("invalid character in base64 data"),
         };
 
-        Error::PlistError {
-            err,
-            file_name: self.file_name.to_path_buf(),
+        if padding > 0 {
+            return Err("base64 data continues after padding");
+        }
+
+        bits = bits << 6 | value;
+        count += 1;
+        if count == 4 {
+            out.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+            count = 0;
         }
     }
+
+    // leftover characters hold a partial group of one or two bytes
+    match count {
+        0 => (),
+        2 => out.push((bits >> 4) as u8),
+        3 => out.extend_from_slice(&((bits >> 2) as u16).to_be_bytes()),
+        _ => return Err("truncated base64 data"),
+    }
+
+    if padding > 2 || (padding > 0 && (count + padding) % 4 != 0) {
+        return Err("invalid base64 padding");
+    }
+
+    Ok(out)
+}
+
+/// Convert the value of a numeric character reference into the character it
+/// refers to, rejecting surrogate code points as they are not characters on
+/// their own.
+fn char_reference(num: u32) -> Result<char, &'static str> {
+    match num {
+        0xD800..=0xDFFF => Err(UNPAIRED_SURROGATE),
+        _ => char::from_u32(num).ok_or("character reference out of unicode range"),
+    }
 }
 
-/// Wrapper for regex replace that allows a replacement to fail
-fn replace_all<E>(
-    re: &Regex,
-    input: &str,
-    replace: impl Fn(&Captures) -> Result<String, E>,
-) -> Result<String, E> {
-    let mut new = String::with_capacity(input.len());
-    let mut last_match = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for caps in re.captures_iter(input) {
-        let Some((start, end)) = caps.pos(0) else {
-            continue;
+    fn parse(content: &str, options: PlistOptions) -> Config {
+        Config::from_plist_with_options("test.plist", content, options).unwrap()
+    }
+
+    fn get<'a>(tree: &'a ConfigTree, key: &str) -> &'a ConfigTree {
+        match tree {
+            ConfigTree::Object(value) => &value[key],
+            _ => panic!("expected an object"),
+        }
+    }
+
+    fn error_location(content: &str) -> (usize, usize, String) {
+        error_location_with_options(content, PlistOptions::default())
+    }
+
+    fn error_location_with_options(content: &str, options: PlistOptions) -> (usize, usize, String) {
+        match Config::from_plist_with_options("test.plist", content, options) {
+            Err(Error::PlistError {
+                err,
+                location: Some(location),
+                ..
+            }) => (location.line, location.column, err),
+            other => panic!("expected a plist error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn keep_types_of_scalars() {
+        let content = "<plist><dict>\
+            <key>a</key><real>23.4</real>\
+            <key>b</key><string>23.4</string>\
+            <key>c</key><integer>3</integer>\
+            <key>d</key><true/>\
+            </dict></plist>";
+        let options = PlistOptions {
+            keep_types: true,
+            ..PlistOptions::default()
         };
+        let cfg = parse(content, options);
 
-        new.push_str(&input[last_match..start]);
-        new.push_str(&replace(&caps)?);
-        last_match = end;
+        assert_eq!(cfg.plist_type_of(ConfigNodeID(0)), Some(PlistType::Dict));
+        let cases = [
+            ("a", PlistType::Real),
+            ("b", PlistType::String),
+            ("c", PlistType::Integer),
+            ("d", PlistType::Bool),
+        ];
+        for (key, ty) in cases {
+            let id = cfg.node_id(get(cfg.tree(), key)).unwrap();
+            assert_eq!(cfg.plist_type_of(id), Some(ty), "type of `{key}`");
+        }
+    }
+
+    #[test]
+    fn types_not_kept_by_default() {
+        let cfg = parse(
+            "<plist><dict><key>a</key><real>1</real></dict></plist>",
+            PlistOptions::default(),
+        );
+        assert_eq!(cfg.plist_type_of(ConfigNodeID(0)), None);
+    }
+
+    #[test]
+    fn parse_from_chunked_chars() {
+        let content = "<?xml version=\"1.0\"?>\n<plist><dict>\
+            <key>name</key><string>a &#38; b</string>\
+            <key>patterns</key><array><dict><key>match</key><string>x+</string></dict></array>\
+            </dict></plist>";
+
+        // split into chunks that fall within tags and entities
+        let chunks: Vec<String> = content
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(7)
+            .map(|chunk| chunk.iter().collect())
+            .collect();
+        let chars = chunks.iter().flat_map(|chunk| chunk.chars());
+
+        let streamed =
+            Config::from_plist_chars("test.plist", chars, PlistOptions::default()).unwrap();
+        let whole = Config::from_plist("test.plist", content).unwrap();
+        assert_eq!(streamed, whole);
+        assert_eq!(
+            streamed.tree()["name"],
+            ConfigTree::String("a & b".to_string())
+        );
+    }
+
+    #[test]
+    fn char_references() {
+        let string = |value: &str| {
+            Config::from_plist(
+                "test.plist",
+                format!("<plist><string>{value}</string></plist>"),
+            )
+        };
+
+        assert_eq!(
+            string("&#128512;&#x1F600;&#x1f600;").unwrap().tree(),
+            &ConfigTree::String("\u{1F600}".repeat(3))
+        );
+
+        for surrogate in ["&#55296;", "&#xD800;", "&#xdfff;"] {
+            let Err(Error::PlistError { err, .. }) = string(surrogate) else {
+                panic!("`{surrogate}` should be rejected");
+            };
+            assert!(err.contains("surrogate"), "{err}");
+        }
+    }
+
+    #[test]
+    fn error_has_location() {
+        let err =
+            Config::from_plist("test.plist", "<plist>\n<array></dict>\n</plist>").unwrap_err();
+        let Error::PlistError {
+            location: Some(location),
+            ..
+        } = &err
+        else {
+            panic!("expected a plist error with a location, got {err:?}");
+        };
+        assert_eq!(location.line, 2);
+        assert!(err.to_string().contains("`test.plist` at 2:"), "{err}");
+    }
+
+    #[test]
+    fn trim_name_strings() {
+        let content = "<plist><dict>\
+            <key>name</key><string> comment.line </string>\
+            <key>include</key><string>\n\t#string\n</string>\
+            <key>match</key><string> # </string>\
+            </dict></plist>";
+
+        let options = PlistOptions {
+            trim_names: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(content, options);
+        let string = |s: &str| ConfigTree::String(s.to_string());
+        assert_eq!(get(cfg.tree(), "name"), &string("comment.line"));
+        assert_eq!(get(cfg.tree(), "include"), &string("#string"));
+        assert_eq!(get(cfg.tree(), "match"), &string(" # "));
+
+        let cfg = parse(content, PlistOptions::default());
+        assert_eq!(get(cfg.tree(), "name"), &string(" comment.line "));
+    }
+
+    #[test]
+    fn bare_ampersand_warning() {
+        let content = "<plist><dict>\n<key>a</key><string>x & y</string>\n</dict></plist>";
+        let cfg = parse(content, PlistOptions::default());
+
+        assert_eq!(
+            get(cfg.tree(), "a"),
+            &ConfigTree::String("x & y".to_string())
+        );
+        assert_eq!(cfg.warnings().len(), 1);
+
+        let location = cfg.warnings()[0].location.unwrap();
+        assert_eq!(
+            location,
+            SourceLocation::from_offset(content, content.find('&').unwrap())
+        );
+        assert_eq!((location.line, location.column), (2, 23));
+    }
+
+    #[test]
+    fn bare_ampersand_rejected() {
+        let options = PlistOptions {
+            bare_ampersands: BareAmpersands::Reject,
+            ..PlistOptions::default()
+        };
+        let content = "<plist><string>x & y</string></plist>";
+        let err = Config::from_plist_with_options("test.plist", content, options).unwrap_err();
+        assert!(matches!(err, Error::PlistError { .. }), "{err:?}");
+    }
+
+    #[test]
+    fn escaped_ampersands_allowed() {
+        let options = PlistOptions {
+            bare_ampersands: BareAmpersands::Reject,
+            ..PlistOptions::default()
+        };
+        for content in ["&#38;", "&#x26;"] {
+            let content = format!("<plist><string>x {content} y</string></plist>");
+
+            let cfg = parse(&content, PlistOptions::default());
+            assert_eq!(cfg.tree(), &ConfigTree::String("x & y".to_string()));
+            assert!(cfg.warnings().is_empty(), "{:?}", cfg.warnings());
+
+            assert!(Config::from_plist_with_options("test.plist", &content, options).is_ok());
+        }
+    }
+
+    #[test]
+    fn mixed_scalar_array() {
+        let content = "<plist><dict>\
+            <key>fileTypes</key>\
+            <array>\
+                <string>Makefile</string>\
+                <true/>\
+                <false/>\
+                <integer>57</integer>\
+                <real>23.4</real>\
+                <string>mk</string>\
+            </array>\
+            <key>name</key><string>Makefile</string>\
+            </dict></plist>";
+        let cfg = parse(content, PlistOptions::default());
+
+        let expected = [
+            ConfigTree::String("Makefile".to_string()),
+            ConfigTree::Bool(true),
+            ConfigTree::Bool(false),
+            ConfigTree::Integer(57),
+            ConfigTree::Float(23.4),
+            ConfigTree::String("mk".to_string()),
+        ];
+        assert_eq!(
+            get(cfg.tree(), "fileTypes"),
+            &ConfigTree::Array(expected.to_vec())
+        );
+    }
+
+    #[test]
+    fn self_closing_containers() {
+        let content = "<plist><dict>\
+            <key>a</key><dict/>\
+            <key>b</key><array/>\
+            <key>c</key><string>x</string>\
+            </dict></plist>";
+        let cfg = parse(content, PlistOptions::default());
+
+        let expected = Config::from_json("test.json", r#"{"a": {}, "b": [], "c": "x"}"#).unwrap();
+        assert_eq!(cfg.tree(), expected.tree());
+    }
+
+    #[test]
+    fn syntax_error_display() {
+        let err =
+            Config::from_plist("Makefile.plist", "<plist><array></dict></plist>").unwrap_err();
+        assert!(matches!(err, Error::PlistError { .. }), "{err:?}");
+
+        let message = err.to_string();
+        assert!(
+            message.starts_with("Error while parsing plist file `Makefile.plist`"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn named_entities() {
+        let options = PlistOptions {
+            bare_ampersands: BareAmpersands::Reject,
+            ..PlistOptions::default()
+        };
+        let content = "<plist><string>&lt;a href=&quot;x&apos;&quot;&gt; &amp; &amp;#38; &#38;amp;</string></plist>";
+        let cfg = parse(content, options);
+
+        assert_eq!(
+            cfg.tree(),
+            &ConfigTree::String("<a href=\"x'\"> & &#38; &amp;".to_string())
+        );
+        assert!(cfg.warnings().is_empty(), "{:?}", cfg.warnings());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let content = "<plist><dict>\
+            <key>name</key><string>a</string>\
+            <key>name</key><string>b</string>\
+            </dict></plist>";
+
+        let cfg = parse(content, PlistOptions::default());
+        assert_eq!(
+            get(cfg.tree(), "name"),
+            &ConfigTree::String("b".to_string())
+        );
+
+        let options = PlistOptions {
+            reject_duplicate_keys: true,
+            ..PlistOptions::default()
+        };
+        let err = Config::from_plist_with_options("test.plist", content, options).unwrap_err();
+        let Error::PlistError { err, .. } = err else {
+            panic!("expected a plist error, got {err:?}");
+        };
+        assert!(err.contains("duplicate key `name`"), "{err}");
+    }
+
+    #[test]
+    fn keep_spans_of_values() {
+        let content =
+            "<plist>\n<dict>\n  <key>name</key>\n  <string>Makefile</string>\n</dict>\n</plist>";
+        let options = PlistOptions {
+            keep_spans: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(content, options);
+
+        let root = cfg.span_of(ConfigNodeID(0)).unwrap();
+        assert_eq!(
+            root.text(content),
+            Some("<dict>\n  <key>name</key>\n  <string>Makefile</string>\n</dict>")
+        );
+
+        let id = cfg.node_id(get(cfg.tree(), "name")).unwrap();
+        let span = cfg.span_of(id).unwrap();
+        assert_eq!(span.text(content), Some("<string>Makefile</string>"));
+        assert_eq!((span.start.line, span.start.column), (4, 3));
+
+        let cfg = parse(content, PlistOptions::default());
+        assert_eq!(cfg.span_of(ConfigNodeID(0)), None);
+    }
+
+    #[test]
+    fn data_line_wrapped_base64() {
+        let content = "<plist><data>\n\taGVsbG8g\n\td29ybGQ=\n</data></plist>";
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+        assert_eq!(cfg.tree(), &ConfigTree::Data(b"hello world".to_vec()));
+
+        let content = "<plist>\n<array>\n  <data>aGV*bG8=</data>\n</array>\n</plist>";
+        let (line, column, _) = error_location(content);
+        assert_eq!((line, column), (3, 3));
+    }
+
+    #[test]
+    fn date_validated() {
+        let content = "<plist><date>2024-02-29T13:05:09Z</date></plist>";
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+        assert_eq!(
+            cfg.tree(),
+            &ConfigTree::Date("2024-02-29T13:05:09Z".to_string())
+        );
+
+        let content = "<plist>\n<dict>\n<key>a</key> <date>yesterday</date>\n</dict>\n</plist>";
+        let (line, column, _) = error_location(content);
+        assert_eq!((line, column), (3, 14));
+    }
+
+    #[test]
+    fn plist_events_for_makefile_grammar() {
+        let content = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<plist version=\"1.0\">\n<dict>\n\
+            <key>name</key><string>Makefile</string>\n\
+            <key>fileTypes</key><array><string>Makefile</string><string>mk</string></array>\n\
+            <key>patterns</key><array><dict>\
+            <key>match</key><string>#.*</string>\
+            <key>name</key><string>comment.line.number-sign.makefile</string>\
+            </dict></array>\n\
+            <key>scopeName</key><string>source.makefile</string>\n\
+            </dict>\n</plist>";
+
+        let events = Config::plist_events("Makefile.plist", content)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let key = |key: &str| ParserEvent::Key(key.to_string());
+        let string = |s: &str| ParserEvent::Value(ConfigTree::String(s.to_string()));
+        let expected = [
+            ParserEvent::EnterDict,
+            key("name"),
+            string("Makefile"),
+            key("fileTypes"),
+            ParserEvent::EnterArray,
+            string("Makefile"),
+            string("mk"),
+            ParserEvent::CloseArray,
+            key("patterns"),
+            ParserEvent::EnterArray,
+            ParserEvent::EnterDict,
+            key("match"),
+            string("#.*"),
+            key("name"),
+            string("comment.line.number-sign.makefile"),
+            ParserEvent::CloseDict,
+            ParserEvent::CloseArray,
+            key("scopeName"),
+            string("source.makefile"),
+            ParserEvent::CloseDict,
+        ];
+        assert_eq!(events.len(), 20);
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn cdata_kept_verbatim() {
+        let cases = [
+            ("<![CDATA[a<b&c]]>", "a<b&c"),
+            ("x&amp;<![CDATA[&]]>", "x&&"),
+            ("<![CDATA[&amp;]]> &lt;", "&amp; <"),
+        ];
+
+        for (text, expected) in cases {
+            let content = format!("<plist><string>{text}</string></plist>");
+            let cfg = parse(&content, PlistOptions::default());
+            assert_eq!(
+                cfg.tree(),
+                &ConfigTree::String(expected.to_string()),
+                "{text}"
+            );
+            assert!(cfg.warnings().is_empty(), "{text}: {:?}", cfg.warnings());
+        }
+
+        let content = "<plist><![CDATA[ <dict> ]]><string>x</string></plist>";
+        let cfg = parse(content, PlistOptions::default());
+        assert_eq!(cfg.tree(), &ConfigTree::String("x".to_string()));
+    }
+
+    #[test]
+    fn surrogate_pair_references() {
+        let content = "<plist><string>a&#55357;&#56832;b&#xD83D;&#xDE00;</string></plist>";
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+        assert_eq!(cfg.tree().as_str(), Some("a\u{1F600}b\u{1F600}"));
+
+        // a high surrogate must be followed by a low surrogate
+        let content = "<plist>\n<string>ab&#55357;&#65;</string>\n</plist>";
+        let (line, column, err) = error_location(content);
+        assert_eq!((line, column), (2, 11));
+        assert!(err.starts_with(UNPAIRED_SURROGATE), "{err}");
     }
 
-    new.push_str(&input[last_match..]);
-    Ok(new)
+    #[test]
+    fn error_on_line_three() {
+        let content = "<plist>\n<dict>\n    <key>a</key><integer>nope</integer>\n</dict>\n</plist>";
+        let (line, column, _) = error_location(content);
+        assert_eq!((line, column), (3, 17));
+
+        let message = Config::from_plist("a.plist", content)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.contains(&format!("`a.plist` at 3:{column}:")),
+            "{message}"
+        );
+    }
+
+    const NESTED: &str = "<plist><array>\
+        <dict><key>match</key><integer>3</integer></dict>\
+        <dict><key>begin</key><string>x</string></dict>\
+        </array></plist>";
+
+    #[test]
+    fn keep_types_dict_within_array() {
+        let options = PlistOptions {
+            keep_types: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(NESTED, options);
+
+        let cases = [
+            ("", PlistType::Array),
+            ("0", PlistType::Dict),
+            ("0/match", PlistType::Integer),
+            ("1", PlistType::Dict),
+            ("1/begin", PlistType::String),
+        ];
+        for (path, ty) in cases {
+            let node = cfg.pointer(path).unwrap();
+            let id = cfg.node_id(node).unwrap();
+            assert_eq!(cfg.plist_type_of(id), Some(ty), "type of `{path}`");
+            assert!(std::ptr::eq(cfg.node(id).unwrap(), node));
+        }
+    }
+
+    #[test]
+    fn keep_spans_dict_within_array() {
+        let options = PlistOptions {
+            keep_spans: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(NESTED, options);
+
+        let cases = [
+            ("0", "<dict><key>match</key><integer>3</integer></dict>"),
+            ("0/match", "<integer>3</integer>"),
+            ("1", "<dict><key>begin</key><string>x</string></dict>"),
+            ("1/begin", "<string>x</string>"),
+        ];
+        for (path, text) in cases {
+            let id = cfg.node_id(cfg.pointer(path).unwrap()).unwrap();
+            let span = cfg.span_of(id).unwrap();
+            assert_eq!(span.text(NESTED), Some(text), "span of `{path}`");
+        }
+
+        let root = cfg.span_of(ConfigNodeID(0)).unwrap();
+        assert!(root.text(NESTED).unwrap().starts_with("<array>"));
+        assert!(root.text(NESTED).unwrap().ends_with("</array>"));
+    }
+
+    #[test]
+    fn keep_spans_line_and_column() {
+        let content =
+            "<plist>\n<dict>\n  <key>name</key>\n  <string>Make</string>\n</dict>\n</plist>";
+        let options = PlistOptions {
+            keep_spans: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(content, options);
+
+        let id = cfg.node_id(cfg.pointer("name").unwrap()).unwrap();
+        let span = cfg.span_of(id).unwrap();
+        assert_eq!((span.start.line, span.start.column), (4, 3));
+        assert_eq!((span.end.line, span.end.column), (4, 24));
+        assert_eq!(span.text(content), Some("<string>Make</string>"));
+    }
+
+    #[test]
+    fn keep_types_without_option() {
+        let cfg = parse(NESTED, PlistOptions::default());
+        assert_eq!(cfg.plist_type_of(ConfigNodeID(0)), None);
+    }
+
+    #[test]
+    fn keep_spans_self_closing() {
+        let content = "<plist><array><dict/><array/></array></plist>";
+        let options = PlistOptions {
+            keep_spans: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(content, options);
+
+        for (path, text) in [("0", "<dict/>"), ("1", "<array/>")] {
+            let id = cfg.node_id(cfg.pointer(path).unwrap()).unwrap();
+            assert_eq!(cfg.span_of(id).unwrap().text(content), Some(text));
+        }
+    }
+
+    #[test]
+    fn error_location_of_lone_surrogate() {
+        let content = "<plist>\n<string>abc &#xD800; def</string>\n</plist>";
+        let (line, column, err) = error_location(content);
+        assert_eq!((line, column), (2, 13));
+        assert!(err.starts_with(UNPAIRED_SURROGATE), "{err}");
+
+        let content = "<plist><string>\u{1F600}&#128512;</string></plist>";
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+        assert_eq!(cfg.tree().as_str(), Some("\u{1F600}\u{1F600}"));
+    }
+
+    #[test]
+    fn error_location_of_structure() {
+        let content = "<plist>\n<array>\n  <string>a</string>\n  </dict>\n</array>\n</plist>";
+        let (line, column, err) = error_location(content);
+        assert_eq!((line, column), (4, 3));
+        assert!(err.starts_with("unexpected </dict>"), "{err}");
+
+        let content = "<plist><dict>\n<key>a</key><string>x</string>\n<key>a</key><string>y</string>\n</dict></plist>";
+        let options = PlistOptions {
+            reject_duplicate_keys: true,
+            ..PlistOptions::default()
+        };
+        let (line, column, err) = error_location_with_options(content, options);
+        assert_eq!((line, column), (3, 1));
+        assert!(err.starts_with("duplicate key `a`"), "{err}");
+    }
+
+    #[test]
+    fn trim_names_opt_in() {
+        let content = "<plist><dict>\
+            <key>name</key><string> keyword.control </string>\
+            <key>match</key><string> if </string>\
+            </dict></plist>";
+
+        let cfg = parse(content, PlistOptions::default());
+        assert_eq!(
+            cfg.pointer("name").unwrap().as_str(),
+            Some(" keyword.control ")
+        );
+        assert_eq!(cfg.pointer("match").unwrap().as_str(), Some(" if "));
+
+        let options = PlistOptions {
+            trim_names: true,
+            ..PlistOptions::default()
+        };
+        let cfg = parse(content, options);
+        assert_eq!(
+            cfg.pointer("name").unwrap().as_str(),
+            Some("keyword.control")
+        );
+        assert_eq!(cfg.pointer("match").unwrap().as_str(), Some(" if "));
+    }
 }
@@ -2,22 +2,105 @@ use std::path::PathBuf;
 
 use crate::{Config, Error};
 
+use super::{ConfigTree, NodeTables, strip_bom};
+
+/// Key of the single entry map that `toml` uses to pass datetimes through serde
+const DATETIME_KEY: &str = "$__toml_private_datetime";
+
 impl Config {
-    /// Parse a toml string
+    /// Parse a toml string.  Datetime values (offset, local, date only or time
+    /// only) are stored as `ConfigTree::Date`, written the same as in the source
+    /// document.
     pub fn from_toml(
         file_name: impl Into<PathBuf>,
         content: impl AsRef<str>,
     ) -> Result<Self, Error> {
         let file_name = file_name.into();
 
-        let toml = toml::from_str(content.as_ref()).map_err(|err| Error::SerdeToml {
-            err: Box::new(err),
-            file_name: file_name.clone(),
-        })?;
+        let mut toml =
+            toml::from_str(strip_bom(content.as_ref())).map_err(|err| Error::SerdeToml {
+                err: Box::new(err),
+                file_name: file_name.clone(),
+            })?;
+
+        convert_datetimes(&mut toml);
 
         Ok(Self {
             tree: toml,
             file_name,
+            tables: NodeTables::default(),
+            warnings: vec![],
         })
     }
 }
+
+/// Replace the maps that `toml` turns datetimes into with a date node
+fn convert_datetimes(tree: &mut ConfigTree) {
+    match tree {
+        ConfigTree::Object(value) => {
+            if let (1, Some(ConfigTree::String(date))) = (value.len(), value.get(DATETIME_KEY)) {
+                *tree = ConfigTree::Date(date.clone());
+                return;
+            }
+
+            value.values_mut().for_each(convert_datetimes);
+        }
+        ConfigTree::Array(value) => value.iter_mut().for_each(convert_datetimes),
+        ConfigTree::Null
+        | ConfigTree::Bool(_)
+        | ConfigTree::Integer(_)
+        | ConfigTree::Float(_)
+        | ConfigTree::String(_)
+        | ConfigTree::Data(_)
+        | ConfigTree::Date(_) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_datetime_kind() {
+        let content = "offset = 1979-05-27T07:32:00Z\n\
+            local = 1979-05-27T07:32:00\n\
+            date = 1979-05-27\n\
+            time = 07:32:00\n\
+            [nested]\n\
+            list = [1979-05-27]\n";
+        let cfg = Config::from_toml("dates.toml", content).unwrap();
+
+        let date = |s: &str| ConfigTree::Date(s.to_string());
+        let tree = cfg.tree();
+        assert_eq!(tree["offset"], date("1979-05-27T07:32:00Z"));
+        assert_eq!(tree["local"], date("1979-05-27T07:32:00"));
+        assert_eq!(tree["date"], date("1979-05-27"));
+        assert_eq!(tree["time"], date("07:32:00"));
+        assert_eq!(tree["nested"]["list"][0], date("1979-05-27"));
+    }
+
+    #[test]
+    fn datetimes_are_dates() {
+        let content = "\
+            released = 2024-02-29T13:05:09Z\n\
+            day = 1979-05-27\n\
+            name = \"1979-05-27\"\n\
+            [[history]]\n\
+            at = 07:32:00\n";
+        let cfg = Config::from_toml("a.toml", content).unwrap();
+
+        let date = |path| cfg.pointer(path).unwrap().as_date();
+        assert_eq!(date("released"), Some("2024-02-29T13:05:09Z"));
+        assert_eq!(date("day"), Some("1979-05-27"));
+        assert_eq!(date("history/0/at"), Some("07:32:00"));
+        assert_eq!(
+            cfg.pointer("name"),
+            Some(&ConfigTree::String("1979-05-27".to_string()))
+        );
+
+        let plist =
+            "<plist><dict><key>released</key><date>2024-02-29T13:05:09Z</date></dict></plist>";
+        let plist = Config::from_plist("a.plist", plist).unwrap();
+        assert_eq!(plist.pointer("released"), cfg.pointer("released"));
+    }
+}
@@ -2,37 +2,59 @@ use std::path::{Path, PathBuf};
 
 use crate::Error;
 
-use super::{Config, ConfigTree};
+use super::{Config, ConfigTree, NodeTables, strip_bom};
 
 impl Config {
-    /// Parse a YAML string. YAML with debug info is not supported.
+    /// Parse a YAML string. YAML with debug info is not supported.  Integers and
+    /// reals are stored as numbers, except for `.inf`, `-.inf` and `.nan`,
+    /// which are kept as strings written as in the source, as JSON can't
+    /// represent them and NaN would not compare equal to itself.
     pub fn from_yaml(
         file_name: impl Into<PathBuf>,
         content: impl AsRef<str>,
     ) -> Result<Self, Error> {
         let file_name = file_name.into();
 
-        let yaml = yaml_rust2::YamlLoader::load_from_str(content.as_ref()).map_err(|err| {
-            Error::YAMLError {
-                err: err.to_string(),
-                file_name: file_name.clone(),
-            }
-        })?;
+        let mut documents = Self::from_yaml_multi(file_name.clone(), content)?;
 
         // only accept 1 document within the file, error if there are multiple
-        if yaml.len() != 1 {
+        if documents.len() != 1 {
             return Err(Error::YAMLError {
-                err: format!("Expected 1 document, got {}", yaml.len()),
-                file_name: file_name.clone(),
+                err: format!("Expected 1 document, got {}", documents.len()),
+                file_name,
             });
         }
 
-        // checked above that the len == 1, so should never panic
-        let yaml = yaml.into_iter().next().unwrap();
+        Ok(documents.remove(0))
+    }
+
+    /// Parse a YAML stream that may contain any number of documents separated
+    /// by `---`, returning one config per document in order.  Each config has
+    /// the same file name.
+    pub fn from_yaml_multi(
+        file_name: impl Into<PathBuf>,
+        content: impl AsRef<str>,
+    ) -> Result<Vec<Self>, Error> {
+        let file_name = file_name.into();
 
-        let tree = yaml_visitor(yaml, &file_name)?;
+        let yaml =
+            yaml_rust2::YamlLoader::load_from_str(strip_bom(content.as_ref())).map_err(|err| {
+                Error::YAMLError {
+                    err: err.to_string(),
+                    file_name: file_name.clone(),
+                }
+            })?;
 
-        Ok(Self { tree, file_name })
+        yaml.into_iter()
+            .map(|yaml| {
+                Ok(Self {
+                    tree: yaml_visitor(yaml, &file_name)?,
+                    file_name: file_name.clone(),
+                    tables: NodeTables::default(),
+                    warnings: vec![],
+                })
+            })
+            .collect()
     }
 }
 
@@ -40,8 +62,12 @@ impl Config {
 /// this cannot just be a serde deserialize as yaml_rust2 doesn't use serde.
 fn yaml_visitor(yaml: yaml_rust2::Yaml, file_name: &Path) -> Result<ConfigTree, Error> {
     match yaml {
-        yaml_rust2::Yaml::Real(value) => Ok(ConfigTree::String(value)),
-        yaml_rust2::Yaml::Integer(value) => Ok(ConfigTree::String(value.to_string())),
+        // yaml_rust2 only produces reals that it can parse
+        yaml_rust2::Yaml::Real(value) => match yaml_rust2::Yaml::Real(value.clone()).as_f64() {
+            Some(real) if real.is_finite() => Ok(ConfigTree::Float(real)),
+            _ => Ok(ConfigTree::String(value)),
+        },
+        yaml_rust2::Yaml::Integer(value) => Ok(ConfigTree::Integer(value)),
         yaml_rust2::Yaml::String(value) => Ok(ConfigTree::String(value)),
         yaml_rust2::Yaml::Boolean(value) => Ok(ConfigTree::Bool(value)),
         yaml_rust2::Yaml::Array(value) => Ok(ConfigTree::Array(
@@ -88,3 +114,74 @@ fn yaml_visitor(yaml: yaml_rust2::Yaml, file_name: &Path) -> Result<ConfigTree,
         }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_into_tree() {
+        let content = "\u{FEFF}name: Make\nhidden: false\nfileTypes:\n  - mk\n  - 3\nempty: ~\n";
+        let cfg = Config::from_yaml("make.yaml", content).unwrap();
+
+        let expected = Config::from_json(
+            "make.json",
+            r#"{"name": "Make", "hidden": false, "fileTypes": ["mk", 3], "empty": null}"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.tree(), expected.tree());
+    }
+
+    #[test]
+    fn single_document_and_simple_keys() {
+        let content = "\u{FEFF}name: a\n---\nname: b\n";
+        assert!(matches!(
+            Config::from_yaml("a.yaml", content),
+            Err(Error::YAMLError { .. })
+        ));
+
+        let documents = Config::from_yaml_multi("a.yaml", content).unwrap();
+        let names: Vec<_> = documents.iter().map(|cfg| cfg.grammar_name()).collect();
+        assert_eq!(names, [Some("a"), Some("b")]);
+        assert!(
+            documents
+                .iter()
+                .all(|cfg| cfg.file_name() == Path::new("a.yaml"))
+        );
+
+        assert!(Config::from_yaml_multi("a.yaml", "").unwrap().is_empty());
+
+        let err = Config::from_yaml("a.yaml", "? [a, b]\n: c\n").unwrap_err();
+        assert!(err.to_string().contains("Unexpected Complex Key"), "{err}");
+    }
+
+    #[test]
+    fn integers_and_reals() {
+        let content = "int: 42\nreal: 1.5\nexp: 1e3\nquoted: '42'\n";
+        let cfg = Config::from_yaml("a.yaml", content).unwrap();
+
+        assert_eq!(cfg.pointer("int"), Some(&ConfigTree::Integer(42)));
+        assert_eq!(cfg.pointer("real"), Some(&ConfigTree::Float(1.5)));
+        assert_eq!(cfg.pointer("exp"), Some(&ConfigTree::Float(1000.0)));
+        assert_eq!(
+            cfg.pointer("quoted"),
+            Some(&ConfigTree::String("42".to_string()))
+        );
+    }
+
+    #[test]
+    fn non_finite_reals_are_strings() {
+        let content = "nan: .nan\ninf: .inf\nneg: -.Inf\n";
+        let cfg = Config::from_yaml("a.yaml", content).unwrap();
+
+        for (path, text) in [("nan", ".nan"), ("inf", ".inf"), ("neg", "-.Inf")] {
+            assert_eq!(cfg.pointer(path).unwrap().as_str(), Some(text), "{path}");
+        }
+
+        assert!(cfg.tree() == Config::from_yaml("a.yaml", content).unwrap().tree());
+        assert_eq!(
+            cfg.to_json_string().unwrap(),
+            r#"{"nan":".nan","inf":".inf","neg":"-.Inf"}"#
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Character level reading of source text, for hand written parsers.
+
+use std::collections::VecDeque;
+
+use crate::SourceLocation;
+
+/// A cursor over a stream of characters, with arbitrary lookahead and tracking
+/// of the location of the next character to be consumed.
+pub struct CharCursor<I> {
+    chars: I,
+
+    /// Characters that have been read from `chars` while looking ahead, but not
+    /// yet consumed
+    lookahead: VecDeque<char>,
+
+    /// Location of the next character to be consumed
+    location: SourceLocation,
+}
+
+impl<I: Iterator<Item = char>> CharCursor<I> {
+    /// Create a new cursor at the start of the input
+    pub fn new(input: impl IntoIterator<IntoIter = I>) -> Self {
+        Self {
+            chars: input.into_iter(),
+            lookahead: VecDeque::new(),
+            location: SourceLocation::default(),
+        }
+    }
+
+    /// Location of the next character to be consumed
+    pub fn location(&self) -> SourceLocation {
+        self.location
+    }
+
+    /// Consume the next character in the stream
+    pub fn next_char(&mut self) -> Option<char> {
+        let next = self.lookahead.pop_front().or_else(|| self.chars.next())?;
+        self.location.advance(next);
+        Some(next)
+    }
+
+    /// Return the next character in the stream, without consuming it
+    pub fn peek(&mut self) -> Option<char> {
+        self.fill_lookahead(1);
+        self.lookahead.front().copied()
+    }
+
+    /// Check whether the upcoming input starts with the provided string
+    pub fn starts_with(&mut self, search: &str) -> bool {
+        let len = search.chars().count();
+        self.fill_lookahead(len);
+
+        self.lookahead.len() >= len
+            && self
+                .lookahead
+                .iter()
+                .copied()
+                .zip(search.chars())
+                .all(|(a, b)| a == b)
+    }
+
+    /// Skip any whitespace characters
+    pub fn skip_whitespace(&mut self) {
+        while let Some(peek) = self.peek() {
+            if !" \t\r\n".contains(peek) {
+                break;
+            }
+
+            self.next_char();
+        }
+    }
+
+    /// advance until the next occurrence of the provided string, including the
+    /// provided input.  If the search string is never found, stops at the end
+    /// of the input.
+    pub fn advance_until(&mut self, search: &str) {
+        while !self.starts_with(search) {
+            if self.next_char().is_none() {
+                return;
+            }
+        }
+
+        for _ in search.chars() {
+            self.next_char();
+        }
+    }
+
+    /// Same as advance_until, but returns the text content that was advanced
+    /// over, not including the search string.  If the search string is never
+    /// found, returns the rest of the input.
+    pub fn capture_until(&mut self, search: &str) -> String {
+        let mut res = String::new();
+
+        while !self.starts_with(search) {
+            match self.next_char() {
+                Some(c) => res.push(c),
+                None => return res,
+            }
+        }
+
+        for _ in search.chars() {
+            self.next_char();
+        }
+
+        res
+    }
+
+    /// Up to `len` of the upcoming characters, without consuming them.  Useful
+    /// for showing where an error happened.
+    pub fn sample(&mut self, len: usize) -> String {
+        self.fill_lookahead(len);
+        self.lookahead.iter().take(len).collect()
+    }
+
+    /// Read from the input until there are at least `len` characters of
+    /// lookahead, or the input ends
+    fn fill_lookahead(&mut self, len: usize) {
+        while self.lookahead.len() < len {
+            match self.chars.next() {
+                Some(c) => self.lookahead.push_back(c),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookahead_and_capture() {
+        let mut cursor = CharCursor::new("  <key>é</key>\nrest".chars());
+
+        cursor.skip_whitespace();
+        assert_eq!(cursor.peek(), Some('<'));
+        assert!(cursor.starts_with("<key>"));
+        assert!(!cursor.starts_with("<keys>"));
+        assert_eq!(cursor.location().column, 3);
+
+        cursor.advance_until("<key>");
+        assert_eq!(cursor.capture_until("</key>"), "é");
+        assert_eq!(cursor.location().byte, 15);
+        assert_eq!(cursor.sample(3), "\nre");
+
+        cursor.skip_whitespace();
+        assert_eq!(cursor.location().line, 2);
+        assert_eq!(cursor.capture_until("missing"), "rest");
+        assert_eq!(cursor.next_char(), None);
+    }
+}
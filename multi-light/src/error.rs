@@ -1,5 +1,7 @@
 use std::{fmt::Display, path::PathBuf};
 
+use crate::SourceLocation;
+
 /// Generic errors that can be thrown by the library.
 #[derive(Debug)]
 pub enum Error {
@@ -14,6 +16,7 @@ pub enum Error {
     PlistError {
         err: String,
         file_name: PathBuf,
+        location: Option<SourceLocation>,
     },
     YAMLError {
         err: String,
@@ -23,36 +26,204 @@ pub enum Error {
         err: Box<toml::de::Error>,
         file_name: PathBuf,
     },
+    DeserializeError {
+        err: String,
+        file_name: PathBuf,
+    },
+    SerializeJson {
+        err: serde_json::Error,
+        file_name: PathBuf,
+    },
+    Io {
+        err: std::io::Error,
+        file_name: PathBuf,
+    },
+    /// A document loaded fine, but isn't a valid grammar
+    GrammarError {
+        err: String,
+        file_name: PathBuf,
+    },
+    /// A document loaded fine, but isn't a valid theme
+    ThemeError {
+        err: String,
+        file_name: PathBuf,
+    },
+    /// No grammar with this scope name is in the registry, or could be read
+    /// with `Registry::on_read_file`
+    GrammarNotFound {
+        scope: String,
+    },
+    /// No theme was added to the registry with this name
+    ThemeNotFound {
+        name: String,
+    },
+    /// Several files failed to load, e.g. from `Registry::load_dir`.  The file
+    /// name is the directory they were loaded from.
+    Multiple {
+        errors: Vec<Error>,
+        file_name: PathBuf,
+    },
+}
+
+impl Error {
+    /// Replace the file name the error refers to, e.g. to add the path to an
+    /// error converted from `std::io::Error`.  Errors for grammars or themes
+    /// that weren't found don't refer to a file, so are left unchanged.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        match &mut self {
+            Error::SerdeJson { file_name, .. }
+            | Error::JSONError { file_name, .. }
+            | Error::PlistError { file_name, .. }
+            | Error::YAMLError { file_name, .. }
+            | Error::SerdeToml { file_name, .. }
+            | Error::DeserializeError { file_name, .. }
+            | Error::SerializeJson { file_name, .. }
+            | Error::Io { file_name, .. }
+            | Error::GrammarError { file_name, .. }
+            | Error::ThemeError { file_name, .. }
+            | Error::Multiple { file_name, .. } => *file_name = path.into(),
+            Error::GrammarNotFound { .. } | Error::ThemeNotFound { .. } => (),
+        }
+
+        self
+    }
+}
+
+/// Allows using `?` on IO operations.  `std::io::Error` doesn't say which file it
+/// was about, so the file name is left empty; use `Error::with_path` to set it.
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io {
+            err,
+            file_name: PathBuf::new(),
+        }
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::SerdeJson { err, file_name } => writeln!(
+            Error::SerdeJson { err, file_name } => write!(
                 f,
                 "Error while parsing JSON file `{}`: {err}",
                 file_name.display()
             ),
-            Error::JSONError { err, file_name } => writeln!(
+            Error::JSONError { err, file_name } => write!(
                 f,
                 "Error while parsing JSON file `{}`: {err}",
                 file_name.display()
             ),
-            Error::PlistError { err, file_name } => writeln!(
+            Error::PlistError {
+                err,
+                file_name,
+                location: Some(location),
+            } => write!(
+                f,
+                "Error while parsing plist file `{}` at {}:{}: {err}",
+                file_name.display(),
+                location.line,
+                location.column
+            ),
+            Error::PlistError {
+                err,
+                file_name,
+                location: None,
+            } => write!(
                 f,
                 "Error while parsing plist file `{}`: {err}",
                 file_name.display()
             ),
-            Error::YAMLError { err, file_name } => writeln!(
+            Error::YAMLError { err, file_name } => write!(
                 f,
                 "Error while parsing YAML file `{}`: {err}",
                 file_name.display()
             ),
-            Error::SerdeToml { err, file_name } => writeln!(
+            // toml's messages end with a blank line after the source excerpt
+            Error::SerdeToml { err, file_name } => write!(
+                f,
+                "Error while parsing TOML file `{}`: {}",
+                file_name.display(),
+                err.to_string().trim_end()
+            ),
+            Error::DeserializeError { err, file_name } => write!(
+                f,
+                "Error while deserializing `{}`: {err}",
+                file_name.display()
+            ),
+            Error::SerializeJson { err, file_name } => write!(
+                f,
+                "Error while writing `{}` as JSON: {err}",
+                file_name.display()
+            ),
+            Error::Io { err, file_name } => write!(
                 f,
-                "Error while parsing TOML file `{}`: {err}",
+                "Error while reading file `{}`: {err}",
                 file_name.display()
             ),
+            Error::GrammarError { err, file_name } => {
+                write!(f, "Invalid grammar `{}`: {err}", file_name.display())
+            }
+            Error::ThemeError { err, file_name } => {
+                write!(f, "Invalid theme `{}`: {err}", file_name.display())
+            }
+            Error::GrammarNotFound { scope } => {
+                write!(f, "No grammar with the scope name `{scope}` was found")
+            }
+            Error::ThemeNotFound { name } => {
+                write!(f, "No theme with the name `{name}` was found")
+            }
+            Error::Multiple { errors, file_name } => {
+                write!(
+                    f,
+                    "{} files in `{}` failed to load:",
+                    errors.len(),
+                    file_name.display()
+                )?;
+                for err in errors {
+                    write!(f, "\n{err}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Config;
+
+    #[test]
+    fn display_has_no_trailing_newline() {
+        let errors = [
+            Config::from_json("a.json", "{").unwrap_err(),
+            Config::from_plist("a.plist", "<plist><array></dict></plist>").unwrap_err(),
+            Config::from_yaml("a.yaml", "a: [").unwrap_err(),
+            Config::from_toml("a.toml", "a = ").unwrap_err(),
+        ];
+
+        for err in errors {
+            let message = format!("{err}");
+            assert!(!message.ends_with('\n'), "{message:?}");
+        }
+    }
+
+    #[test]
+    fn io_error_with_path() {
+        fn read(path: &str) -> Result<String, Error> {
+            Ok(std::fs::read_to_string(path)?)
+        }
+
+        let err = read("/does/not/exist.json").unwrap_err();
+        assert!(matches!(&err, Error::Io { file_name, .. } if file_name.as_os_str().is_empty()));
+
+        let err = err.with_path("/does/not/exist.json");
+        let Error::Io { err: io, file_name } = &err else {
+            panic!("expected an io error, got {err:?}");
+        };
+        assert_eq!(io.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(file_name, &PathBuf::from("/does/not/exist.json"));
+        assert!(err.to_string().contains("`/does/not/exist.json`"), "{err}");
+    }
+}
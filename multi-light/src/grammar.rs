@@ -0,0 +1,544 @@
+//! Typed representation of a TextMate grammar, built from a parsed document in
+//! any of the supported file formats.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use indexmap::IndexMap;
+
+use crate::{Config, ConfigTree, ConfigWarning, Error, config::PathSegment};
+
+/// A TextMate grammar, e.g. from a `.tmLanguage` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grammar {
+    /// Scope name that other grammars and themes refer to this grammar by,
+    /// e.g. `source.makefile`
+    pub scope_name: String,
+
+    /// File names or extensions the grammar applies to.  Entries that aren't
+    /// strings are skipped, the same as in `Registry::detect_language`.
+    pub file_types: Vec<String>,
+
+    /// Top level rules, tried in order
+    pub patterns: Vec<Rule>,
+
+    /// Named rules that can be included by `#name`
+    pub repository: IndexMap<String, Rule>,
+
+    /// Regexes matching lines after which the indentation should increase or
+    /// decrease, see `Registry::indent_action`.  Read from the top level of
+    /// the document, its `settings` as in `.tmPreferences` files, or its
+    /// `indentationRules` as in VSCode language configurations.
+    pub increase_indent_pattern: Option<String>,
+    pub decrease_indent_pattern: Option<String>,
+
+    /// Problems that were worked around when building the grammar in lenient
+    /// mode, see `GrammarOptions::lenient`
+    pub warnings: Vec<ConfigWarning>,
+}
+
+/// Options for building a grammar from a parsed document
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GrammarOptions {
+    /// Accept a single rule object where an array of rules is expected, as
+    /// under `patterns`, wrapping it in a one element array and recording a
+    /// warning.  Defaults to false, making it an error.
+    pub lenient: bool,
+}
+
+/// A single rule within a grammar
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    /// Highlight a single regex match
+    Match {
+        name: Option<String>,
+        regex: String,
+        captures: Captures,
+    },
+
+    /// Highlight everything from a match of `begin` until a match of `end`,
+    /// using `patterns` for the text in between
+    BeginEnd {
+        name: Option<String>,
+        content_name: Option<String>,
+        begin: String,
+        end: String,
+        begin_captures: Captures,
+        end_captures: Captures,
+        patterns: Vec<Rule>,
+    },
+
+    /// Use the rules from elsewhere: `#name` from the repository, `$self` or
+    /// `$base` for the whole grammar, or the scope name of another grammar
+    Include(String),
+
+    /// A group of rules with no regex of its own, usually a repository entry
+    Patterns {
+        name: Option<String>,
+        patterns: Vec<Rule>,
+    },
+}
+
+/// Names and rules to apply to a regex's capture groups, by group number
+pub type Captures = BTreeMap<usize, Capture>;
+
+/// How to highlight a single capture group.  If it has patterns, the text it
+/// captured is tokenized using them.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Capture {
+    pub name: Option<String>,
+    pub patterns: Vec<Rule>,
+}
+
+impl Grammar {
+    /// Build a grammar from a parsed document.  Errors say which rule was
+    /// invalid, as a slash separated path from the root of the document.
+    pub fn from_config(cfg: &Config) -> Result<Grammar, Error> {
+        Self::from_config_with_options(cfg, GrammarOptions::default())
+    }
+
+    /// Build a grammar from a parsed document, using the given options
+    pub fn from_config_with_options(
+        cfg: &Config,
+        options: GrammarOptions,
+    ) -> Result<Grammar, Error> {
+        let parser = GrammarParser {
+            cfg,
+            options,
+            warnings: RefCell::new(vec![]),
+        };
+        let tree = cfg.tree();
+
+        let Some(scope_name) = tree.get("scopeName") else {
+            return Err(parser.error("", "missing required field `scopeName`"));
+        };
+        let scope_name = parser.string(scope_name, "scopeName")?;
+
+        let file_types = match tree.get("fileTypes") {
+            Some(file_types) => parser
+                .array(file_types, "fileTypes")?
+                .iter()
+                .filter_map(ConfigTree::as_str)
+                .map(str::to_string)
+                .collect(),
+            None => vec![],
+        };
+
+        let patterns = parser.patterns(tree, "")?;
+
+        let repository = match tree.get("repository") {
+            Some(repository) => parser.repository(repository, "repository")?,
+            None => IndexMap::new(),
+        };
+
+        let indent_pattern = |key: &str| {
+            ["", "settings", "indentationRules"]
+                .iter()
+                .map(|parent| join(parent, key))
+                .find_map(|path| Some((tree.pointer(&path)?, path)))
+                .map(|(pattern, path)| parser.string(pattern, &path))
+                .transpose()
+        };
+        let increase_indent_pattern = indent_pattern("increaseIndentPattern")?;
+        let decrease_indent_pattern = indent_pattern("decreaseIndentPattern")?;
+
+        Ok(Grammar {
+            scope_name,
+            file_types,
+            patterns,
+            repository,
+            increase_indent_pattern,
+            decrease_indent_pattern,
+            warnings: parser.warnings.into_inner(),
+        })
+    }
+}
+
+/// Conversion of the document's tree into rules, keeping the document around
+/// for error messages
+struct GrammarParser<'a> {
+    cfg: &'a Config,
+    options: GrammarOptions,
+    warnings: RefCell<Vec<ConfigWarning>>,
+}
+
+impl GrammarParser<'_> {
+    /// Parse a single rule
+    fn rule(&self, tree: &ConfigTree, path: &str) -> Result<Rule, Error> {
+        let ConfigTree::Object(value) = tree else {
+            return Err(self.error(path, "expected a rule object"));
+        };
+
+        let field = |key: &str| -> Result<Option<String>, Error> {
+            value
+                .get(key)
+                .map(|value| self.string(value, &join(path, key)))
+                .transpose()
+        };
+
+        if let Some(include) = field("include")? {
+            return Ok(Rule::Include(include));
+        }
+
+        let name = field("name")?;
+
+        if let Some(regex) = field("match")? {
+            return Ok(Rule::Match {
+                name,
+                regex,
+                captures: self.captures(tree, "captures", path)?,
+            });
+        }
+
+        if let Some(begin) = field("begin")? {
+            let Some(end) = field("end")? else {
+                return Err(self.error(path, "rule has `begin` but no `end`"));
+            };
+
+            // `captures` applies to both ends, unless overridden
+            let captures = self.captures(tree, "captures", path)?;
+            let begin_captures = if value.contains_key("beginCaptures") {
+                self.captures(tree, "beginCaptures", path)?
+            } else {
+                captures.clone()
+            };
+            let end_captures = if value.contains_key("endCaptures") {
+                self.captures(tree, "endCaptures", path)?
+            } else {
+                captures
+            };
+
+            return Ok(Rule::BeginEnd {
+                name,
+                content_name: field("contentName")?,
+                begin,
+                end,
+                begin_captures,
+                end_captures,
+                patterns: self.patterns(tree, path)?,
+            });
+        }
+
+        Ok(Rule::Patterns {
+            name,
+            patterns: self.patterns(tree, path)?,
+        })
+    }
+
+    /// Parse the `patterns` array of an object, if it has one
+    fn patterns(&self, tree: &ConfigTree, path: &str) -> Result<Vec<Rule>, Error> {
+        let Some(patterns) = tree.get("patterns") else {
+            return Ok(vec![]);
+        };
+
+        let path = join(path, "patterns");
+        if self.options.lenient && patterns.as_object().is_some() {
+            self.warn(
+                patterns,
+                &format!("`{path}`: expected an array, using the object as its only rule"),
+            );
+            return Ok(vec![self.rule(patterns, &format!("{path}/0"))?]);
+        }
+
+        self.array(patterns, &path)?
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| self.rule(rule, &format!("{path}/{idx}")))
+            .collect()
+    }
+
+    /// Parse a map of rule names to rules.  A name given twice is an error, as
+    /// the document only kept the last of the rules.
+    fn repository(&self, tree: &ConfigTree, path: &str) -> Result<IndexMap<String, Rule>, Error> {
+        let Some(value) = tree.as_object() else {
+            return Err(self.error(path, "expected an object"));
+        };
+
+        let mut key_path: Vec<_> = path
+            .split('/')
+            .map(|key| PathSegment::Key(key.to_string()))
+            .collect();
+        for key in value.keys() {
+            key_path.push(PathSegment::Key(key.clone()));
+            if self.cfg.is_duplicate_key(&key_path) {
+                return Err(self.error(path, &format!("duplicate rule `{key}`")));
+            }
+            key_path.pop();
+        }
+
+        value
+            .iter()
+            .map(|(key, rule)| Ok((key.clone(), self.rule(rule, &join(path, key))?)))
+            .collect()
+    }
+
+    /// Parse a map of capture group numbers to captures, if the object has one
+    /// under the given key
+    fn captures(&self, tree: &ConfigTree, key: &str, path: &str) -> Result<Captures, Error> {
+        let Some(captures) = tree.get(key) else {
+            return Ok(Captures::new());
+        };
+
+        let path = join(path, key);
+        let Some(captures) = captures.as_object() else {
+            return Err(self.error(&path, "expected an object"));
+        };
+
+        captures
+            .iter()
+            .map(|(group, capture)| {
+                let path = join(&path, group);
+                let Ok(group) = group.parse() else {
+                    return Err(self.error(&path, "capture group is not a number"));
+                };
+
+                let name = capture
+                    .get("name")
+                    .map(|name| self.string(name, &join(&path, "name")))
+                    .transpose()?;
+                let patterns = self.patterns(capture, &path)?;
+
+                Ok((group, Capture { name, patterns }))
+            })
+            .collect()
+    }
+
+    /// Get a string value, or an error for any other type
+    fn string(&self, tree: &ConfigTree, path: &str) -> Result<String, Error> {
+        match tree.as_str() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(self.error(path, "expected a string")),
+        }
+    }
+
+    /// Get an array value, or an error for any other type
+    fn array<'b>(&self, tree: &'b ConfigTree, path: &str) -> Result<&'b [ConfigTree], Error> {
+        match tree.as_array() {
+            Some(value) => Ok(value),
+            None => Err(self.error(path, "expected an array")),
+        }
+    }
+
+    /// Record a problem that was worked around, at the node's location if the
+    /// document kept spans
+    fn warn(&self, tree: &ConfigTree, message: &str) {
+        let location = self
+            .cfg
+            .node_id(tree)
+            .and_then(|id| self.cfg.span_of(id))
+            .map(|span| span.start);
+
+        self.warnings.borrow_mut().push(ConfigWarning {
+            message: message.to_string(),
+            location,
+        });
+    }
+
+    /// Create an error message
+    fn error(&self, path: &str, err: &str) -> Error {
+        let err = if path.is_empty() {
+            err.to_string()
+        } else {
+            format!("`{path}`: {err}")
+        };
+
+        Error::GrammarError {
+            err,
+            file_name: self.cfg.file_name().to_path_buf(),
+        }
+    }
+}
+
+/// Add a key onto the end of a path
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}/{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grammar(content: &str) -> Result<Grammar, Error> {
+        Grammar::from_config(&Config::from_json("test.json", content).unwrap())
+    }
+
+    fn error(content: &str) -> String {
+        match grammar(content) {
+            Err(Error::GrammarError { err, .. }) => err,
+            other => panic!("expected a grammar error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rules_from_config() {
+        let grammar = grammar(
+            r##"{
+                "scopeName": "source.test",
+                "fileTypes": ["test"],
+                "patterns": [
+                    {"include": "#string"},
+                    {"match": "\\d+", "name": "constant.numeric",
+                        "captures": {"0": {"name": "digits"}}}
+                ],
+                "repository": {
+                    "string": {
+                        "begin": "\"", "end": "\"", "name": "string.quoted",
+                        "captures": {"0": {"name": "punctuation"}},
+                        "endCaptures": {"0": {"name": "punctuation.end"}}
+                    },
+                    "group": {"patterns": [{"include": "$self"}]}
+                }
+            }"##,
+        )
+        .unwrap();
+
+        assert_eq!(grammar.scope_name, "source.test");
+        assert_eq!(grammar.file_types, ["test"]);
+        assert_eq!(grammar.patterns[0], Rule::Include("#string".to_string()));
+
+        let digits = Capture {
+            name: Some("digits".to_string()),
+            patterns: vec![],
+        };
+        assert_eq!(
+            grammar.patterns[1],
+            Rule::Match {
+                name: Some("constant.numeric".to_string()),
+                regex: "\\d+".to_string(),
+                captures: Captures::from([(0, digits)]),
+            }
+        );
+
+        let Rule::BeginEnd {
+            begin_captures,
+            end_captures,
+            ..
+        } = &grammar.repository["string"]
+        else {
+            panic!("expected a begin/end rule");
+        };
+        // `captures` is used for `begin`, but `endCaptures` overrides it
+        assert_eq!(begin_captures[&0].name.as_deref(), Some("punctuation"));
+        assert_eq!(end_captures[&0].name.as_deref(), Some("punctuation.end"));
+
+        assert_eq!(
+            grammar.repository["group"],
+            Rule::Patterns {
+                name: None,
+                patterns: vec![Rule::Include("$self".to_string())],
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert_eq!(error("{}"), "missing required field `scopeName`");
+        assert_eq!(
+            error(r#"{"scopeName": "a", "patterns": [{"begin": "x"}]}"#),
+            "`patterns/0`: rule has `begin` but no `end`"
+        );
+        assert_eq!(
+            error(r#"{"scopeName": "a", "repository": {"x": {"match": 1}}}"#),
+            "`repository/x/match`: expected a string"
+        );
+        assert_eq!(
+            error(r#"{"scopeName": "a", "patterns": [{"match": "x", "captures": {"one": {}}}]}"#),
+            "`patterns/0/captures/one`: capture group is not a number"
+        );
+    }
+
+    #[test]
+    fn file_types_skip_non_strings() {
+        let content = "<plist><dict>\
+            <key>scopeName</key><string>source.makefile</string>\
+            <key>fileTypes</key>\
+            <array>\
+                <string>Makefile</string>\
+                <true/>\
+                <integer>57</integer>\
+                <real>23.4</real>\
+                <string>mk</string>\
+            </array>\
+            </dict></plist>";
+        let cfg = Config::from_plist("Makefile.plist", content).unwrap();
+
+        let grammar = Grammar::from_config(&cfg).unwrap();
+        assert_eq!(grammar.file_types, ["Makefile", "mk"]);
+    }
+
+    #[test]
+    fn lone_rule_object_under_patterns() {
+        let content = r#"{
+            "scopeName": "source.test",
+            "patterns": {"match": "\\d+", "name": "constant.numeric"}
+        }"#;
+        assert_eq!(error(content), "`patterns`: expected an array");
+
+        let cfg = Config::from_json("test.json", content).unwrap();
+        let options = GrammarOptions { lenient: true };
+        let grammar = Grammar::from_config_with_options(&cfg, options).unwrap();
+        assert_eq!(
+            grammar.patterns,
+            [Rule::Match {
+                name: Some("constant.numeric".to_string()),
+                regex: "\\d+".to_string(),
+                captures: Captures::new(),
+            }]
+        );
+
+        let messages: Vec<_> = grammar.warnings.iter().map(|w| &w.message).collect();
+        assert_eq!(
+            messages,
+            ["`patterns`: expected an array, using the object as its only rule"]
+        );
+    }
+
+    #[test]
+    fn duplicate_repository_keys() {
+        let content = r#"{
+            "scopeName": "source.test",
+            "repository": {"x": {"match": "a"}, "y": {"match": "b"}, "x": {"match": "c"}}
+        }"#;
+        assert_eq!(error(content), "`repository`: duplicate rule `x`");
+
+        let content = "<plist><dict>\
+            <key>scopeName</key><string>source.test</string>\
+            <key>repository</key><dict>\
+                <key>x</key><dict><key>match</key><string>a</string></dict>\
+                <key>x</key><dict><key>match</key><string>b</string></dict>\
+            </dict>\
+            </dict></plist>";
+        let cfg = Config::from_plist("test.plist", content).unwrap();
+        let Err(Error::GrammarError { err, .. }) = Grammar::from_config(&cfg) else {
+            panic!("expected a grammar error");
+        };
+        assert_eq!(err, "`repository`: duplicate rule `x`");
+
+        // other keys keep their last value, as in the document
+        let grammar = grammar(r#"{"scopeName": "a", "scopeName": "b", "repository": {}}"#);
+        assert_eq!(grammar.unwrap().scope_name, "b");
+    }
+
+    #[test]
+    fn indent_patterns() {
+        let grammar = grammar(
+            r#"{
+                "scopeName": "source.test",
+                "settings": {"increaseIndentPattern": "\\{$"},
+                "indentationRules": {"decreaseIndentPattern": "^\\}"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(grammar.increase_indent_pattern.as_deref(), Some("\\{$"));
+        assert_eq!(grammar.decrease_indent_pattern.as_deref(), Some("^\\}"));
+
+        assert_eq!(
+            error(r#"{"scopeName": "a", "increaseIndentPattern": 1}"#),
+            "`increaseIndentPattern`: expected a string"
+        );
+    }
+}
@@ -1,7 +1,26 @@
 mod config;
+mod cursor;
 mod error;
+mod grammar;
 mod registry;
+mod render;
+mod span;
+mod theme;
 
 pub use config::*;
+pub use cursor::CharCursor;
 pub use error::Error;
-pub use registry::Registry;
+pub use grammar::{Capture, Captures, Grammar, GrammarOptions, Rule};
+pub use registry::{
+    DEFAULT_TAB_WIDTH, DuplicatePolicy, GrammarBoundary, IndentAction, IndexedToken, LineChange,
+    LineError, Registry, RuleId, ScopeTable, Token, TokenizeState, verify_tokens,
+};
+pub use render::{
+    HtmlOptions, VscodeToken, render_ansi, render_ansi_256, render_ansi_to, render_html,
+    render_html_classed, render_html_to, render_scope_overlay, render_vscode_json, rgb_to_ansi256,
+    scope_class,
+};
+pub use span::{SourceLocation, Span};
+pub use theme::{
+    Colour, FontStyle, ResolvedStyle, RuleMatch, Specificity, Style, Theme, ThemeRule,
+};
@@ -1,13 +1,113 @@
-use std::collections::HashMap;
+mod parse;
+mod prefilter;
+mod tokenize;
 
-use crate::{Config, Error};
+pub use parse::{IndexedToken, LineChange, LineError, ScopeTable};
+pub use tokenize::{
+    DEFAULT_TAB_WIDTH, GrammarBoundary, IndentAction, RuleId, Token, TokenizeState, verify_tokens,
+};
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    path::Path,
+    rc::Rc,
+};
+
+use indexmap::IndexMap;
+use onig::Regex;
+
+use crate::{Config, ConfigFormat, ConfigTree, Error, Grammar, ResolvedStyle, Theme};
+
+/// Function used to read a grammar that isn't in the registry yet, given its
+/// scope name
+type ReadFile = Box<dyn Fn(&str) -> Result<String, Error>>;
+
+/// Tokens of each line of a document, as given by `Registry::parse`
+type ParsedLines = Vec<Vec<Token>>;
 
 /// Storage for all data required to syntax highlight a piece of source code
 pub struct Registry {
     /// function to use to read a file referenced from a source file
-    callback: Option<Box<dyn Fn()>>,
+    callback: Option<ReadFile>,
+
+    /// Grammars, by their scope name
+    grammars: HashMap<String, Config>,
 
+    /// Themes, by the name they were added with
     themes: HashMap<String, Config>,
+
+    /// Compiled regexes, by their source, or the error from compiling them so
+    /// that invalid regexes aren't retried
+    regexes: RefCell<HashMap<String, Result<Regex, String>>>,
+
+    /// Grammars converted into rules, by scope name, built the first time
+    /// each one is used for tokenizing
+    compiled: RefCell<HashMap<String, Rc<Grammar>>>,
+
+    /// Skip lines that none of a grammar's top level rules can match, see
+    /// `set_prefilter`
+    prefilter: bool,
+
+    /// Characters that a match of one of a grammar's top level rules has to
+    /// start with, by scope name, or None if they could start with anything
+    first_chars: RefCell<HashMap<String, Option<HashSet<char>>>>,
+
+    /// What to do when a grammar is added with the same scope name as one
+    /// already in the registry
+    duplicate_policy: DuplicatePolicy,
+
+    /// Themes used by `resolve_style`, first to last
+    active_themes: Vec<Theme>,
+
+    /// Record where tokenizing moves between grammars, see
+    /// `set_embedded_boundaries`
+    embedded_boundaries: bool,
+
+    /// Results of `parse`, by scope name and hash of the input, oldest first
+    parse_cache: RefCell<IndexMap<(String, u64), ParsedLines>>,
+
+    /// Most results kept in `parse_cache`, or 0 to not cache them
+    parse_cache_capacity: usize,
+
+    /// Counts of the work done, for tests to check what was skipped
+    #[cfg(test)]
+    probe: Probe,
+}
+
+/// Counts of the work a registry has done
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Probe {
+    /// Lines given to `tokenize_line`
+    lines_tokenized: std::cell::Cell<usize>,
+
+    /// Grammars converted into rules and regexes compiled
+    compilations: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl Probe {
+    /// Count a grammar or regex being compiled
+    fn compiled(&self) {
+        self.compilations.set(self.compilations.get() + 1);
+    }
+}
+
+/// What to do when a grammar is added to a registry that already has a
+/// grammar with the same scope name, e.g. when a directory has both the plist
+/// and JSON version of a grammar
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Fail to add the new grammar, with an error
+    Error,
+
+    /// Keep the grammar that was added first, ignoring the new one
+    PreferFirst,
+
+    /// Replace the existing grammar with the new one
+    #[default]
+    PreferLast,
 }
 
 impl Registry {
@@ -15,7 +115,19 @@ impl Registry {
     pub fn new() -> Self {
         Registry {
             callback: None,
+            grammars: HashMap::new(),
             themes: HashMap::new(),
+            regexes: RefCell::new(HashMap::new()),
+            compiled: RefCell::new(HashMap::new()),
+            prefilter: true,
+            first_chars: RefCell::new(HashMap::new()),
+            duplicate_policy: DuplicatePolicy::default(),
+            active_themes: vec![],
+            embedded_boundaries: false,
+            parse_cache: RefCell::new(IndexMap::new()),
+            parse_cache_capacity: 0,
+            #[cfg(test)]
+            probe: Probe::default(),
         }
     }
 }
@@ -27,20 +139,431 @@ impl Default for Registry {
 }
 
 impl Registry {
-    // fn read_file(f: fn(&str) -> Result<&str>) {} // function for reading a required dependency file
+    /// Set the function used to read grammars referenced by an `include` that
+    /// aren't in the registry yet.  It is given the scope name, e.g.
+    /// `source.c`, and returns the grammar's contents in any format.  Grammars
+    /// read without a `scopeName` are given the scope they were read for.
+    pub fn on_read_file(&mut self, f: impl Fn(&str) -> Result<String, Error> + 'static) {
+        self.callback = Some(Box::new(f));
+        self.first_chars.get_mut().clear();
+        self.parse_cache.get_mut().clear();
+    }
 
-    /// Add a  new file to the registry
+    /// Set what happens when a grammar is added with the same scope name as
+    /// one already in the registry.  Defaults to replacing the existing one.
+    /// `load_dir` adds files in order of their names, so decides which of
+    /// them is first.
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    /// Record where `tokenize_line` moves into or out of the rules of an
+    /// included grammar, e.g. for the CSS within an HTML document, so that
+    /// each embedded language can be handled separately.  The boundaries of
+    /// each line are given by `TokenizeState::boundaries`.  Defaults to off.
+    pub fn set_embedded_boundaries(&mut self, enabled: bool) {
+        self.embedded_boundaries = enabled;
+    }
+
+    /// Check whether a line has any of the characters that the grammar's top
+    /// level rules can start a match with before tokenizing it, giving lines
+    /// without any a single token with the grammar's scope, rather than
+    /// trying every rule at every position.  This never changes the tokens,
+    /// so is only worth turning off to measure it.  Defaults to on.
+    pub fn set_prefilter(&mut self, enabled: bool) {
+        self.prefilter = enabled;
+    }
+
+    /// Load every grammar that the grammar for `scope` includes, directly or
+    /// through other included grammars, using the `on_read_file` function for
+    /// any not yet in the registry.  Without that function, missing grammars
+    /// are left unresolved.
+    pub fn resolve_includes(&mut self, scope: &str) -> Result<(), Error> {
+        let mut pending = vec![scope.to_string()];
+        let mut seen = HashSet::new();
+
+        while let Some(scope) = pending.pop() {
+            if !seen.insert(scope.clone()) {
+                continue;
+            }
+
+            let key = if self.grammars.contains_key(&scope) {
+                scope
+            } else {
+                let Some(callback) = &self.callback else {
+                    continue;
+                };
+
+                let content = callback(&scope)?;
+                let mut cfg = Self::detect_format(&scope, content.as_bytes())?;
+                default_scope_name(&mut cfg, &scope);
+
+                // the loaded grammar may have a different scope name than
+                // asked for, so is looked up by the one it was stored under
+                self.insert_grammar(cfg)?
+            };
+
+            if let Some(cfg) = self.grammars.get(&key) {
+                external_scopes(cfg.tree(), &mut pending);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a  new file to the registry.  It is treated as a grammar if it has a
+    /// `scopeName`, otherwise as a theme, unless its VSCode `$schema` says
+    /// which it is; use `add_grammar` or `add_theme` if the kind of file is
+    /// already known.
     pub fn add(&mut self, name: &str, input: &str) -> Result<(), Error> {
-        let cfg = Config::from_plist(name, input)
-            .or_else(|_| Config::from_json(name, input))
-            .or_else(|_| Config::from_toml(name, input))
-            .or_else(|_| Config::from_yaml(name, input))?;
+        self.add_bytes(name, input.as_bytes())
+    }
+
+    /// Add a new file to the registry from its raw contents, which allows
+    /// binary plists as well as all of the text formats
+    pub fn add_bytes(&mut self, name: &str, input: &[u8]) -> Result<(), Error> {
+        let cfg = Self::detect_format(name, input)?;
+
+        self.insert(name, cfg)
+    }
+
+    /// Add a grammar to the registry, in any format.  It is stored by its
+    /// `scopeName`, and is an error if it doesn't have one.
+    pub fn add_grammar(&mut self, name: &str, input: &str) -> Result<(), Error> {
+        let cfg = Self::detect_format(name, input.as_bytes())?;
+
+        self.insert_grammar(cfg).map(drop)
+    }
+
+    /// Add a grammar to the registry, the same as `add_grammar`, except that
+    /// `scope` is used as the grammar's `scopeName` if it doesn't have one,
+    /// e.g. for a fragment of a grammar written for testing.  A `scopeName` in
+    /// the grammar itself takes precedence.
+    pub fn add_grammar_with_scope(
+        &mut self,
+        name: &str,
+        input: &str,
+        scope: &str,
+    ) -> Result<(), Error> {
+        let mut cfg = Self::detect_format(name, input.as_bytes())?;
+        default_scope_name(&mut cfg, scope);
+
+        self.insert_grammar(cfg).map(drop)
+    }
+
+    /// Add a theme to the registry, in any format.  It is stored by `name`.
+    pub fn add_theme(&mut self, name: &str, input: &str) -> Result<(), Error> {
+        let cfg = Self::detect_format(name, input.as_bytes())?;
 
         self.themes.insert(name.to_string(), cfg);
 
         Ok(())
     }
 
+    /// Load every file directly within a directory, see `load_file`.  Files
+    /// that fail to load don't stop the others from loading; their errors are
+    /// all returned together once the whole directory has been read.
+    pub fn load_dir(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        let mut files = std::fs::read_dir(path)
+            .and_then(|dir| {
+                dir.map(|entry| Ok(entry?.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|err| Error::from(err).with_path(path))?;
+        files.sort();
+
+        let errors: Vec<_> = files
+            .into_iter()
+            .filter(|file| file.is_file())
+            .filter_map(|file| self.load_file(file).err())
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Multiple {
+                errors,
+                file_name: path.to_path_buf(),
+            })
+        }
+    }
+
+    /// Load a single file, picking its format from the extension, or trying
+    /// each parser in turn for unknown extensions.  `.tmLanguage` files are
+    /// always grammars and `.tmTheme` files always themes, anything else is
+    /// sorted the same as `add`.  The file is stored under its file name.
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+
+        let bytes = std::fs::read(path).map_err(|err| Error::from(err).with_path(path))?;
+        let text = || {
+            std::str::from_utf8(&bytes).map_err(|err| {
+                let err = std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+                Error::from(err).with_path(path)
+            })
+        };
+
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+
+        let cfg = match extension.as_deref() {
+            Some("plist" | "tmlanguage" | "tmtheme" | "tmpreferences") => {
+                Config::from_plist_bytes(path, &bytes)?
+            }
+            Some("json") => Config::from_json(path, text()?)?,
+            Some("jsonc") => Config::from_jsonc(path, text()?)?,
+            Some("toml") => Config::from_toml(path, text()?)?,
+            Some("yaml" | "yml") => Config::from_yaml(path, text()?)?,
+            _ => Self::detect_format(&path.to_string_lossy(), &bytes)?,
+        };
+
+        match extension.as_deref() {
+            Some("tmlanguage") => self.insert_grammar(cfg).map(drop),
+            Some("tmtheme") => {
+                self.themes.insert(name.to_string(), cfg);
+                Ok(())
+            }
+            _ => self.insert(&name, cfg),
+        }
+    }
+
+    /// Parse a file of unknown format by trying each parser in turn
+    fn detect_format(name: &str, input: &[u8]) -> Result<Config, Error> {
+        Config::from_plist_bytes(name, input).or_else(|err| {
+            // binary plists can't be any other format
+            let Ok(input) = std::str::from_utf8(input) else {
+                return Err(err);
+            };
+
+            Config::from_json(name, input)
+                .or_else(|_| Config::from_toml(name, input))
+                .or_else(|_| Config::from_yaml(name, input))
+        })
+    }
+
+    /// Add a new file to the registry, when its format is already known.  This
+    /// skips trying every parser in turn, so errors come from the right one.
+    pub fn add_with_format(
+        &mut self,
+        name: &str,
+        input: &str,
+        format: ConfigFormat,
+    ) -> Result<(), Error> {
+        let cfg = Config::from_format(format, name, input)?;
+
+        self.insert(name, cfg)
+    }
+
+    /// Get a grammar by its scope name, e.g. `source.makefile`
+    pub fn grammar_for_scope(&self, scope: &str) -> Option<&Config> {
+        self.grammars.get(scope)
+    }
+
+    /// Find the grammar for a file from its name, using each grammar's
+    /// `fileTypes`.  An entry matches either the whole file name, e.g.
+    /// `Makefile`, or the end of the name after a `.`, ignoring case, e.g.
+    /// `mk` for `foo.mk`.  Returns the scope name of the grammar; if several
+    /// grammars match, the one whose scope name sorts first is used.
+    pub fn detect_language(&self, file_name: &str) -> Option<&str> {
+        let name = Path::new(file_name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(file_name);
+        let lower = name.to_ascii_lowercase();
+
+        self.sorted_grammars().into_iter().find_map(|(scope, cfg)| {
+            let file_types = cfg.tree().get("fileTypes")?.as_array()?;
+
+            file_types
+                .iter()
+                .filter_map(ConfigTree::as_str)
+                .any(|ty| {
+                    let ty_lower = ty.to_ascii_lowercase();
+                    name == ty
+                        || lower
+                            .strip_suffix(&ty_lower)
+                            .is_some_and(|rest| rest.ends_with('.'))
+                })
+                .then_some(scope)
+        })
+    }
+
+    /// Find the grammar for a file from its first line, e.g. a `#!/bin/sh`
+    /// shebang, using each grammar's `firstLineMatch` regex.  Grammars whose
+    /// regex doesn't compile are skipped.  Returns the scope name of the
+    /// grammar; if several grammars match, the one whose scope name sorts
+    /// first is used.
+    pub fn detect_language_by_content(&self, first_line: &str) -> Option<&str> {
+        let mut regexes = self.regexes.borrow_mut();
+
+        self.sorted_grammars().into_iter().find_map(|(scope, cfg)| {
+            let source = cfg.tree().get("firstLineMatch")?.as_str()?;
+            let regex = regexes
+                .entry(source.to_string())
+                .or_insert_with(|| Regex::new(source).map_err(|err| err.to_string()))
+                .as_ref()
+                .ok()?;
+
+            regex.find(first_line).map(|_| scope)
+        })
+    }
+
+    /// Every grammar, in order of scope name, so that searching them gives
+    /// the same result however the registry was built
+    fn sorted_grammars(&self) -> Vec<(&str, &Config)> {
+        let mut grammars: Vec<_> = self
+            .grammars
+            .iter()
+            .map(|(scope, cfg)| (scope.as_str(), cfg))
+            .collect();
+        grammars.sort_by_key(|&(scope, _)| scope);
+        grammars
+    }
+
+    /// Get a theme by the name it was added with
+    pub fn theme(&self, name: &str) -> Option<&Config> {
+        self.themes.get(name)
+    }
+
+    /// Set the themes used by `resolve_style`, by the names they were added
+    /// with, e.g. a partial theme of overrides followed by the theme it
+    /// overrides.  Each is read as a `.tmTheme` if it has a `settings` array,
+    /// otherwise as a VSCode theme.  The themes are built when this is called,
+    /// so adding a theme with the same name afterwards doesn't change them.
+    pub fn set_active_themes(&mut self, names: &[&str]) -> Result<(), Error> {
+        self.active_themes = names
+            .iter()
+            .map(|&name| {
+                let Some(cfg) = self.themes.get(name) else {
+                    return Err(Error::ThemeNotFound {
+                        name: name.to_string(),
+                    });
+                };
+
+                if cfg.tree().get("settings").is_some() {
+                    Theme::from_config(cfg)
+                } else {
+                    Theme::from_vscode_config(cfg)
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(())
+    }
+
+    /// Find the style of a token from its scopes, outermost first, using the
+    /// active themes, see `set_active_themes`.  The first theme with a rule
+    /// for the scopes styles the token, rather than the rules of every theme
+    /// being combined.  Colours that neither that theme's rules nor its
+    /// defaults set, and every part of the style of a token that no theme has
+    /// a rule for, come from the first theme with a default for them.
+    pub fn resolve_style(&self, scopes: &[String]) -> ResolvedStyle {
+        let foreground = self
+            .active_themes
+            .iter()
+            .find_map(|theme| theme.settings.foreground);
+        let background = self
+            .active_themes
+            .iter()
+            .find_map(|theme| theme.settings.background);
+        let font_style = self
+            .active_themes
+            .iter()
+            .find_map(|theme| theme.settings.font_style)
+            .unwrap_or_default();
+
+        let Some(theme) = self
+            .active_themes
+            .iter()
+            .find(|theme| !theme.resolve_all(scopes).is_empty())
+        else {
+            return ResolvedStyle {
+                foreground,
+                background,
+                font_style,
+            };
+        };
+
+        let style = theme.style_for(scopes);
+        ResolvedStyle {
+            foreground: style.foreground.or(foreground),
+            background: style.background.or(background),
+            font_style: style.font_style,
+        }
+    }
+
+    /// Store a parsed file.  Grammars are stored by their `scopeName`, as that
+    /// is how other grammars refer to them, and themes by name.  A VSCode
+    /// `$schema` decides which a file is if it has one: the colour theme
+    /// schema, `vscode://schemas/color-theme`, for themes, and the TextMate
+    /// schemas, `vscode://schemas/textmate-colorizer` or one ending in
+    /// `tmlanguage.json`, for grammars.  Otherwise documents with a
+    /// `scopeName` are grammars, and anything else is a theme.
+    fn insert(&mut self, name: &str, cfg: Config) -> Result<(), Error> {
+        let schema = cfg
+            .tree()
+            .get("$schema")
+            .and_then(ConfigTree::as_str)
+            .map(str::to_ascii_lowercase);
+        let is_grammar = match schema.as_deref() {
+            Some("vscode://schemas/color-theme") => false,
+            Some(schema)
+                if schema == "vscode://schemas/textmate-colorizer"
+                    || schema.ends_with("tmlanguage.json") =>
+            {
+                true
+            }
+            _ => cfg.grammar_scope_name().is_some(),
+        };
+
+        if is_grammar {
+            self.insert_grammar(cfg).map(drop)
+        } else {
+            self.themes.insert(name.to_string(), cfg);
+            Ok(())
+        }
+    }
+
+    /// Store a grammar by its `scopeName`, which it is an error not to have.
+    /// Returns the scope name that the grammar is stored under.
+    fn insert_grammar(&mut self, cfg: Config) -> Result<String, Error> {
+        let Some(scope) = cfg.grammar_scope_name() else {
+            return Err(Error::GrammarError {
+                err: "missing required field `scopeName`".to_string(),
+                file_name: cfg.file_name().to_path_buf(),
+            });
+        };
+
+        let scope = scope.to_string();
+        if self.grammars.contains_key(&scope) {
+            match self.duplicate_policy {
+                DuplicatePolicy::Error => {
+                    return Err(Error::GrammarError {
+                        err: format!("a grammar with the scope name `{scope}` was already added"),
+                        file_name: cfg.file_name().to_path_buf(),
+                    });
+                }
+                DuplicatePolicy::PreferFirst => return Ok(scope),
+                DuplicatePolicy::PreferLast => (),
+            }
+        }
+
+        self.compiled.get_mut().remove(&scope);
+        self.grammars.insert(scope.clone(), cfg);
+        // other grammars can include this one, so any of the results may change
+        self.first_chars.get_mut().clear();
+        self.parse_cache.get_mut().clear();
+
+        Ok(scope)
+    }
+
     // // Get the theme for a given name (or default if there isn't one already).  Allows for more complex construction of themes, i.e.
     // // if you want to merge them, read them, modify them based on code, etc. (do the same for grammars)
     // fn theme(name: &str) -> Theme<'a> {}
@@ -52,3 +575,584 @@ impl Registry {
     // fn parse(&self, input: &str) -> output {}
     // fn parse_mut(&mut self, input: &str) -> output {}
 }
+
+/// Give a grammar a `scopeName` if it doesn't already have one
+fn default_scope_name(cfg: &mut Config, scope: &str) {
+    if cfg.grammar_scope_name().is_none()
+        && let ConfigTree::Object(value) = cfg.tree_mut()
+    {
+        value.insert(
+            "scopeName".to_string(),
+            ConfigTree::String(scope.to_string()),
+        );
+    }
+}
+
+/// Find the scopes of other grammars referenced by `include` rules within a
+/// grammar.  Includes can refer to the grammar itself (`$self`, `$base`), its
+/// own repository (`#name`), another grammar (`source.c`), or a repository
+/// entry within another grammar (`source.c#name`).
+fn external_scopes(tree: &ConfigTree, scopes: &mut Vec<String>) {
+    match tree {
+        ConfigTree::Object(value) => {
+            if let Some(include) = value.get("include").and_then(ConfigTree::as_str) {
+                let scope = include.split('#').next().unwrap_or("");
+                if !scope.is_empty() && !scope.starts_with('$') {
+                    scopes.push(scope.to_string());
+                }
+            }
+
+            value
+                .values()
+                .for_each(|value| external_scopes(value, scopes));
+        }
+        ConfigTree::Array(value) => value
+            .iter()
+            .for_each(|value| external_scopes(value, scopes)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Colour, FontStyle};
+
+    const MAKEFILE: &str = r##"{
+        "name": "Makefile",
+        "scopeName": "source.makefile",
+        "fileTypes": ["Makefile", "mk"],
+        "patterns": [{"match": "#.*", "name": "comment.line"}]
+    }"##;
+
+    #[test]
+    fn add_with_format_json_grammar() {
+        let mut registry = Registry::new();
+        registry
+            .add_with_format("make.json", MAKEFILE, ConfigFormat::Json)
+            .unwrap();
+
+        let expected = Config::from_json("make.json", MAKEFILE).unwrap();
+        assert_eq!(
+            registry
+                .grammar_for_scope("source.makefile")
+                .unwrap()
+                .tree(),
+            expected.tree()
+        );
+    }
+
+    #[test]
+    fn add_with_format_malformed() {
+        let mut registry = Registry::new();
+        let err = registry
+            .add_with_format("make.json", "{\"scopeName\": ", ConfigFormat::Json)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::SerdeJson { .. }), "{err}");
+    }
+
+    #[test]
+    fn add_bytes_detects_binary_plists() {
+        // a binary plist holding the single string "Make"
+        let mut binary = b"bplist00\x54Make\x08".to_vec();
+        binary.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 1]);
+        binary.extend_from_slice(&1u64.to_be_bytes());
+        binary.extend_from_slice(&0u64.to_be_bytes());
+        binary.extend_from_slice(&13u64.to_be_bytes());
+
+        let mut registry = Registry::new();
+        registry.add_bytes("make.plist", &binary).unwrap();
+        registry
+            .add_bytes("make.json", MAKEFILE.as_bytes())
+            .unwrap();
+
+        assert_eq!(
+            registry.theme("make.plist").unwrap().tree(),
+            &crate::ConfigTree::String("Make".to_string())
+        );
+        let expected = Config::from_json("make.json", MAKEFILE).unwrap();
+        assert_eq!(
+            registry
+                .grammar_for_scope("source.makefile")
+                .unwrap()
+                .tree(),
+            expected.tree()
+        );
+
+        let err = registry.add_bytes("bad", b"\xFF\xFE").unwrap_err();
+        assert!(matches!(err, Error::PlistError { .. }), "{err}");
+        assert!(!registry.themes.contains_key("bad"));
+    }
+
+    #[test]
+    fn grammars_stored_by_scope() {
+        let mut registry = Registry::new();
+        registry.add("make.json", MAKEFILE).unwrap();
+        registry
+            .add("dark.json", r##"{"name": "Dark", "colors": {}}"##)
+            .unwrap();
+
+        let grammar = registry.grammar_for_scope("source.makefile").unwrap();
+        assert_eq!(grammar.grammar_name(), Some("Makefile"));
+        assert!(registry.theme("make.json").is_none());
+
+        assert_eq!(
+            registry.theme("dark.json").unwrap().grammar_name(),
+            Some("Dark")
+        );
+        assert!(registry.grammar_for_scope("dark.json").is_none());
+    }
+
+    #[test]
+    fn add_grammar_and_theme_explicitly() {
+        let mut registry = Registry::new();
+        registry.add_theme("make-theme.json", MAKEFILE).unwrap();
+        registry
+            .add_grammar(
+                "plain.yaml",
+                "name: Plain\nscopeName: text.plain\npatterns: []\n",
+            )
+            .unwrap();
+
+        assert!(registry.theme("make-theme.json").is_some());
+        assert!(registry.grammar_for_scope("source.makefile").is_none());
+
+        let grammar = registry.grammar_for_scope("text.plain").unwrap();
+        assert_eq!(grammar.grammar_name(), Some("Plain"));
+        assert!(registry.theme("plain.yaml").is_none());
+    }
+
+    #[test]
+    fn load_dir_collects_errors() {
+        let dir = std::env::temp_dir().join(format!("multi-light-load-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("make.json"), MAKEFILE).unwrap();
+        std::fs::write(dir.join("broken.json"), "{\"scopeName\": ").unwrap();
+
+        let mut registry = Registry::new();
+        let result = registry.load_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(registry.grammar_for_scope("source.makefile").is_some());
+        match result {
+            Err(Error::Multiple { errors, file_name }) => {
+                assert_eq!(file_name, dir);
+                assert_eq!(errors.len(), 1, "{errors:?}");
+                assert!(
+                    matches!(&errors[0], Error::SerdeJson { file_name, .. }
+                    if file_name.ends_with("broken.json")),
+                    "{errors:?}"
+                );
+            }
+            other => panic!("expected multiple errors, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resolve_includes_with_read_file() {
+        let mut registry = Registry::new();
+        registry
+            .add(
+                "c.json",
+                r##"{"scopeName": "source.c", "patterns": [
+                    {"include": "$self"},
+                    {"include": "#strings"},
+                    {"include": "source.h#macros"}
+                ]}"##,
+            )
+            .unwrap();
+
+        let requested = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = requested.clone();
+        registry.on_read_file(move |scope| {
+            log.borrow_mut().push(scope.to_string());
+            match scope {
+                "source.h" => Ok(r##"{"scopeName": "source.h",
+                    "patterns": [{"include": "source.c"}]}"##
+                    .to_string()),
+                _ => panic!("unexpected scope {scope}"),
+            }
+        });
+
+        registry.resolve_includes("source.c").unwrap();
+        assert_eq!(*requested.borrow(), ["source.h"]);
+        assert!(registry.grammar_for_scope("source.h").is_some());
+
+        // already loaded grammars aren't read again
+        registry.resolve_includes("source.c").unwrap();
+        assert_eq!(requested.borrow().len(), 1);
+    }
+
+    #[test]
+    fn resolve_includes_without_read_file() {
+        let mut registry = Registry::new();
+        registry
+            .add(
+                "c.json",
+                r##"{"scopeName": "source.c", "patterns": [{"include": "source.h"}]}"##,
+            )
+            .unwrap();
+
+        registry.resolve_includes("source.c").unwrap();
+        assert!(registry.grammar_for_scope("source.h").is_none());
+    }
+
+    #[test]
+    fn detect_language_from_file_types() {
+        let mut registry = Registry::new();
+        registry.add("make.json", MAKEFILE).unwrap();
+
+        assert_eq!(
+            registry.detect_language("src/Makefile"),
+            Some("source.makefile")
+        );
+        assert_eq!(
+            registry.detect_language("rules.MK"),
+            Some("source.makefile")
+        );
+        assert_eq!(registry.detect_language("makefile.txt"), None);
+        assert_eq!(registry.detect_language("mk"), Some("source.makefile"));
+        assert_eq!(registry.detect_language("foomk"), None);
+    }
+
+    #[test]
+    fn detect_language_by_first_line() {
+        let mut registry = Registry::new();
+        registry
+            .add(
+                "sh.json",
+                r##"{"scopeName": "source.shell", "firstLineMatch": "^#!.*\\b(bash|sh)\\b"}"##,
+            )
+            .unwrap();
+        registry
+            .add(
+                "broken.json",
+                r##"{"scopeName": "source.broken", "firstLineMatch": "("}"##,
+            )
+            .unwrap();
+
+        assert_eq!(
+            registry.detect_language_by_content("#!/bin/sh"),
+            Some("source.shell")
+        );
+        assert_eq!(
+            registry.detect_language_by_content("#!/usr/bin/env bash"),
+            Some("source.shell")
+        );
+        assert_eq!(registry.detect_language_by_content("echo hi"), None);
+        assert_eq!(registry.detect_language_by_content("("), None);
+
+        // compiled once, including the regex that failed
+        let regexes = registry.regexes.borrow();
+        assert_eq!(regexes.len(), 2);
+        assert!(regexes["("].is_err());
+    }
+
+    #[test]
+    fn add_grammar_with_scope_override() {
+        let fragment = r#"{"patterns": [{"match": "x", "name": "keyword"}]}"#;
+
+        let mut registry = Registry::new();
+        registry
+            .add_grammar_with_scope("fragment.json", fragment, "source.fragment")
+            .unwrap();
+
+        let grammar = registry.grammar_for_scope("source.fragment").unwrap();
+        assert_eq!(grammar.grammar_scope_name(), Some("source.fragment"));
+
+        // a scope in the grammar itself wins
+        registry
+            .add_grammar_with_scope("make.json", MAKEFILE, "source.other")
+            .unwrap();
+        assert!(registry.grammar_for_scope("source.makefile").is_some());
+        assert!(registry.grammar_for_scope("source.other").is_none());
+    }
+
+    #[test]
+    fn add_grammar_without_scope() {
+        let mut registry = Registry::new();
+        let err = registry
+            .add_grammar("fragment.json", r#"{"patterns": []}"#)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::GrammarError { .. }), "{err}");
+        assert!(err.to_string().contains("scopeName"), "{err}");
+    }
+
+    const MAKEFILE_PLIST: &str = "<plist><dict>\
+        <key>name</key><string>Makefile (plist)</string>\
+        <key>scopeName</key><string>source.makefile</string>\
+        </dict></plist>";
+
+    fn add_both(policy: DuplicatePolicy) -> (Registry, Result<(), Error>) {
+        let mut registry = Registry::new();
+        registry.set_duplicate_policy(policy);
+        registry.add_grammar("make.json", MAKEFILE).unwrap();
+        let second = registry.add_grammar("make.tmLanguage", MAKEFILE_PLIST);
+        (registry, second)
+    }
+
+    #[test]
+    fn duplicate_policy_prefer_last() {
+        let (registry, second) = add_both(DuplicatePolicy::PreferLast);
+        second.unwrap();
+
+        let grammar = registry.grammar_for_scope("source.makefile").unwrap();
+        assert_eq!(grammar.grammar_name(), Some("Makefile (plist)"));
+    }
+
+    #[test]
+    fn duplicate_policy_prefer_first() {
+        let (registry, second) = add_both(DuplicatePolicy::PreferFirst);
+        second.unwrap();
+
+        let grammar = registry.grammar_for_scope("source.makefile").unwrap();
+        assert_eq!(grammar.grammar_name(), Some("Makefile"));
+    }
+
+    #[test]
+    fn duplicate_policy_error() {
+        let (registry, second) = add_both(DuplicatePolicy::Error);
+
+        let err = second.unwrap_err();
+        assert!(err.to_string().contains("source.makefile"), "{err}");
+        let grammar = registry.grammar_for_scope("source.makefile").unwrap();
+        assert_eq!(grammar.grammar_name(), Some("Makefile"));
+    }
+
+    #[test]
+    fn resolve_includes_of_renamed_grammar() {
+        let requested = Rc::new(RefCell::new(vec![]));
+
+        let mut registry = Registry::new();
+        registry
+            .add_grammar(
+                "main.json",
+                r#"{"scopeName": "source.main", "patterns": [{"include": "source.c"}]}"#,
+            )
+            .unwrap();
+
+        let log = Rc::clone(&requested);
+        registry.on_read_file(move |scope| {
+            log.borrow_mut().push(scope.to_string());
+            Ok(match scope {
+                // stored under its own scope name, not the one asked for
+                "source.c" => {
+                    r#"{"scopeName": "source.cpp", "patterns": [{"include": "source.h#x"}]}"#
+                }
+                _ => r#"{"patterns": []}"#,
+            }
+            .to_string())
+        });
+        registry.resolve_includes("source.main").unwrap();
+
+        assert_eq!(*requested.borrow(), ["source.c", "source.h"]);
+        assert!(registry.grammar_for_scope("source.cpp").is_some());
+        assert!(registry.grammar_for_scope("source.h").is_some());
+    }
+
+    /// Registries with the same grammars added in every order
+    fn registries_in_each_order(grammars: &[&str]) -> Vec<Registry> {
+        let mut orders = vec![grammars.to_vec()];
+        let mut reversed = grammars.to_vec();
+        reversed.reverse();
+        orders.push(reversed);
+
+        orders
+            .into_iter()
+            .map(|order| {
+                let mut registry = Registry::new();
+                for grammar in order {
+                    registry.add_grammar("grammar.json", grammar).unwrap();
+                }
+                registry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detect_language_by_file_type() {
+        let mut registry = Registry::new();
+        registry.add_grammar("make.json", MAKEFILE).unwrap();
+
+        assert_eq!(
+            registry.detect_language("src/foo.MK"),
+            Some("source.makefile")
+        );
+        assert_eq!(
+            registry.detect_language("Makefile"),
+            Some("source.makefile")
+        );
+        assert_eq!(registry.detect_language("foo.mkx"), None);
+        assert_eq!(registry.detect_language("foomk"), None);
+    }
+
+    #[test]
+    fn detect_language_tie_break() {
+        let grammars = [
+            r#"{"scopeName": "source.b", "fileTypes": ["h"]}"#,
+            r#"{"scopeName": "source.a", "fileTypes": ["H"]}"#,
+            r#"{"scopeName": "source.c", "fileTypes": ["h"]}"#,
+        ];
+
+        for registry in registries_in_each_order(&grammars) {
+            assert_eq!(registry.detect_language("x.h"), Some("source.a"));
+        }
+    }
+
+    #[test]
+    fn detect_language_by_content_tie_break() {
+        let grammars = [
+            r#"{"scopeName": "source.b", "firstLineMatch": "^#!"}"#,
+            r#"{"scopeName": "source.a", "firstLineMatch": "^#!.*sh"}"#,
+            r#"{"scopeName": "source.c", "firstLineMatch": "^#!/bin"}"#,
+        ];
+
+        for registry in registries_in_each_order(&grammars) {
+            assert_eq!(
+                registry.detect_language_by_content("#!/bin/bash"),
+                Some("source.a")
+            );
+            assert_eq!(
+                registry.detect_language_by_content("#!/bin/env"),
+                Some("source.b")
+            );
+        }
+    }
+
+    #[test]
+    fn detect_language_by_shebang() {
+        let shell = r#"{"scopeName": "source.shell", "firstLineMatch": "^#!.*\\b(bash|sh)\\b"}"#;
+        let mut registry = Registry::new();
+        registry.add_grammar("shell.json", shell).unwrap();
+        registry.add_grammar("make.json", MAKEFILE).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(
+                registry.detect_language_by_content("#!/bin/bash"),
+                Some("source.shell")
+            );
+            assert_eq!(registry.detect_language_by_content("all: build"), None);
+        }
+        assert_eq!(registry.regexes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn resolve_style_stacks_themes() {
+        let mut registry = Registry::new();
+        registry
+            .add_theme(
+                "overrides.json",
+                r##"{"tokenColors": [
+                    {"scope": "keyword", "settings": {"foreground": "#ff0000"}}
+                ]}"##,
+            )
+            .unwrap();
+        registry
+            .add_theme(
+                "base.json",
+                r##"{
+                    "colors": {"editor.foreground": "#eeeeee", "editor.background": "#111111"},
+                    "tokenColors": [
+                        {"scope": "comment", "settings": {"foreground": "#00ff00", "fontStyle": "italic"}},
+                        {"scope": "keyword", "settings": {"foreground": "#0000ff", "fontStyle": "bold"}}
+                    ]
+                }"##,
+            )
+            .unwrap();
+        registry
+            .set_active_themes(&["overrides.json", "base.json"])
+            .unwrap();
+
+        let colour = |hex| Colour::from_hex(hex);
+        let scopes = |scopes: &[&str]| scopes.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // only the top theme's rule applies, not the base theme's font style
+        let keyword = registry.resolve_style(&scopes(&["source.test", "keyword.control"]));
+        assert_eq!(keyword.foreground, colour("#ff0000"));
+        assert_eq!(keyword.background, colour("#111111"));
+        assert_eq!(keyword.font_style, FontStyle::default());
+
+        let comment = registry.resolve_style(&scopes(&["source.test", "comment.line"]));
+        assert_eq!(comment.foreground, colour("#00ff00"));
+        assert_eq!(comment.background, colour("#111111"));
+        assert!(comment.font_style.italic);
+
+        let other = registry.resolve_style(&scopes(&["source.test"]));
+        assert_eq!(other.foreground, colour("#eeeeee"));
+        assert_eq!(other.background, colour("#111111"));
+
+        registry.set_active_themes(&[]).unwrap();
+        assert_eq!(
+            registry.resolve_style(&scopes(&["source.test", "comment"])),
+            ResolvedStyle::default()
+        );
+    }
+
+    #[test]
+    fn set_active_themes_unknown_name() {
+        let mut registry = Registry::new();
+        let err = registry.set_active_themes(&["missing.json"]).unwrap_err();
+
+        assert!(
+            matches!(&err, Error::ThemeNotFound { name } if name == "missing.json"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn classify_by_schema() {
+        let mut registry = Registry::new();
+
+        // a theme with a `scopeName` would otherwise be taken as a grammar
+        registry
+            .add(
+                "theme.json",
+                r#"{
+                    "$schema": "vscode://schemas/color-theme",
+                    "scopeName": "theme.dark",
+                    "tokenColors": []
+                }"#,
+            )
+            .unwrap();
+        assert!(registry.theme("theme.json").is_some());
+        assert!(registry.grammar_for_scope("theme.dark").is_none());
+
+        registry
+            .add(
+                "grammar.json",
+                r#"{
+                    "$schema": "https://raw.githubusercontent.com/martinring/tmlanguage/master/tmlanguage.json",
+                    "scopeName": "source.test",
+                    "patterns": []
+                }"#,
+            )
+            .unwrap();
+        assert!(registry.grammar_for_scope("source.test").is_some());
+
+        // a grammar schema without a `scopeName` is still a grammar, which is
+        // an error rather than being stored as a theme
+        let err = registry
+            .add(
+                "fragment.json",
+                r#"{"$schema": "vscode://schemas/textmate-colorizer", "patterns": []}"#,
+            )
+            .unwrap_err();
+        assert!(matches!(err, Error::GrammarError { .. }), "{err}");
+        assert!(registry.theme("fragment.json").is_none());
+
+        // unknown schemas fall back to looking for a `scopeName`
+        registry
+            .add(
+                "other.json",
+                r#"{"$schema": "https://example.com/schema.json", "scopeName": "source.other"}"#,
+            )
+            .unwrap();
+        assert!(registry.grammar_for_scope("source.other").is_some());
+        registry
+            .add("plain.json", r#"{"tokenColors": []}"#)
+            .unwrap();
+        assert!(registry.theme("plain.json").is_some());
+    }
+}
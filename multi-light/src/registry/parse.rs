@@ -0,0 +1,635 @@
+//! Tokenizing whole documents, one line after another, using
+//! `Registry::tokenize_line`.
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
+};
+
+use crate::{Error, RuleId, Token, TokenizeState};
+
+use super::Registry;
+
+/// Scope names, numbered in the order they were first seen, shared by the
+/// tokens from `Registry::parse_indexed` so that each token only stores
+/// indices into the table rather than copies of the names
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScopeTable {
+    names: Vec<String>,
+    indices: HashMap<String, u32>,
+}
+
+impl ScopeTable {
+    /// Create an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the name of the scope with the given index, if it is in the table
+    pub fn name(&self, index: u32) -> Option<&str> {
+        self.names.get(index as usize).map(String::as_str)
+    }
+
+    /// Get the index of a scope name, if it is in the table
+    pub fn index(&self, name: &str) -> Option<u32> {
+        self.indices.get(name).copied()
+    }
+
+    /// Number of scope names in the table
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Whether the table has no scope names
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Get the index of a scope name, adding it to the table if needed
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(index) = self.index(name) {
+            return index;
+        }
+
+        let index = self.names.len() as u32;
+        self.names.push(name.to_string());
+        self.indices.insert(name.to_string(), index);
+        index
+    }
+}
+
+/// A token from `Registry::parse_indexed`, the same as `Token` except that
+/// its scopes are indices into a `ScopeTable`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedToken {
+    /// Byte range of the token within the line
+    pub text_range: Range<usize>,
+    pub scopes: Vec<u32>,
+}
+
+/// A line whose tokens are different between two versions of a document, see
+/// `Registry::diff_tokens`.  Lines are compared by their index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineChange {
+    /// The line at this index in the new document has different tokens to
+    /// the line at the same index in the old one, or is past its end
+    Changed { line: usize, tokens: Vec<Token> },
+
+    /// The old document had a line at this index, but the new one doesn't
+    Removed { line: usize },
+}
+
+/// A line that couldn't be tokenized by `Registry::parse_isolated`
+#[derive(Debug)]
+pub struct LineError {
+    /// Index of the line within the document, starting from 0
+    pub line: usize,
+    pub error: Error,
+}
+
+impl Registry {
+    /// Split a document into tokens, using the grammar for `scope`, giving
+    /// the tokens of each line.  Lines end at `\n` or `\r\n`, and line endings
+    /// aren't included in the tokens.  Stops at the first line that fails to
+    /// tokenize.  Results are reused for the same input if caching has been
+    /// turned on with `set_parse_cache_capacity`.
+    pub fn parse(&self, scope: &str, input: &str) -> Result<Vec<Vec<Token>>, Error> {
+        if self.parse_cache_capacity == 0 {
+            return self
+                .parse_with_end_state(scope, input)
+                .map(|(lines, _)| lines);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        input.hash(&mut hasher);
+        let key = (scope.to_string(), hasher.finish());
+
+        if let Some(lines) = self.parse_cache.borrow().get(&key) {
+            return Ok(lines.clone());
+        }
+
+        let (lines, _) = self.parse_with_end_state(scope, input)?;
+
+        let mut cache = self.parse_cache.borrow_mut();
+        if cache.len() >= self.parse_cache_capacity {
+            cache.shift_remove_index(0);
+        }
+        cache.insert(key, lines.clone());
+
+        Ok(lines)
+    }
+
+    /// Keep the results of up to `capacity` calls to `parse`, so that parsing
+    /// the same input with the same grammar again doesn't tokenize it again.
+    /// Results are found by a hash of the input, and the oldest is dropped
+    /// once the cache is full.  Adding a grammar or changing the
+    /// `on_read_file` function empties the cache.  Defaults to 0, caching
+    /// nothing.
+    pub fn set_parse_cache_capacity(&mut self, capacity: usize) {
+        self.parse_cache_capacity = capacity;
+
+        let cache = self.parse_cache.get_mut();
+        while cache.len() > capacity {
+            cache.shift_remove_index(0);
+        }
+    }
+
+    /// Split a document into tokens, the same as `parse`, also giving the
+    /// state at the end of the last line.  Passing that state to
+    /// `Registry::tokenize_line` for the lines of more text gives the same
+    /// tokens as parsing the document with the text appended, as long as the
+    /// document ended with a line ending, so that the text starts a new line.
+    pub fn parse_with_end_state(
+        &self,
+        scope: &str,
+        input: &str,
+    ) -> Result<(Vec<Vec<Token>>, TokenizeState), Error> {
+        let mut state = TokenizeState::new();
+
+        let lines = input
+            .lines()
+            .map(|line| self.tokenize_line(scope, line, &mut state))
+            .collect::<Result<_, _>>()?;
+
+        Ok((lines, state))
+    }
+
+    /// Split a document into tokens, the same as `parse`, with each token's
+    /// scopes given as indices into the returned table of scope names
+    pub fn parse_indexed(
+        &self,
+        scope: &str,
+        input: &str,
+    ) -> Result<(Vec<Vec<IndexedToken>>, ScopeTable), Error> {
+        let mut table = ScopeTable::new();
+
+        let lines = self
+            .parse(scope, input)?
+            .into_iter()
+            .map(|tokens| {
+                tokens
+                    .into_iter()
+                    .map(|token| IndexedToken {
+                        text_range: token.text_range,
+                        scopes: token.scopes.iter().map(|name| table.intern(name)).collect(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok((lines, table))
+    }
+
+    /// Count how many times each rule matched while tokenizing a document
+    /// with the grammar for `scope`, including the rules of other grammars
+    /// that it includes, e.g. to find rules that never match in a sample
+    /// file.  Rules that never matched are left out, and the `end` of a
+    /// `begin`/`end` rule isn't counted separately from its `begin`.
+    pub fn coverage(&self, scope: &str, input: &str) -> Result<HashMap<RuleId, usize>, Error> {
+        let mut state = TokenizeState::new();
+        let mut coverage = HashMap::new();
+
+        for line in input.lines() {
+            self.tokenize_line_counting(scope, line, &mut state, Some(&mut coverage))?;
+        }
+
+        Ok(coverage)
+    }
+
+    /// Split only some lines of a document into tokens, e.g. to show an
+    /// excerpt of a file, giving the tokens of each line in `lines`, by index
+    /// starting from 0.  The lines before the range still have to be
+    /// tokenized, as rules left open by earlier lines change how later lines
+    /// are split, but their tokens are thrown away, and nothing after the
+    /// range is tokenized.  Lines past the end of the document are left out.
+    pub fn parse_range(
+        &self,
+        scope: &str,
+        input: &str,
+        lines: Range<usize>,
+    ) -> Result<Vec<Vec<Token>>, Error> {
+        let mut state = TokenizeState::new();
+        let mut tokens = vec![];
+
+        for (idx, line) in input.lines().enumerate().take(lines.end) {
+            let line = self.tokenize_line(scope, line, &mut state)?;
+            if idx >= lines.start {
+                tokens.push(line);
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// Split a document into tokens, the same as `parse`, except that a line
+    /// failing to tokenize, e.g. because of an invalid regex in the grammar,
+    /// doesn't stop the rest of the document being tokenized.  The line is
+    /// given a single token with the grammar's scope, and the next line starts
+    /// again with no rules open.  Only a missing grammar for `scope` is an
+    /// error; the errors of each failed line are returned with the tokens.
+    pub fn parse_isolated(
+        &self,
+        scope: &str,
+        input: &str,
+    ) -> Result<(Vec<Vec<Token>>, Vec<LineError>), Error> {
+        let grammar = self.compiled_grammar(scope)?;
+
+        let mut state = TokenizeState::new();
+        let mut errors = vec![];
+
+        let lines = input
+            .lines()
+            .enumerate()
+            .map(
+                |(idx, line)| match self.tokenize_line(scope, line, &mut state) {
+                    Ok(tokens) => tokens,
+                    Err(error) => {
+                        errors.push(LineError { line: idx, error });
+                        state = TokenizeState::new();
+
+                        let fallback = Token {
+                            text_range: 0..line.len(),
+                            scopes: vec![grammar.scope_name.clone()],
+                        };
+                        if line.is_empty() {
+                            vec![]
+                        } else {
+                            vec![fallback]
+                        }
+                    }
+                },
+            )
+            .collect();
+
+        Ok((lines, errors))
+    }
+
+    /// Find the lines whose tokens changed between two versions of a
+    /// document, e.g. after an edit, using the grammar for `scope`.  Each line
+    /// of `new` is compared to the line with the same index in `old`, so
+    /// adding or removing a line reports every line after it that differs
+    /// from the line that used to be there.  A line whose text changed is
+    /// always reported, even if its tokens' ranges and scopes are the same.
+    /// A line with the same text in
+    /// both is only tokenized once while the state before it is the same in
+    /// both, so an edit that doesn't change which rules are open at the end
+    /// of its line only costs tokenizing that line twice.
+    pub fn diff_tokens(&self, scope: &str, old: &str, new: &str) -> Result<Vec<LineChange>, Error> {
+        let old: Vec<_> = old.lines().collect();
+        let new: Vec<_> = new.lines().collect();
+
+        let mut old_state = TokenizeState::new();
+        let mut new_state = TokenizeState::new();
+        // whether `old_state` is out of date because it is the same as
+        // `new_state`, so that lines that are the same in both only need
+        // tokenizing once
+        let mut in_sync = true;
+        let mut changes = vec![];
+
+        for line in 0..old.len().max(new.len()) {
+            let (old_line, new_line) = match (old.get(line), new.get(line)) {
+                (Some(old_line), Some(new_line)) => (old_line, new_line),
+                (None, Some(new_line)) => {
+                    let tokens = self.tokenize_line(scope, new_line, &mut new_state)?;
+                    changes.push(LineChange::Changed { line, tokens });
+                    continue;
+                }
+                (Some(_), None) => {
+                    changes.push(LineChange::Removed { line });
+                    continue;
+                }
+                (None, None) => unreachable!(),
+            };
+
+            if in_sync && old_line == new_line {
+                self.tokenize_line(scope, new_line, &mut new_state)?;
+                continue;
+            }
+
+            if in_sync {
+                old_state = new_state.clone();
+            }
+
+            let old_tokens = self.tokenize_line(scope, old_line, &mut old_state)?;
+            let tokens = self.tokenize_line(scope, new_line, &mut new_state)?;
+            in_sync = old_state == new_state;
+
+            if old_line != new_line || old_tokens != tokens {
+                changes.push(LineChange::Changed { line, tokens });
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = r#"{
+        "scopeName": "source.test",
+        "patterns": [
+            {"match": "\\bif\\b", "name": "keyword.control"},
+            {"begin": "\\(", "end": "\\)", "name": "meta.group", "patterns": [
+                {"match": "[", "name": "invalid"}
+            ]}
+        ]
+    }"#;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.add_grammar("test.json", GRAMMAR).unwrap();
+        registry
+    }
+
+    #[test]
+    fn parse_uses_state_across_lines() {
+        let registry = registry();
+        let lines = registry.parse("source.test", "if\r\nif\n").unwrap();
+
+        assert_eq!(lines.len(), 2);
+        for tokens in lines {
+            assert_eq!(tokens.len(), 1);
+            assert_eq!(tokens[0].scopes, ["source.test", "keyword.control"]);
+        }
+    }
+
+    #[test]
+    fn parse_stops_at_failing_line() {
+        let registry = registry();
+        let err = registry.parse("source.test", "if\n(x)\nif").unwrap_err();
+        assert!(matches!(err, Error::GrammarError { .. }), "{err}");
+    }
+
+    #[test]
+    fn parse_isolated_falls_back_for_failing_line() {
+        let registry = registry();
+        let input = "if\n(x\nif\n";
+        let (lines, errors) = registry.parse_isolated("source.test", input).unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 1);
+        assert!(errors[0].error.to_string().contains("invalid regex"));
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[1],
+            [Token {
+                text_range: 0..2,
+                scopes: vec!["source.test".to_string()],
+            }]
+        );
+
+        // the group left open by the failed line is closed again
+        assert_eq!(lines[2], registry.parse("source.test", "if").unwrap()[0]);
+    }
+
+    #[test]
+    fn parse_isolated_unknown_grammar() {
+        assert!(registry().parse_isolated("source.other", "if").is_err());
+    }
+
+    #[test]
+    fn parse_range_matches_full_parse() {
+        let mut registry = Registry::new();
+        registry
+            .add_grammar(
+                "comments.json",
+                r#"{
+                    "scopeName": "source.comments",
+                    "patterns": [
+                        {"begin": "/\\*", "end": "\\*/", "name": "comment.block"},
+                        {"match": "\\bif\\b", "name": "keyword.control"}
+                    ]
+                }"#,
+            )
+            .unwrap();
+
+        let input = "if\n/* if\nif\nif */ if\nif\n";
+        let full = registry.parse("source.comments", input).unwrap();
+
+        for range in [0..5, 1..3, 2..4, 4..5, 3..10] {
+            let excerpt = registry
+                .parse_range("source.comments", input, range.clone())
+                .unwrap();
+            let end = range.end.min(full.len());
+            assert_eq!(excerpt, full[range.start..end], "{range:?}");
+        }
+
+        assert!(
+            registry
+                .parse_range("source.comments", input, 6..8)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn parse_unknown_scope() {
+        let registry = registry();
+
+        for err in [
+            registry.parse("source.missing", "if\n").unwrap_err(),
+            registry
+                .parse_range("source.missing", "if\n", 0..1)
+                .unwrap_err(),
+            registry
+                .parse_isolated("source.missing", "if\n")
+                .unwrap_err(),
+        ] {
+            assert!(
+                matches!(&err, Error::GrammarNotFound { scope } if scope == "source.missing"),
+                "{err:?}"
+            );
+            assert_eq!(
+                err.to_string(),
+                "No grammar with the scope name `source.missing` was found"
+            );
+        }
+
+        // the error for a grammar that exists but is invalid is different
+        let mut registry = Registry::new();
+        registry
+            .add_grammar("bad.json", r#"{"scopeName": "source.bad", "patterns": 1}"#)
+            .unwrap();
+        let err = registry.parse("source.bad", "if\n").unwrap_err();
+        assert!(matches!(err, Error::GrammarError { .. }), "{err:?}");
+    }
+
+    #[test]
+    fn continue_from_end_state() {
+        let mut registry = Registry::new();
+        registry
+            .add_grammar(
+                "test.json",
+                r#"{
+                    "scopeName": "source.test",
+                    "patterns": [
+                        {"match": "\\bif\\b", "name": "keyword.control"},
+                        {"begin": "\\(", "end": "\\)", "name": "meta.group"}
+                    ]
+                }"#,
+            )
+            .unwrap();
+        let first = "if (\nif\n";
+        let second = "if ) if\nif\n";
+
+        let (mut lines, mut state) = registry.parse_with_end_state("source.test", first).unwrap();
+        assert_eq!(lines.len(), 2);
+        for line in second.lines() {
+            lines.push(
+                registry
+                    .tokenize_line("source.test", line, &mut state)
+                    .unwrap(),
+            );
+        }
+
+        let whole = registry
+            .parse("source.test", &format!("{first}{second}"))
+            .unwrap();
+        assert_eq!(lines, whole);
+
+        // the group opened in the first part is still open in the second
+        assert_eq!(whole[2][0].scopes, ["source.test", "meta.group"]);
+    }
+
+    #[test]
+    fn diff_tokens_reports_changed_lines() {
+        let mut registry = Registry::new();
+        registry
+            .add_grammar(
+                "test.json",
+                r#"{
+                    "scopeName": "source.test",
+                    "patterns": [
+                        {"match": "\\bif\\b", "name": "keyword.control"},
+                        {"begin": "\\(", "end": "\\)", "name": "meta.group"}
+                    ]
+                }"#,
+            )
+            .unwrap();
+        let changed = |changes: Vec<LineChange>| {
+            changes
+                .into_iter()
+                .map(|change| match change {
+                    LineChange::Changed { line, .. } => format!("changed {line}"),
+                    LineChange::Removed { line } => format!("removed {line}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let old = "if\nx\nif\n) if\nif\n";
+
+        let diff = |new| registry.diff_tokens("source.test", old, new).unwrap();
+        assert_eq!(diff(old), []);
+
+        // a change that doesn't open or close a rule only affects its line
+        assert_eq!(changed(diff("if\nif\nif\n) if\nif\n")), ["changed 1"]);
+
+        // opening a group changes the lines up to where it is closed
+        assert_eq!(
+            changed(diff("if\n(\nif\n) if\nif\n")),
+            ["changed 1", "changed 2", "changed 3"]
+        );
+
+        assert_eq!(changed(diff("if\nx\nif\n")), ["removed 3", "removed 4"]);
+        assert_eq!(changed(diff("if\nx\nif\n) if\nif\nif\n")), ["changed 5"]);
+
+        let changes = diff("if\nx\nif\n) if\n(\n");
+        let expected = registry.parse("source.test", "(").unwrap().remove(0);
+        assert_eq!(
+            changes,
+            [LineChange::Changed {
+                line: 4,
+                tokens: expected
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_indexed_resolves_names() {
+        let registry = registry();
+        let input = "if  if\nif\n";
+        let (lines, table) = registry.parse_indexed("source.test", input).unwrap();
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.name(0), Some("source.test"));
+        assert_eq!(table.name(1), Some("keyword.control"));
+        assert_eq!(table.name(2), None);
+        assert_eq!(table.index("keyword.control"), Some(1));
+
+        let expected = registry.parse("source.test", input).unwrap();
+        assert_eq!(lines.len(), expected.len());
+        for (line, expected) in lines.iter().zip(&expected) {
+            assert_eq!(line.len(), expected.len());
+            for (token, expected) in line.iter().zip(expected) {
+                assert_eq!(token.text_range, expected.text_range);
+                let names: Vec<_> = token
+                    .scopes
+                    .iter()
+                    .map(|&index| table.name(index).unwrap())
+                    .collect();
+                assert_eq!(names, expected.scopes);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_cache_reuses_results() {
+        let mut registry = registry();
+        let runs = |registry: &Registry| registry.probe.lines_tokenized.get();
+
+        registry.parse("source.test", "if\nif").unwrap();
+        registry.parse("source.test", "if\nif").unwrap();
+        assert_eq!(runs(&registry), 4, "nothing is cached by default");
+
+        registry.set_parse_cache_capacity(1);
+        let first = registry.parse("source.test", "if\nif").unwrap();
+        assert_eq!(registry.parse("source.test", "if\nif").unwrap(), first);
+        assert_eq!(runs(&registry), 6);
+
+        // only the newest result is kept
+        registry.parse("source.test", "x").unwrap();
+        registry.parse("source.test", "if\nif").unwrap();
+        assert_eq!(runs(&registry), 9);
+
+        // adding a grammar can change any result
+        registry
+            .add_grammar("other.json", r#"{"scopeName": "source.other"}"#)
+            .unwrap();
+        registry.parse("source.test", "if\nif").unwrap();
+        assert_eq!(runs(&registry), 11);
+    }
+
+    #[test]
+    fn coverage_counts_matches() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [
+                {"match": "\\bif\\b", "name": "keyword.control"},
+                {"begin": "\\(", "end": "\\)", "name": "meta.group", "patterns": [
+                    {"include": "$self"},
+                    {"match": "\\bnever\\b", "name": "invalid"}
+                ]}
+            ]
+        }"#;
+        let mut registry = Registry::new();
+        registry.add_grammar("test.json", grammar).unwrap();
+
+        let sample = "if if (x)\n(if\n)\nif";
+        let coverage = registry.coverage("source.test", sample).unwrap();
+
+        let rule = |name: &str, regex: &str| RuleId {
+            grammar: "source.test".to_string(),
+            name: Some(name.to_string()),
+            regex: regex.to_string(),
+        };
+        assert_eq!(coverage[&rule("keyword.control", "\\bif\\b")], 4);
+        assert_eq!(coverage[&rule("meta.group", "\\(")], 2);
+
+        // the rule within the group never matched
+        assert_eq!(coverage.len(), 2);
+    }
+}
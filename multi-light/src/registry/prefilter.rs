@@ -0,0 +1,243 @@
+//! A cheap check for lines that no rule of a grammar can match anywhere, using
+//! the characters that each rule's regex can start a match with.
+
+use std::collections::HashSet;
+
+/// Widest character range in a `[...]` class that is listed out, rather than
+/// giving up on the class
+const MAX_CLASS_RANGE: u32 = 256;
+
+/// The characters that a match of the regex could start with, or None if it
+/// could start with anything, or with nothing at all, e.g. because it can
+/// match an empty string.  Anything not understood gives None, so it is always
+/// safe to assume that text without any of the characters can't match.
+pub(super) fn first_chars(regex: &str) -> Option<HashSet<char>> {
+    let mut chars = HashSet::new();
+
+    for alternative in split_alternatives(regex)? {
+        chars.extend(alternative_first_chars(alternative)?);
+    }
+
+    Some(chars)
+}
+
+/// Split a regex at each top level `|`
+fn split_alternatives(regex: &str) -> Option<Vec<&str>> {
+    let mut alternatives = vec![];
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut chars = regex.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => skip_class(&mut chars)?,
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1)?,
+            '|' if depth == 0 => {
+                alternatives.push(&regex[start..idx]);
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+
+    alternatives.push(&regex[start..]);
+    Some(alternatives)
+}
+
+/// Move past the rest of a `[...]` class, the `[` having been read already
+fn skip_class(chars: &mut impl Iterator<Item = (usize, char)>) -> Option<()> {
+    let mut depth = 1;
+    let mut first = true;
+
+    while depth > 0 {
+        let (_, c) = chars.next()?;
+        match c {
+            '\\' => {
+                chars.next()?;
+            }
+            '[' => depth += 1,
+            // a `]` straight after the opening `[` is a literal
+            ']' if !first => depth -= 1,
+            _ => (),
+        }
+        first = c == '^' && first;
+    }
+
+    Some(())
+}
+
+/// The characters that one alternative of a regex could start with, from its
+/// first element
+fn alternative_first_chars(alternative: &str) -> Option<HashSet<char>> {
+    let mut chars = alternative.char_indices();
+    let (_, c) = chars.next()?;
+
+    let (first, rest) = match c {
+        '\\' => {
+            let (_, escaped) = chars.next()?;
+            let first = match escaped {
+                'n' => '\n',
+                't' => '\t',
+                // classes such as `\d` include non-ASCII characters, and the
+                // rest are anchors, back references or code points
+                _ if escaped.is_alphanumeric() => return None,
+                _ => escaped,
+            };
+            (HashSet::from([first]), chars.as_str())
+        }
+        '[' => {
+            let class = &alternative[1..];
+            let mut rest = class.char_indices();
+            skip_class(&mut rest)?;
+            let end = rest.next().map_or(class.len(), |(idx, _)| idx);
+            (class_chars(&class[..end - 1])?, &class[end..])
+        }
+        '(' => {
+            let inner = &alternative[1..];
+            let end = group_end(inner)?;
+            // named and atomic groups match the same text as their contents
+            let group = &inner[..end];
+            let named = group
+                .strip_prefix("?<")
+                .and_then(|named| named.split_once('>'))
+                .filter(|(name, _)| !name.starts_with(['=', '!']))
+                .map(|(_, group)| group);
+            let group = named
+                .or_else(|| group.strip_prefix("?:"))
+                .or_else(|| group.strip_prefix("?>"));
+            let group = match group {
+                Some(group) => group,
+                // lookarounds, comments and option groups
+                None if inner.starts_with('?') => return None,
+                None => &inner[..end],
+            };
+            (first_chars(group)?, &inner[end + 1..])
+        }
+        // anchors and characters that can be anything
+        '^' | '$' | '.' | ')' | '|' | '*' | '+' | '?' | '{' => return None,
+        _ => (HashSet::from([c]), chars.as_str()),
+    };
+
+    // a first element that can be repeated zero times could leave the match
+    // starting with whatever follows it
+    if rest.starts_with(['?', '*']) || rest.starts_with("{0") || rest.starts_with("{,") {
+        return None;
+    }
+
+    Some(first)
+}
+
+/// Byte offset of the `)` closing a group, given the text after its `(`
+fn group_end(inner: &str) -> Option<usize> {
+    let mut depth = 1;
+    let mut chars = inner.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next()?;
+            }
+            '[' => skip_class(&mut chars)?,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// The characters in the body of a `[...]` class, or None for classes that
+/// are negated or use anything other than single characters and ranges
+fn class_chars(class: &str) -> Option<HashSet<char>> {
+    if class.starts_with('^') || class.contains('[') || class.contains("&&") {
+        return None;
+    }
+
+    let mut set = HashSet::new();
+    let mut chars = class.chars();
+
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                escaped if escaped.is_alphanumeric() => return None,
+                escaped => escaped,
+            },
+            c => c,
+        };
+
+        let mut lookahead = chars.clone();
+        if lookahead.next() == Some('-')
+            && let Some(end) = lookahead.next()
+            && end != '\\'
+        {
+            if (end as u32).checked_sub(c as u32)? > MAX_CLASS_RANGE {
+                return None;
+            }
+            set.extend(c..=end);
+            chars = lookahead;
+        } else {
+            set.insert(c);
+        }
+    }
+
+    Some(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(regex: &str) -> Option<String> {
+        let mut chars: Vec<_> = first_chars(regex)?.into_iter().collect();
+        chars.sort_unstable();
+        Some(chars.into_iter().collect())
+    }
+
+    #[test]
+    fn literal_starts() {
+        assert_eq!(chars("if"), Some("i".to_string()));
+        assert_eq!(chars("if|else|(?:while)"), Some("eiw".to_string()));
+        assert_eq!(chars("\\{|\\n"), Some("\n{".to_string()));
+        assert_eq!(chars("[a-c_]+x"), Some("_abc".to_string()));
+        assert_eq!(chars("[]x]"), Some("]x".to_string()));
+        assert_eq!(chars("(?<open>/\\*)"), Some("/".to_string()));
+        assert_eq!(chars("(?>ab|c)d"), Some("ac".to_string()));
+        assert_eq!(chars("#+.*$"), Some("#".to_string()));
+    }
+
+    #[test]
+    fn unknown_starts() {
+        for regex in [
+            "",
+            "a|",
+            ".x",
+            "^#",
+            "\\bif",
+            "\\d+",
+            "[^a]",
+            "[[:alpha:]]",
+            "a?b",
+            "a*b",
+            "a{0,2}b",
+            "(?=a)a",
+            "(?i)if",
+            "(?<=x)y",
+            "(a|)b",
+            "\\1",
+        ] {
+            assert_eq!(chars(regex), None, "{regex}");
+        }
+    }
+}
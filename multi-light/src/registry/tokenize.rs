@@ -0,0 +1,1921 @@
+//! Splitting source text into tokens, one line at a time, using the rules of a
+//! grammar in the registry.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::Path,
+    rc::Rc,
+};
+
+use onig::{Regex, Region, SearchOptions};
+
+use crate::{Captures, Error, Grammar, Rule};
+
+use super::{Registry, default_scope_name, prefilter};
+
+/// A span of a line, with every scope that applies to it, outermost first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// Byte range of the token within the line
+    pub text_range: Range<usize>,
+    pub scopes: Vec<String>,
+}
+
+/// A rule in a grammar, as counted by `Registry::coverage`.  Rules are told
+/// apart by their regex, `begin` for a `begin`/`end` rule, so rules that
+/// only differ in their captures or patterns are counted together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RuleId {
+    /// Scope name of the grammar the rule is from
+    pub grammar: String,
+    pub name: Option<String>,
+    pub regex: String,
+}
+
+/// How the indentation changes around a line, see `Registry::indent_action`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndentAction {
+    /// Keep the same indentation as the previous line
+    #[default]
+    Keep,
+
+    /// Indent the lines after this one one more level, e.g. after a `{`
+    Indent,
+
+    /// Indent this line and the ones after it one less level, e.g. for a `}`
+    Outdent,
+
+    /// Indent this line one less level, then the lines after it one level more
+    /// than this one, e.g. for `} else {`
+    OutdentThenIndent,
+}
+
+/// Tab width usually used for `Token::visual_column_range`
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+impl Token {
+    /// Range of columns that the token takes up when the line is displayed,
+    /// given the line the token is from, with tabs expanded to the next
+    /// multiple of `tab_width` columns (see `DEFAULT_TAB_WIDTH`).  Columns
+    /// start from 0, and every other character is counted as one column.
+    pub fn visual_column_range(&self, line: &str, tab_width: usize) -> Range<usize> {
+        let tab_width = tab_width.max(1);
+        let column_at = |end: usize| {
+            let end = end.min(line.len());
+            line[..end].chars().fold(0, |column, c| match c {
+                '\t' => (column / tab_width + 1) * tab_width,
+                _ => column + 1,
+            })
+        };
+
+        column_at(self.text_range.start)..column_at(self.text_range.end)
+    }
+}
+
+/// Check that `tokens` could have come from tokenizing `line`: every range
+/// is non-empty, starts and ends on a character boundary, and starts where
+/// the previous one ended, with the first starting at 0 and the last ending
+/// at the end of the line.  Gives a description of the first problem found.
+pub fn verify_tokens(line: &str, tokens: &[Token]) -> Result<(), String> {
+    let mut pos = 0;
+
+    for (index, token) in tokens.iter().enumerate() {
+        let range = &token.text_range;
+        if range.start != pos {
+            return Err(format!(
+                "token {index} starts at byte {}, expected {pos}",
+                range.start
+            ));
+        }
+        if range.is_empty() {
+            return Err(format!("token {index} is empty"));
+        }
+        if range.end > line.len() {
+            return Err(format!(
+                "token {index} ends at byte {}, after the end of the line ({})",
+                range.end,
+                line.len()
+            ));
+        }
+        if !line.is_char_boundary(range.start) || !line.is_char_boundary(range.end) {
+            return Err(format!("token {index} ({range:?}) splits a character"));
+        }
+        pos = range.end;
+    }
+
+    if pos != line.len() {
+        return Err(format!("tokens end at byte {pos}, expected {}", line.len()));
+    }
+
+    Ok(())
+}
+
+/// Rules that are still open at the end of a line, passed from one call of
+/// `Registry::tokenize_line` to the next.  Use a new state for the first line
+/// of a file.  States compare equal if they have the same rules open, so the
+/// lines after them will be tokenized the same way.
+#[derive(Debug, Clone, Default)]
+pub struct TokenizeState {
+    stack: Vec<OpenRule>,
+
+    /// Boundaries found in the last line tokenized with this state
+    boundaries: Vec<GrammarBoundary>,
+}
+
+impl TokenizeState {
+    /// Create the state for the start of a file
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the last line tokenized with this state moved between the rules
+    /// of different grammars, in order.  Always empty unless turned on with
+    /// `Registry::set_embedded_boundaries`.
+    pub fn boundaries(&self) -> &[GrammarBoundary] {
+        &self.boundaries
+    }
+}
+
+impl PartialEq for TokenizeState {
+    fn eq(&self, other: &Self) -> bool {
+        self.stack == other.stack
+    }
+}
+
+/// A place in a line where tokenizing moved from the rules of one grammar to
+/// those of another, e.g. into the CSS embedded in an HTML `<style>` tag and
+/// back out again.  Tokens never span a boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarBoundary {
+    /// Byte offset within the line, where the first token from `to` starts
+    pub offset: usize,
+
+    /// Scope names of the grammars before and after the boundary
+    pub from: String,
+    pub to: String,
+}
+
+/// A `begin`/`end` rule whose `begin` has matched, but not its `end`.  The
+/// rule stays open across lines until `end` matches.
+#[derive(Debug, Clone, PartialEq)]
+struct OpenRule {
+    /// Scopes of the `begin` and `end` matches
+    scopes: Vec<String>,
+
+    /// Scopes of the text between `begin` and `end`
+    content_scopes: Vec<String>,
+
+    /// The `end` regex, with any back references to `begin`'s capture groups
+    /// replaced by the text they captured
+    end: String,
+    end_captures: Captures,
+    patterns: Vec<Rule>,
+
+    /// Scope name of the grammar the rule is from, which its patterns'
+    /// includes are relative to
+    grammar: String,
+}
+
+/// Byte ranges of each of a match's capture groups, group 0 being the whole
+/// match, or None for groups that didn't participate in the match
+type Groups = Vec<Option<Range<usize>>>;
+
+/// What to do with the best match found at a position in the line
+enum Step {
+    /// The open rule's `end` matched
+    End,
+
+    /// A `match` rule matched, from the grammar with the given scope name
+    Match {
+        scopes: Vec<String>,
+        captures: Captures,
+        grammar: String,
+    },
+
+    /// A `begin`/`end` rule's `begin` matched
+    Begin { open: OpenRule, captures: Captures },
+}
+
+/// Deepest nesting of capture patterns applied within a match, as a capture's
+/// patterns can match the same text again with the same rule
+const MAX_CAPTURE_DEPTH: usize = 32;
+
+/// What is shared by everything tokenized within a single line
+struct LineContext<'a> {
+    /// Scope name of the grammar used to tokenize, for `$base`
+    base: &'a str,
+
+    /// File name of that grammar, for errors about its regexes
+    file_name: &'a Path,
+
+    /// Every grammar that has been included so far, or None for includes of
+    /// grammars that couldn't be found
+    grammars: HashMap<String, Option<Rc<Grammar>>>,
+
+    /// Number of capture patterns that the text being tokenized is within
+    depth: usize,
+
+    /// Number of times each rule has matched, if they are being counted
+    coverage: Option<&'a mut HashMap<RuleId, usize>>,
+}
+
+/// The rules used when no `begin`/`end` rule is open
+struct RootRules<'a> {
+    patterns: &'a [Rule],
+    scopes: &'a [String],
+
+    /// Scope name of the grammar the patterns are from
+    grammar: &'a str,
+}
+
+impl Registry {
+    /// Split a single line of source text into tokens, using the grammar for
+    /// `scope`.  The line shouldn't include its line ending.  `state` holds the
+    /// rules left open by the previous line, and is updated for the next one.
+    /// Tokens cover the whole line, with adjacent tokens that have the same
+    /// scopes merged together.
+    pub fn tokenize_line(
+        &self,
+        scope: &str,
+        line: &str,
+        state: &mut TokenizeState,
+    ) -> Result<Vec<Token>, Error> {
+        self.tokenize_line_counting(scope, line, state, None)
+    }
+
+    /// Split a line into tokens, the same as `tokenize_line`, adding the rules
+    /// that matched to `coverage` if it is given
+    pub(super) fn tokenize_line_counting(
+        &self,
+        scope: &str,
+        line: &str,
+        state: &mut TokenizeState,
+        coverage: Option<&mut HashMap<RuleId, usize>>,
+    ) -> Result<Vec<Token>, Error> {
+        #[cfg(test)]
+        self.probe
+            .lines_tokenized
+            .set(self.probe.lines_tokenized.get() + 1);
+
+        let grammar = self.compiled_grammar(scope)?;
+        let file_name = self.file_name(scope);
+
+        // match against the line ending as well, so that rules can refer to
+        // it the same as if they were matching against the whole file
+        let text = format!("{line}\n");
+
+        let mut tokens = Tokens {
+            line_len: line.len(),
+            tokens: vec![],
+            boundaries: self.embedded_boundaries.then(Boundaries::default),
+        };
+        let mut cx = LineContext {
+            base: scope,
+            file_name,
+            grammars: HashMap::from([(scope.to_string(), Some(Rc::clone(&grammar)))]),
+            depth: 0,
+            coverage,
+        };
+        let root = RootRules {
+            patterns: &grammar.patterns,
+            scopes: std::slice::from_ref(&grammar.scope_name),
+            grammar: scope,
+        };
+
+        // a line that none of the rules can start a match in only needs one
+        // token, without trying each of them at every position
+        if state.stack.is_empty() && self.prefilter && !self.could_match(&mut cx, &root, &text)? {
+            tokens.push(0..text.len(), root.scopes, scope);
+        } else {
+            self.tokenize_text(&mut cx, &text, 0, &mut state.stack, &root, &mut tokens)?;
+        }
+        debug_assert_eq!(verify_tokens(line, &tokens.tokens), Ok(()));
+
+        state.boundaries = tokens.boundaries.map(|b| b.found).unwrap_or_default();
+
+        Ok(tokens.tokens)
+    }
+
+    /// Tokenize `text` from `pos` to its end, using the rules of the innermost
+    /// open rule in `stack`, or `root` once none are open
+    fn tokenize_text(
+        &self,
+        cx: &mut LineContext,
+        text: &str,
+        mut pos: usize,
+        stack: &mut Vec<OpenRule>,
+        root: &RootRules,
+        tokens: &mut Tokens,
+    ) -> Result<(), Error> {
+        let mut empty_match_at = None;
+
+        while pos < text.len() {
+            let (scopes, end, patterns, current) = match stack.last() {
+                Some(open) => (
+                    &open.content_scopes[..],
+                    Some(&open.end),
+                    &open.patterns[..],
+                    open.grammar.as_str(),
+                ),
+                None => (root.scopes, None, root.patterns, root.grammar),
+            };
+
+            // find all of the rules that could match, loading any other
+            // grammars that are included and trying again if needed
+            let rules = loop {
+                let mut candidates = Candidates::new(&cx.grammars, cx.base);
+                candidates.add(patterns, current);
+
+                if candidates.missing.is_empty() {
+                    break candidates.rules;
+                }
+                for missing in candidates.missing {
+                    let included = self.included_grammar(&missing)?;
+                    cx.grammars.insert(missing, included);
+                }
+            };
+
+            // text that no rule matches belongs to the grammar whose rules
+            // were tried, if they are all from one grammar, e.g. within an
+            // HTML `<style>` tag whose only pattern is an include of CSS
+            let active = match rules.first() {
+                Some(&(_, grammar)) if rules.iter().all(|&(_, other)| other == grammar) => grammar,
+                _ => current,
+            };
+            if let Some(boundaries) = &mut tokens.boundaries {
+                boundaries.grammar.get_or_insert_with(|| active.to_string());
+            }
+
+            // the earliest match wins, with ties going to whichever was tried
+            // first; the open rule's `end` is tried before any of its patterns
+            let mut best: Option<(Groups, Step, Option<usize>)> = None;
+            // a match that doesn't consume anything, twice at the same place,
+            // would loop forever, e.g. a zero-width `begin` that includes
+            // `$self`, so the second time it is skipped in favour of the
+            // other rules
+            let mut skipped_empty = false;
+            let mut consider =
+                |regex: &str, rule: Option<usize>, step: &dyn Fn(&Groups) -> Step| {
+                    let Some(groups) = self.search(regex, text, pos, cx.file_name)? else {
+                        return Ok::<_, Error>(false);
+                    };
+
+                    let range = match_range(&groups);
+                    if range.is_empty() && empty_match_at == Some(range.start) {
+                        skipped_empty = true;
+                        return Ok(false);
+                    }
+
+                    let start = range.start;
+                    if best
+                        .as_ref()
+                        .is_none_or(|(best, _, _)| start < match_range(best).start)
+                    {
+                        let step = step(&groups);
+                        best = Some((groups, step, rule));
+                        return Ok(start == pos);
+                    }
+
+                    Ok(false)
+                };
+
+            let mut found = false;
+            if let Some(end) = end {
+                found = consider(end, None, &|_| Step::End)?;
+            }
+            for (idx, &(rule, rule_grammar)) in rules.iter().enumerate() {
+                if found {
+                    break;
+                }
+
+                found = match rule {
+                    Rule::Match {
+                        name,
+                        regex,
+                        captures,
+                    } => consider(regex, Some(idx), &|_| Step::Match {
+                        scopes: with_scope(scopes, name),
+                        captures: captures.clone(),
+                        grammar: rule_grammar.to_string(),
+                    })?,
+                    Rule::BeginEnd {
+                        name,
+                        content_name,
+                        begin,
+                        end,
+                        begin_captures,
+                        end_captures,
+                        patterns,
+                    } => consider(begin, Some(idx), &|groups| {
+                        let scopes = with_scope(scopes, name);
+                        let open = OpenRule {
+                            content_scopes: with_scope(&scopes, content_name),
+                            scopes,
+                            end: resolve_back_references(end, groups, text),
+                            end_captures: end_captures.clone(),
+                            patterns: patterns.clone(),
+                            grammar: rule_grammar.to_string(),
+                        };
+                        Step::Begin {
+                            open,
+                            captures: begin_captures.clone(),
+                        }
+                    })?,
+                    Rule::Include(_) | Rule::Patterns { .. } => false,
+                };
+            }
+
+            let scopes = scopes.to_vec();
+            let Some((groups, step, rule)) = best else {
+                // only skipped matches were left, so skip a character to get
+                // past them
+                if skipped_empty {
+                    let len = text[pos..].chars().next().map_or(1, char::len_utf8);
+                    tokens.push(pos..pos + len, &scopes, active);
+                    pos += len;
+                    continue;
+                }
+
+                tokens.push(pos..text.len(), &scopes, active);
+                break;
+            };
+
+            let range = match_range(&groups);
+            if range.is_empty() {
+                empty_match_at = Some(range.start);
+            }
+
+            if let Some(coverage) = &mut cx.coverage
+                && let Some(rule) = rule
+            {
+                *coverage.entry(rule_id(rules[rule])).or_default() += 1;
+            }
+
+            tokens.push(pos..range.start, &scopes, active);
+
+            match step {
+                Step::End => {
+                    let open = stack.pop().unwrap();
+                    let captures = (&open.scopes[..], &open.end_captures, &open.grammar[..]);
+                    self.push_captures(cx, text, &groups, captures, tokens)?;
+                }
+                Step::Match {
+                    scopes,
+                    captures,
+                    grammar,
+                } => {
+                    self.push_captures(cx, text, &groups, (&scopes, &captures, &grammar), tokens)?;
+                }
+                Step::Begin { open, captures } => {
+                    let captures_for = (&open.scopes[..], &captures, &open.grammar[..]);
+                    self.push_captures(cx, text, &groups, captures_for, tokens)?;
+                    stack.push(open);
+                }
+            }
+
+            pos = range.end;
+        }
+
+        Ok(())
+    }
+
+    /// Whether any of the root rules could match somewhere in `text`, judging
+    /// by the characters that their matches can start with
+    fn could_match(
+        &self,
+        cx: &mut LineContext,
+        root: &RootRules,
+        text: &str,
+    ) -> Result<bool, Error> {
+        if !self.first_chars.borrow().contains_key(root.grammar) {
+            let first_chars = self.root_first_chars(cx, root)?;
+            self.first_chars
+                .borrow_mut()
+                .insert(root.grammar.to_string(), first_chars);
+        }
+
+        Ok(match &self.first_chars.borrow()[root.grammar] {
+            Some(first_chars) => text.chars().any(|c| first_chars.contains(&c)),
+            None => true,
+        })
+    }
+
+    /// The characters that a match of any of the root rules has to start with,
+    /// or None if they could start with anything
+    fn root_first_chars(
+        &self,
+        cx: &mut LineContext,
+        root: &RootRules,
+    ) -> Result<Option<HashSet<char>>, Error> {
+        let rules = loop {
+            let mut candidates = Candidates::new(&cx.grammars, cx.base);
+            candidates.add(root.patterns, root.grammar);
+
+            if candidates.missing.is_empty() {
+                break candidates.rules;
+            }
+            for missing in candidates.missing {
+                let included = self.included_grammar(&missing)?;
+                cx.grammars.insert(missing, included);
+            }
+        };
+
+        let mut first_chars = HashSet::new();
+        for (rule, _) in rules {
+            let regex = match rule {
+                Rule::Match { regex, .. } => regex,
+                Rule::BeginEnd { begin, .. } => begin,
+                Rule::Include(_) | Rule::Patterns { .. } => continue,
+            };
+
+            // skipping lines would hide the error from an invalid regex
+            if self.compile_regex(regex, cx.file_name).is_err() {
+                return Ok(None);
+            }
+            let Some(chars) = prefilter::first_chars(regex) else {
+                return Ok(None);
+            };
+            first_chars.extend(chars);
+        }
+
+        Ok(Some(first_chars))
+    }
+
+    /// Add the tokens for a match, given the scopes of the match, the captures
+    /// of the rule that matched and the scope name of the grammar it is from.
+    /// Capture groups with a scope name get that scope added on top of the
+    /// match's scopes, and groups nested within other groups get the scopes of
+    /// every group they are within, outermost first.  The text of a group with
+    /// `patterns` is tokenized using them, in place of any groups within it.
+    fn push_captures(
+        &self,
+        cx: &mut LineContext,
+        text: &str,
+        groups: &Groups,
+        (scopes, captures, grammar): (&[String], &Captures, &str),
+        tokens: &mut Tokens,
+    ) -> Result<(), Error> {
+        let range = match_range(groups);
+
+        // the groups with a scope or patterns, limited to the match, as
+        // lookarounds can capture text outside of it
+        let named: Vec<_> = captures
+            .iter()
+            .filter_map(|(&group, capture)| {
+                let group = groups.get(group)?.as_ref()?;
+                let group = group.start.max(range.start)..group.end.min(range.end);
+                let used = capture.name.is_some() || !capture.patterns.is_empty();
+                (used && !group.is_empty()).then_some((group, capture))
+            })
+            .collect();
+
+        // scopes of text within every group that contains the given range
+        let scopes_within = |within: &Range<usize>| {
+            let mut piece_scopes = scopes.to_vec();
+            piece_scopes.extend(
+                named
+                    .iter()
+                    .filter(|(group, _)| group.start <= within.start && within.end <= group.end)
+                    .filter_map(|(_, capture)| capture.name.clone()),
+            );
+            piece_scopes
+        };
+
+        // split the match at every group boundary, each piece then has the
+        // scopes of all of the groups containing it
+        let mut bounds: Vec<_> = named
+            .iter()
+            .flat_map(|(group, _)| [group.start, group.end])
+            .chain([range.start, range.end])
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut tokenized_until = range.start;
+        for piece in bounds.windows(2) {
+            let piece = piece[0]..piece[1];
+            if piece.start < tokenized_until {
+                continue;
+            }
+
+            // the outermost group with patterns that contains the piece
+            let with_patterns = named
+                .iter()
+                .filter(|(group, capture)| {
+                    !capture.patterns.is_empty()
+                        && group.start <= piece.start
+                        && piece.end <= group.end
+                })
+                .max_by_key(|(group, _)| (group.len(), std::cmp::Reverse(group.start)));
+
+            match with_patterns {
+                Some((group, capture)) if cx.depth < MAX_CAPTURE_DEPTH => {
+                    let root = RootRules {
+                        patterns: &capture.patterns,
+                        scopes: &scopes_within(group),
+                        grammar,
+                    };
+
+                    // rules left open at the end of the group are dropped, as
+                    // the group's text can't contain their end
+                    cx.depth += 1;
+                    let text = &text[..group.end];
+                    let result =
+                        self.tokenize_text(cx, text, group.start, &mut vec![], &root, tokens);
+                    cx.depth -= 1;
+                    result?;
+
+                    tokenized_until = group.end;
+                }
+                _ => tokens.push(piece.clone(), &scopes_within(&piece), grammar),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the rules of a grammar, converting it from its document the first
+    /// time it is used
+    pub(super) fn compiled_grammar(&self, scope: &str) -> Result<Rc<Grammar>, Error> {
+        if let Some(grammar) = self.compiled.borrow().get(scope) {
+            return Ok(Rc::clone(grammar));
+        }
+
+        let Some(cfg) = self.grammars.get(scope) else {
+            return Err(Error::GrammarNotFound {
+                scope: scope.to_string(),
+            });
+        };
+
+        #[cfg(test)]
+        self.probe.compiled();
+
+        let grammar = Rc::new(Grammar::from_config(cfg)?);
+        self.compiled
+            .borrow_mut()
+            .insert(scope.to_string(), Rc::clone(&grammar));
+
+        Ok(grammar)
+    }
+
+    /// Compile the grammar for `scope` and every grammar that it includes,
+    /// directly or through other included grammars, along with all of their
+    /// regexes, so that the first call to `parse` doesn't have to, e.g. when
+    /// a server starts.  Included grammars not in the registry are loaded as
+    /// by `resolve_includes`.  Gives the first problem found, such as an
+    /// invalid regex, which tokenizing would only find once the rule is used.
+    /// `end` regexes with back references can only be compiled once their
+    /// `begin` has matched, so are skipped.
+    pub fn warm_up(&mut self, scope: &str) -> Result<(), Error> {
+        self.resolve_includes(scope)?;
+        self.compiled_grammar(scope)?;
+
+        let mut pending = vec![scope.to_string()];
+        let mut seen = HashSet::new();
+
+        while let Some(scope) = pending.pop() {
+            if !seen.insert(scope.clone()) {
+                continue;
+            }
+
+            // includes of missing grammars are ignored, as when tokenizing
+            let Some(grammar) = self.included_grammar(&scope)? else {
+                continue;
+            };
+            let file_name = self.file_name(&scope);
+
+            for rule in grammar.patterns.iter().chain(grammar.repository.values()) {
+                self.warm_up_rule(rule, file_name, &mut pending)?;
+            }
+            for pattern in [
+                &grammar.increase_indent_pattern,
+                &grammar.decrease_indent_pattern,
+            ] {
+                pattern
+                    .iter()
+                    .try_for_each(|pattern| self.compile_regex(pattern, file_name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How the indentation should change around a line, using the
+    /// `increaseIndentPattern` and `decreaseIndentPattern` of the grammar for
+    /// `scope`, e.g. for an editor to indent the line after a `{`.  Grammars
+    /// without the patterns always give `IndentAction::Keep`.
+    pub fn indent_action(&self, scope: &str, line: &str) -> Result<IndentAction, Error> {
+        let grammar = self.compiled_grammar(scope)?;
+        let matches = |pattern: &Option<String>| match pattern {
+            Some(pattern) => self
+                .search(pattern, line, 0, self.file_name(scope))
+                .map(|found| found.is_some()),
+            None => Ok(false),
+        };
+
+        let decrease = matches(&grammar.decrease_indent_pattern)?;
+        let increase = matches(&grammar.increase_indent_pattern)?;
+        Ok(match (decrease, increase) {
+            (false, false) => IndentAction::Keep,
+            (false, true) => IndentAction::Indent,
+            (true, false) => IndentAction::Outdent,
+            (true, true) => IndentAction::OutdentThenIndent,
+        })
+    }
+
+    /// File name of the grammar for `scope`, for errors about its regexes, or
+    /// the scope name for grammars only kept in their compiled form
+    fn file_name<'a>(&'a self, scope: &'a str) -> &'a Path {
+        self.grammars
+            .get(scope)
+            .map_or(Path::new(scope), |cfg| cfg.file_name())
+    }
+
+    /// Compile the regexes of a rule and the rules within it, adding the
+    /// scope names of other grammars that it includes to `includes`
+    fn warm_up_rule(
+        &self,
+        rule: &Rule,
+        file_name: &Path,
+        includes: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        let mut warm_up_captures = |captures: &Captures| {
+            captures
+                .values()
+                .flat_map(|capture| &capture.patterns)
+                .try_for_each(|rule| self.warm_up_rule(rule, file_name, includes))
+        };
+
+        match rule {
+            Rule::Match {
+                regex, captures, ..
+            } => {
+                self.compile_regex(regex, file_name)?;
+                warm_up_captures(captures)
+            }
+            Rule::BeginEnd {
+                begin,
+                end,
+                begin_captures,
+                end_captures,
+                patterns,
+                ..
+            } => {
+                self.compile_regex(begin, file_name)?;
+                if !has_back_references(end) {
+                    self.compile_regex(end, file_name)?;
+                }
+                warm_up_captures(begin_captures)?;
+                warm_up_captures(end_captures)?;
+                patterns
+                    .iter()
+                    .try_for_each(|rule| self.warm_up_rule(rule, file_name, includes))
+            }
+            Rule::Include(include) => {
+                let scope = include.split('#').next().unwrap_or("");
+                if !scope.is_empty() && !scope.starts_with('$') {
+                    includes.push(scope.to_string());
+                }
+                Ok(())
+            }
+            Rule::Patterns { patterns, .. } => patterns
+                .iter()
+                .try_for_each(|rule| self.warm_up_rule(rule, file_name, includes)),
+        }
+    }
+
+    /// Get a grammar included by another, reading it with the `on_read_file`
+    /// function if it isn't in the registry.  Returns None if it can't be
+    /// found, as includes of missing grammars are ignored.
+    fn included_grammar(&self, scope: &str) -> Result<Option<Rc<Grammar>>, Error> {
+        if self.grammars.contains_key(scope) || self.compiled.borrow().contains_key(scope) {
+            return self.compiled_grammar(scope).map(Some);
+        }
+
+        let Some(callback) = &self.callback else {
+            return Ok(None);
+        };
+
+        // the registry can't be changed while tokenizing, so the grammar is
+        // only kept in its compiled form
+        let content = callback(scope)?;
+        let mut cfg = Self::detect_format(scope, content.as_bytes())?;
+        default_scope_name(&mut cfg, scope);
+
+        #[cfg(test)]
+        self.probe.compiled();
+
+        let grammar = Rc::new(Grammar::from_config(&cfg)?);
+        self.compiled
+            .borrow_mut()
+            .insert(scope.to_string(), Rc::clone(&grammar));
+
+        Ok(Some(grammar))
+    }
+
+    /// Compile a regex the first time it is used, giving an error if it is
+    /// invalid
+    fn compile_regex(&self, regex: &str, file_name: &Path) -> Result<(), Error> {
+        let mut regexes = self.regexes.borrow_mut();
+        if !regexes.contains_key(regex) {
+            #[cfg(test)]
+            self.probe.compiled();
+
+            let compiled = Regex::new(regex).map_err(|err| err.to_string());
+            regexes.insert(regex.to_string(), compiled);
+        }
+
+        match &regexes[regex] {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::GrammarError {
+                err: format!("invalid regex `{regex}`: {err}"),
+                file_name: file_name.to_path_buf(),
+            }),
+        }
+    }
+
+    /// Find the first match of a regex in `text`, starting from `pos`.  The
+    /// regex is compiled the first time it is used.
+    fn search(
+        &self,
+        regex: &str,
+        text: &str,
+        pos: usize,
+        file_name: &Path,
+    ) -> Result<Option<Groups>, Error> {
+        self.compile_regex(regex, file_name)?;
+        let regexes = self.regexes.borrow();
+        let Ok(compiled) = &regexes[regex] else {
+            unreachable!("the regex compiled");
+        };
+
+        let mut region = Region::new();
+        let found = compiled.search_with_options(
+            text,
+            pos,
+            text.len(),
+            SearchOptions::SEARCH_OPTION_NONE,
+            Some(&mut region),
+        );
+
+        Ok(found.map(|_| {
+            (0..region.len())
+                .map(|group| region.pos(group).map(|(start, end)| start..end))
+                .collect()
+        }))
+    }
+}
+
+/// The tokens of a single line
+struct Tokens {
+    /// Length of the line, without the line ending added for matching
+    line_len: usize,
+    tokens: Vec<Token>,
+
+    /// Where the line moves between grammars, if they are being recorded
+    boundaries: Option<Boundaries>,
+}
+
+/// The grammar boundaries found so far in a line
+#[derive(Default)]
+struct Boundaries {
+    /// Scope name of the grammar of the last text added, or of the rules
+    /// open at the start of the line before any text has been added
+    grammar: Option<String>,
+    found: Vec<GrammarBoundary>,
+}
+
+impl Boundaries {
+    /// Note that the text from `offset` on is from the given grammar.  Moving
+    /// to another grammar and straight back adds no boundary.
+    fn enter(&mut self, offset: usize, grammar: &str) {
+        let from = match &mut self.grammar {
+            Some(from) if from != grammar => std::mem::replace(from, grammar.to_string()),
+            _ => return,
+        };
+
+        match self.found.last_mut() {
+            Some(last) if last.offset == offset => {
+                last.to = grammar.to_string();
+                if last.from == last.to {
+                    self.found.pop();
+                }
+            }
+            _ => self.found.push(GrammarBoundary {
+                offset,
+                from,
+                to: grammar.to_string(),
+            }),
+        }
+    }
+}
+
+impl Tokens {
+    /// Add a token from the given grammar, ignoring anything past the end of
+    /// the line, and merging it with the previous token if they have the same
+    /// scopes, unless there is a grammar boundary between them
+    fn push(&mut self, range: Range<usize>, scopes: &[String], grammar: &str) {
+        let range = range.start.min(self.line_len)..range.end.min(self.line_len);
+
+        // an empty range still counts for boundaries, so that a line ending
+        // just after an embedded grammar starts has the boundary at its end
+        let mut at_boundary = false;
+        if let Some(boundaries) = &mut self.boundaries {
+            boundaries.enter(range.start, grammar);
+            at_boundary = boundaries
+                .found
+                .last()
+                .is_some_and(|b| b.offset == range.start);
+        }
+
+        if range.is_empty() {
+            return;
+        }
+
+        if let Some(last) = self.tokens.last_mut()
+            && !at_boundary
+            && last.text_range.end == range.start
+            && last.scopes == scopes
+        {
+            last.text_range.end = range.end;
+            return;
+        }
+
+        self.tokens.push(Token {
+            text_range: range,
+            scopes: scopes.to_vec(),
+        });
+    }
+}
+
+/// The rules that can match directly within a list of patterns, looking
+/// inside groups of rules and following includes
+struct Candidates<'a> {
+    /// Grammars that can be included, by scope name
+    grammars: &'a HashMap<String, Option<Rc<Grammar>>>,
+
+    /// Scope name of the grammar being used to tokenize, for `$base`
+    base: &'a str,
+
+    /// Includes already followed, as the grammar's scope name and the
+    /// repository entry, to stop include loops
+    visited: HashSet<(&'a str, Option<&'a str>)>,
+
+    /// Each rule found, with the scope name of the grammar it is from
+    rules: Vec<(&'a Rule, &'a str)>,
+
+    /// Included grammars that haven't been loaded yet
+    missing: Vec<String>,
+}
+
+impl<'a> Candidates<'a> {
+    fn new(grammars: &'a HashMap<String, Option<Rc<Grammar>>>, base: &'a str) -> Self {
+        Self {
+            grammars,
+            base,
+            visited: HashSet::new(),
+            rules: vec![],
+            missing: vec![],
+        }
+    }
+
+    /// Add the rules from a list of patterns in the given grammar
+    fn add(&mut self, patterns: &'a [Rule], grammar: &'a str) {
+        for rule in patterns {
+            match rule {
+                Rule::Patterns { patterns, .. } => self.add(patterns, grammar),
+                Rule::Include(include) => self.include(include, grammar),
+                _ => self.rules.push((rule, grammar)),
+            }
+        }
+    }
+
+    /// Follow an include from a rule in the given grammar: `$self` is that
+    /// grammar's top level patterns, `$base` the top level patterns of the
+    /// grammar being used to tokenize, `#name` a repository entry, and any
+    /// other include the scope name of another grammar, optionally followed
+    /// by `#name` for one of its repository entries
+    fn include(&mut self, include: &'a str, grammar: &'a str) {
+        let (scope, name) = match include {
+            "$self" => (grammar, None),
+            "$base" => (self.base, None),
+            _ => match include.split_once('#') {
+                Some(("", name)) => (grammar, Some(name)),
+                Some((scope, name)) => (scope, Some(name)),
+                None => (include, None),
+            },
+        };
+
+        let Some((scope, target)) = self.grammars.get_key_value(scope) else {
+            if !self.missing.iter().any(|missing| missing == scope) {
+                self.missing.push(scope.to_string());
+            }
+            return;
+        };
+
+        let Some(target) = target else {
+            return;
+        };
+
+        if !self.visited.insert((scope, name)) {
+            return;
+        }
+
+        match name {
+            Some(name) => {
+                if let Some(rule) = target.repository.get(name) {
+                    self.add(std::slice::from_ref(rule), scope);
+                }
+            }
+            None => self.add(&target.patterns, scope),
+        }
+    }
+}
+
+/// Identify a rule that matched, given the scope name of its grammar
+fn rule_id((rule, grammar): (&Rule, &str)) -> RuleId {
+    let (name, regex) = match rule {
+        Rule::Match { name, regex, .. } => (name, regex),
+        Rule::BeginEnd { name, begin, .. } => (name, begin),
+        Rule::Include(_) | Rule::Patterns { .. } => unreachable!("only rules with a regex match"),
+    };
+
+    RuleId {
+        grammar: grammar.to_string(),
+        name: name.clone(),
+        regex: regex.clone(),
+    }
+}
+
+/// Byte range of the whole of a match
+fn match_range(groups: &Groups) -> Range<usize> {
+    groups[0].clone().unwrap()
+}
+
+/// Add a rule's scope name onto the scopes it is nested within, if it has one
+fn with_scope(scopes: &[String], name: &Option<String>) -> Vec<String> {
+    let mut scopes = scopes.to_vec();
+    scopes.extend(name.iter().cloned());
+    scopes
+}
+
+/// Replace back references in an `end` regex, e.g. `\1`, with the text that
+/// group captured in the `begin` match, so that e.g. a heredoc ends with the
+/// same word that it started with
+fn resolve_back_references(end: &str, groups: &Groups, text: &str) -> String {
+    let mut resolved = String::with_capacity(end.len());
+    let mut chars = end.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            resolved.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some(digit @ '0'..='9') => {
+                let group = digit as usize - '0' as usize;
+                if let Some(Some(range)) = groups.get(group) {
+                    escape_regex(&text[range.clone()], &mut resolved);
+                }
+            }
+            Some(escaped) => {
+                resolved.push('\\');
+                resolved.push(escaped);
+            }
+            None => resolved.push('\\'),
+        }
+    }
+
+    resolved
+}
+
+/// Whether an `end` regex refers to its `begin` match's capture groups, see
+/// `resolve_back_references`
+fn has_back_references(end: &str) -> bool {
+    let mut chars = end.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.next().is_some_and(|c| c.is_ascii_digit()) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Add text to a regex, escaped so that it only matches itself
+fn escape_regex(text: &str, regex: &mut String) {
+    for c in text.chars() {
+        if r"\^$.|?*+()[]{}-/# ".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(grammars: &[&str]) -> Registry {
+        let mut registry = Registry::new();
+        for (idx, grammar) in grammars.iter().enumerate() {
+            registry
+                .add_grammar(&format!("grammar{idx}.json"), grammar)
+                .unwrap();
+        }
+        registry
+    }
+
+    /// Tokenize each line in turn, giving the text of each token and its
+    /// scopes joined by spaces
+    fn tokenize(registry: &Registry, scope: &str, lines: &[&str]) -> Vec<Vec<(String, String)>> {
+        let mut state = TokenizeState::new();
+        lines
+            .iter()
+            .map(|line| {
+                let tokens = registry.tokenize_line(scope, line, &mut state).unwrap();
+                tokens
+                    .iter()
+                    .map(|token| {
+                        (
+                            line[token.text_range.clone()].to_string(),
+                            token.scopes.join(" "),
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Expected tokens, as text and space separated scopes
+    fn expect(tokens: &[(&str, &str)]) -> Vec<(String, String)> {
+        tokens
+            .iter()
+            .map(|&(text, scopes)| (text.to_string(), scopes.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn match_and_begin_end_rules() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [
+                {"match": "-[a-z]+", "name": "constant.option"},
+                {"begin": "\"", "end": "\"", "name": "string.quoted"}
+            ]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["cc -wall \"a -b\" x"])[0],
+            expect(&[
+                ("cc ", "source.test"),
+                ("-wall", "source.test constant.option"),
+                (" ", "source.test"),
+                ("\"a -b\"", "source.test string.quoted"),
+                (" x", "source.test"),
+            ])
+        );
+    }
+
+    #[test]
+    fn earliest_match_then_first_rule() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [
+                {"match": "bc", "name": "second"},
+                {"match": "ab", "name": "first"},
+                {"match": "abc", "name": "longer"}
+            ]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        // `ab` starts earlier than `bc`, and wins over the longer `abc` as it
+        // comes first in the patterns
+        assert_eq!(
+            tokenize(&registry, "source.test", &["abc"])[0],
+            expect(&[("ab", "source.test first"), ("c", "source.test")])
+        );
+    }
+
+    #[test]
+    fn empty_matches_make_progress() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [
+                {"match": "(?=x)", "name": "empty"},
+                {"match": "y", "name": "letter"}
+            ]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["xxy"])[0],
+            expect(&[("xx", "source.test"), ("y", "source.test letter")])
+        );
+    }
+
+    #[test]
+    fn unknown_scope_is_an_error() {
+        let registry = registry(&[r#"{"scopeName": "source.test"}"#]);
+        let err = registry
+            .tokenize_line("source.missing", "x", &mut TokenizeState::new())
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::GrammarNotFound { scope } if scope == "source.missing"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn block_comment_across_lines() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "begin": "/\\*",
+                "end": "\\*/",
+                "name": "comment.block",
+                "patterns": [{"match": "TODO", "name": "keyword.todo"}]
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        let lines = tokenize(
+            &registry,
+            "source.test",
+            &["a /* one", "TODO two", "three */ b"],
+        );
+        assert_eq!(
+            lines,
+            [
+                expect(&[
+                    ("a ", "source.test"),
+                    ("/* one", "source.test comment.block")
+                ]),
+                expect(&[
+                    ("TODO", "source.test comment.block keyword.todo"),
+                    (" two", "source.test comment.block"),
+                ]),
+                expect(&[
+                    ("three */", "source.test comment.block"),
+                    (" b", "source.test")
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn end_back_references() {
+        let grammar = r#"{
+            "scopeName": "source.shell",
+            "patterns": [{
+                "begin": "<<(\\w+)",
+                "end": "^\\1$",
+                "name": "string.heredoc"
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        let lines = tokenize(
+            &registry,
+            "source.shell",
+            &["cat <<EOF", "EOFX", "EOF", "ls"],
+        );
+        let heredoc = "source.shell string.heredoc";
+        assert_eq!(
+            lines,
+            [
+                expect(&[("cat ", "source.shell"), ("<<EOF", heredoc)]),
+                expect(&[("EOFX", heredoc)]),
+                expect(&[("EOF", heredoc)]),
+                expect(&[("ls", "source.shell")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn back_references_are_escaped() {
+        let groups = vec![Some(0..5), Some(2..5)];
+        assert_eq!(
+            resolve_back_references("^\\1\\b\\9", &groups, "<<a.*"),
+            "^a\\.\\*\\b"
+        );
+    }
+
+    #[test]
+    fn nested_capture_groups() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "match": "((\\w+)\\.(\\w+))\\(\\)",
+                "name": "meta.call",
+                "captures": {
+                    "1": {"name": "entity.name"},
+                    "3": {"name": "entity.name.function"},
+                    "4": {"name": "not.a.group"}
+                }
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["io.print()"])[0],
+            expect(&[
+                ("io.", "source.test meta.call entity.name"),
+                (
+                    "print",
+                    "source.test meta.call entity.name entity.name.function"
+                ),
+                ("()", "source.test meta.call"),
+            ])
+        );
+    }
+
+    #[test]
+    fn lookaround_captures_limited_to_match() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "match": "(?<=(a))b",
+                "name": "letter",
+                "captures": {"1": {"name": "outside"}}
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["ab"])[0],
+            expect(&[("a", "source.test"), ("b", "source.test letter")])
+        );
+    }
+
+    #[test]
+    fn content_name_and_end_captures() {
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "begin": "\"",
+                "end": "\"",
+                "name": "string.quoted",
+                "contentName": "string.content",
+                "beginCaptures": {"0": {"name": "punctuation.begin"}},
+                "endCaptures": {"0": {"name": "punctuation.end"}}
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["\"hi\""])[0],
+            expect(&[
+                ("\"", "source.test string.quoted punctuation.begin"),
+                ("hi", "source.test string.quoted string.content"),
+                ("\"", "source.test string.quoted punctuation.end"),
+            ])
+        );
+    }
+
+    const MAKEFILE: &str = r#"{
+        "scopeName": "source.makefile",
+        "patterns": [
+            {
+                "match": "^([A-Za-z_]+)\\s*(=)",
+                "name": "meta.assignment",
+                "captures": {
+                    "1": {"name": "variable.other"},
+                    "2": {"name": "keyword.operator.assignment"}
+                }
+            },
+            {"match": "-[a-z]+", "name": "constant.option"}
+        ]
+    }"#;
+
+    #[test]
+    fn makefile_assignment() {
+        let registry = registry(&[MAKEFILE]);
+
+        assert_eq!(
+            tokenize(&registry, "source.makefile", &["CFLAGS = -O2 -wall"])[0],
+            expect(&[
+                ("CFLAGS", "source.makefile meta.assignment variable.other"),
+                (" ", "source.makefile meta.assignment"),
+                (
+                    "=",
+                    "source.makefile meta.assignment keyword.operator.assignment"
+                ),
+                (" -O2 ", "source.makefile"),
+                ("-wall", "source.makefile constant.option"),
+            ])
+        );
+    }
+
+    #[test]
+    fn repository_and_self_includes() {
+        let grammar = r##"{
+            "scopeName": "source.test",
+            "patterns": [{"include": "#parens"}, {"include": "#number"}],
+            "repository": {
+                "parens": {
+                    "begin": "\\(",
+                    "end": "\\)",
+                    "name": "meta.parens",
+                    "patterns": [{"include": "$self"}]
+                },
+                "number": {"match": "[0-9]+", "name": "constant.numeric"}
+            }
+        }"##;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["1((2))"])[0],
+            expect(&[
+                ("1", "source.test constant.numeric"),
+                ("(", "source.test meta.parens"),
+                ("(", "source.test meta.parens meta.parens"),
+                ("2", "source.test meta.parens meta.parens constant.numeric"),
+                (")", "source.test meta.parens meta.parens"),
+                (")", "source.test meta.parens"),
+            ])
+        );
+    }
+
+    #[test]
+    fn include_loops_are_followed_once() {
+        let grammar = r##"{
+            "scopeName": "source.test",
+            "patterns": [{"include": "#loop"}],
+            "repository": {
+                "loop": {"patterns": [{"include": "#loop"}, {"include": "#number"}]},
+                "number": {"match": "[0-9]+", "name": "constant.numeric"}
+            }
+        }"##;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["a1"])[0],
+            expect(&[("a", "source.test"), ("1", "source.test constant.numeric")])
+        );
+    }
+
+    #[test]
+    fn other_grammar_includes() {
+        let outer = r##"{
+            "scopeName": "text.outer",
+            "patterns": [
+                {"begin": "`", "end": "`", "name": "embedded", "patterns": [{"include": "source.inner"}]},
+                {"include": "source.inner#word"},
+                {"include": "source.missing"}
+            ]
+        }"##;
+        let inner = r##"{
+            "scopeName": "source.inner",
+            "patterns": [{"match": "[0-9]+", "name": "number"}, {"match": "@", "name": "at"}, {"include": "$base"}],
+            "repository": {"word": {"match": "[a-z]+", "name": "word"}}
+        }"##;
+        let registry = registry(&[outer, inner]);
+
+        // `$base` within the inner grammar is the outer grammar
+        assert_eq!(
+            tokenize(&registry, "text.outer", &["ab `1 c @`"])[0],
+            expect(&[
+                ("ab", "text.outer word"),
+                (" ", "text.outer"),
+                ("`", "text.outer embedded"),
+                ("1", "text.outer embedded number"),
+                (" ", "text.outer embedded"),
+                ("c", "text.outer embedded word"),
+                (" ", "text.outer embedded"),
+                ("@", "text.outer embedded at"),
+                ("`", "text.outer embedded"),
+            ])
+        );
+    }
+
+    #[test]
+    fn includes_read_with_callback() {
+        let outer = r#"{"scopeName": "text.outer", "patterns": [{"include": "source.inner"}]}"#;
+        let mut registry = registry(&[outer]);
+        registry.on_read_file(|scope| {
+            assert_eq!(scope, "source.inner");
+            Ok(
+                r#"{"scopeName": "source.inner", "patterns": [{"match": "x", "name": "read"}]}"#
+                    .to_string(),
+            )
+        });
+
+        assert_eq!(
+            tokenize(&registry, "text.outer", &["ax"])[0],
+            expect(&[("a", "text.outer"), ("x", "text.outer read")])
+        );
+    }
+
+    const KEYWORDS: &str = r#"{
+        "scopeName": "source.test",
+        "patterns": [
+            {"match": "\\b(if|else)\\b", "name": "keyword.control"},
+            {"match": "[0-9]+", "name": "constant.numeric"}
+        ]
+    }"#;
+
+    #[test]
+    fn visual_columns_expand_tabs() {
+        let registry = registry(&[KEYWORDS]);
+        let line = "\t\tif 1\t2";
+        let tokens = registry
+            .tokenize_line("source.test", line, &mut TokenizeState::new())
+            .unwrap();
+
+        let keyword = tokens
+            .iter()
+            .find(|token| &line[token.text_range.clone()] == "if")
+            .unwrap();
+        assert_eq!(keyword.text_range, 2..4);
+        assert_eq!(keyword.visual_column_range(line, DEFAULT_TAB_WIDTH), 8..10);
+        assert_eq!(keyword.visual_column_range(line, 2), 4..6);
+
+        // a tab after text only goes to the next tab stop
+        let last = tokens.last().unwrap();
+        assert_eq!(&line[last.text_range.clone()], "2");
+        assert_eq!(last.visual_column_range(line, DEFAULT_TAB_WIDTH), 16..17);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &[line])[0][1],
+            ("if".to_string(), "source.test keyword.control".to_string())
+        );
+    }
+
+    #[test]
+    fn capture_patterns() {
+        let grammar = r##"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "match": "(\\w+)\\((.*)\\)",
+                "name": "meta.call",
+                "captures": {
+                    "1": {"name": "entity.name.function"},
+                    "2": {"name": "meta.arguments", "patterns": [{"include": "#number"}]}
+                }
+            }],
+            "repository": {
+                "number": {"match": "[0-9]+", "name": "constant.numeric"}
+            }
+        }"##;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["f(1, 23)"])[0],
+            expect(&[
+                ("f", "source.test meta.call entity.name.function"),
+                ("(", "source.test meta.call"),
+                ("1", "source.test meta.call meta.arguments constant.numeric"),
+                (", ", "source.test meta.call meta.arguments"),
+                (
+                    "23",
+                    "source.test meta.call meta.arguments constant.numeric"
+                ),
+                (")", "source.test meta.call"),
+            ])
+        );
+    }
+
+    #[test]
+    fn capture_patterns_replace_inner_groups() {
+        // group 2 is within group 1, whose patterns are used instead
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "match": "<((a)b)>",
+                "captures": {
+                    "1": {"name": "tag", "patterns": [{"match": "b", "name": "letter.b"}]},
+                    "2": {"name": "letter.a"}
+                }
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        assert_eq!(
+            tokenize(&registry, "source.test", &["<ab>"])[0],
+            expect(&[
+                ("<", "source.test"),
+                ("a", "source.test tag"),
+                ("b", "source.test tag letter.b"),
+                (">", "source.test"),
+            ])
+        );
+    }
+
+    #[test]
+    fn capture_patterns_recursion_limited() {
+        // the capture is the whole match, so its patterns match it again
+        let grammar = r#"{
+            "scopeName": "source.test",
+            "patterns": [{
+                "match": "x",
+                "captures": {"0": {"name": "x", "patterns": [{"include": "$self"}]}}
+            }]
+        }"#;
+        let registry = registry(&[grammar]);
+
+        let tokens = &tokenize(&registry, "source.test", &["x"])[0];
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].1.split(' ').count(), MAX_CAPTURE_DEPTH + 2);
+    }
+
+    #[test]
+    fn multibyte_token_ranges() {
+        let registry = registry(&[
+            KEYWORDS,
+            r#"{
+                "scopeName": "source.empty",
+                "patterns": [{"match": "(?=ü)", "name": "empty"}]
+            }"#,
+        ]);
+
+        for (scope, line) in [
+            ("source.test", "é if 日本 12 ü"),
+            ("source.empty", "aü日ü"),
+            ("source.test", "日本語"),
+            ("source.test", ""),
+        ] {
+            let tokens = registry
+                .tokenize_line(scope, line, &mut TokenizeState::new())
+                .unwrap();
+            assert_eq!(verify_tokens(line, &tokens), Ok(()), "{line:?}");
+        }
+
+        let tokens = registry
+            .tokenize_line("source.test", "é if", &mut TokenizeState::new())
+            .unwrap();
+        assert_eq!(tokens[0].text_range, 0..3);
+        assert_eq!(tokens[1].text_range, 3..5);
+    }
+
+    #[test]
+    fn verify_tokens_errors() {
+        let token = |text_range: Range<usize>| Token {
+            text_range,
+            scopes: vec!["source.test".to_string()],
+        };
+
+        assert!(verify_tokens("é", &[token(0..1), token(1..2)]).is_err());
+        assert!(verify_tokens("ab", &[token(0..1)]).is_err());
+        assert!(verify_tokens("ab", &[token(0..2), token(1..2)]).is_err());
+        assert!(verify_tokens("ab", &[token(1..2)]).is_err());
+        assert!(verify_tokens("ab", &[token(0..0), token(0..2)]).is_err());
+        assert!(verify_tokens("ab", &[token(0..3)]).is_err());
+        assert_eq!(verify_tokens("ab", &[token(0..1), token(1..2)]), Ok(()));
+    }
+
+    const HTML: &str = r#"{
+        "scopeName": "text.html",
+        "patterns": [
+            {"begin": "<style>", "end": "</style>", "name": "meta.tag.style",
+                "contentName": "source.css.embedded", "patterns": [{"include": "source.css"}]},
+            {"match": "<\\w+>", "name": "meta.tag"}
+        ]
+    }"#;
+
+    const CSS: &str = r#"{
+        "scopeName": "source.css",
+        "patterns": [
+            {"match": "[a-z]+(?=\\s*\\{)", "name": "entity.name.selector"},
+            {"begin": "\\{", "end": "\\}", "name": "meta.block", "patterns": [
+                {"match": "[a-z-]+(?=:)", "name": "support.property"}
+            ]}
+        ]
+    }"#;
+
+    fn boundaries(registry: &Registry, lines: &[&str]) -> Vec<Vec<(usize, String, String)>> {
+        let mut state = TokenizeState::new();
+        lines
+            .iter()
+            .map(|line| {
+                registry
+                    .tokenize_line("text.html", line, &mut state)
+                    .unwrap();
+                state
+                    .boundaries()
+                    .iter()
+                    .map(|b| (b.offset, b.from.clone(), b.to.clone()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn embedded_grammar_boundaries() {
+        let mut registry = registry(&[HTML, CSS]);
+        let line = "<p><style>a { color: red }</style><p>";
+        assert_eq!(boundaries(&registry, &[line]), [vec![]], "off by default");
+
+        registry.set_embedded_boundaries(true);
+        let enter = |offset| (offset, "text.html".to_string(), "source.css".to_string());
+        let leave = |offset| (offset, "source.css".to_string(), "text.html".to_string());
+        assert_eq!(boundaries(&registry, &[line]), [vec![enter(10), leave(26)]]);
+
+        // boundaries at the end or start of a line are kept
+        let lines = ["<style>", "a { color: red }", "</style>"];
+        assert_eq!(
+            boundaries(&registry, &lines),
+            [vec![enter(7)], vec![], vec![leave(0)]]
+        );
+
+        // tokens are split at boundaries, even with the same scopes
+        let html = HTML.replace(r#""contentName": "source.css.embedded","#, "");
+        let mut registry = self::registry(&[&html, CSS]);
+        registry.set_embedded_boundaries(true);
+        let mut state = TokenizeState::new();
+        let tokens = registry.tokenize_line("text.html", "<style> </style>", &mut state);
+        let ranges: Vec<_> = tokens.unwrap().into_iter().map(|t| t.text_range).collect();
+        assert_eq!(ranges, [0..7, 7..8, 8..16]);
+    }
+
+    #[test]
+    fn prefilter_matches_full_tokenizing() {
+        let grammar = r##"{
+            "scopeName": "source.test",
+            "patterns": [
+                {"match": "#.*", "name": "comment.line"},
+                {"include": "#string"},
+                {"match": "[0-9]+", "name": "constant.numeric"}
+            ],
+            "repository": {
+                "string": {"begin": "\"", "end": "\"", "name": "string.quoted", "patterns": [
+                    {"match": "\\\\.", "name": "constant.character.escape"}
+                ]}
+            }
+        }"##;
+        let lines = [
+            "plain text, no rules",
+            "",
+            "text with 42 in it",
+            "a \"string that",
+            "goes on and on",
+            "ends\" here # and a comment",
+            "ünïcödé prose",
+        ];
+
+        let mut registry = registry(&[grammar]);
+        let filtered = tokenize(&registry, "source.test", &lines);
+        assert!(registry.first_chars.borrow()["source.test"].is_some());
+
+        registry.set_prefilter(false);
+        assert_eq!(filtered, tokenize(&registry, "source.test", &lines));
+        assert_eq!(
+            filtered[0],
+            expect(&[("plain text, no rules", "source.test")])
+        );
+
+        // a rule that could start anywhere turns the prefilter off
+        let registry = self::registry(&[KEYWORDS]);
+        tokenize(&registry, "source.test", &["plain"]);
+        assert!(registry.first_chars.borrow()["source.test"].is_none());
+    }
+
+    #[test]
+    fn root_scope_is_always_outermost() {
+        let registry = registry(&[HTML, CSS]);
+        let lines = [
+            "<p><style>a { color: red }</style>",
+            "<style>",
+            "a {",
+            "}</style><p>",
+        ];
+
+        let tokens: Vec<_> = tokenize(&registry, "text.html", &lines).concat();
+        for (text, scopes) in &tokens {
+            assert_eq!(
+                scopes.split(' ').next(),
+                Some("text.html"),
+                "`{text}`: {scopes}"
+            );
+        }
+
+        // including the tokens from the embedded CSS
+        let selector = (
+            "a".to_string(),
+            "text.html meta.tag.style source.css.embedded entity.name.selector".to_string(),
+        );
+        assert!(tokens.contains(&selector), "{tokens:?}");
+    }
+
+    #[test]
+    fn grammar_without_patterns() {
+        let grammar = r##"{
+            "scopeName": "text.plain",
+            "repository": {"unused": {"match": "\\w+", "name": "keyword"}}
+        }"##;
+        let mut registry = registry(&[grammar]);
+
+        let lines = ["some text", "", "more text"];
+        let expected = [
+            expect(&[("some text", "text.plain")]),
+            vec![],
+            expect(&[("more text", "text.plain")]),
+        ];
+        assert_eq!(tokenize(&registry, "text.plain", &lines), expected);
+
+        // the same without skipping lines that no rule can match
+        registry.set_prefilter(false);
+        assert_eq!(tokenize(&registry, "text.plain", &lines), expected);
+    }
+
+    #[test]
+    fn warm_up_compiles_everything() {
+        let outer = r##"{
+            "scopeName": "source.outer",
+            "patterns": [
+                {"match": "(\\w+)(=)", "captures": {"1": {"patterns": [{"include": "#word"}]}}},
+                {"begin": "\\(", "end": "\\)", "patterns": [{"include": "source.inner"}]}
+            ],
+            "repository": {"word": {"match": "[a-z]+", "name": "variable"}}
+        }"##;
+        let new_registry = || {
+            let mut registry = registry(&[outer]);
+            registry.on_read_file(|_| Ok(KEYWORDS.replace("source.test", "source.inner")));
+            registry
+        };
+        let compilations = |registry: &Registry| registry.probe.compilations.get();
+        let lines = "x=1 (if 2)\n(\nelse 3)";
+
+        let cold = new_registry();
+        let expected = cold.parse("source.outer", lines).unwrap();
+        assert!(compilations(&cold) > 0);
+
+        let mut warm = new_registry();
+        warm.warm_up("source.outer").unwrap();
+        let before = compilations(&warm);
+        assert_eq!(warm.parse("source.outer", lines).unwrap(), expected);
+        assert_eq!(compilations(&warm), before);
+
+        // problems are found without having to tokenize anything
+        let mut registry = new_registry();
+        registry.on_read_file(|_| Ok(r#"{"patterns": [{"match": "["}]}"#.to_string()));
+        let err = registry.warm_up("source.outer").unwrap_err();
+        assert!(err.to_string().contains("invalid regex `[`"), "{err}");
+        assert!(matches!(
+            self::registry(&[outer]).warm_up("source.missing"),
+            Err(Error::GrammarNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn indent_actions() {
+        let grammar = r#"{
+            "scopeName": "source.braces",
+            "indentationRules": {
+                "increaseIndentPattern": "\\{[^}]*$",
+                "decreaseIndentPattern": "^\\s*\\}"
+            }
+        }"#;
+        let registry = registry(&[grammar, KEYWORDS]);
+        let action = |line| registry.indent_action("source.braces", line).unwrap();
+
+        assert_eq!(action("if (x) {"), IndentAction::Indent);
+        assert_eq!(action("    y();"), IndentAction::Keep);
+        assert_eq!(action("}"), IndentAction::Outdent);
+        assert_eq!(action("} else {"), IndentAction::OutdentThenIndent);
+        assert_eq!(action("{ z(); }"), IndentAction::Keep);
+
+        // grammars without indent patterns never change the indentation
+        let action = registry.indent_action("source.test", "if {").unwrap();
+        assert_eq!(action, IndentAction::Keep);
+    }
+
+    #[test]
+    fn zero_width_self_include_nests() {
+        // the group's `begin` matches without consuming anything, and includes
+        // itself through `$self`, so would start again forever at each `[`
+        let grammar = r##"{
+            "scopeName": "source.test",
+            "patterns": [
+                {"begin": "(?=\\[)", "end": "(?<=\\])", "name": "meta.group", "patterns": [
+                    {"include": "$self"},
+                    {"include": "#bracket"}
+                ]}
+            ],
+            "repository": {
+                "bracket": {"begin": "\\[", "end": "\\]", "name": "meta.brackets",
+                    "patterns": [{"include": "$self"}]}
+            }
+        }"##;
+        let registry = registry(&[grammar]);
+
+        let one = "source.test meta.group meta.brackets";
+        let two = format!("{one} meta.group meta.brackets");
+        let three = format!("{two} meta.group meta.brackets");
+        assert_eq!(
+            tokenize(&registry, "source.test", &["[a[b[c]]] x"])[0],
+            expect(&[
+                ("[a", one),
+                ("[b", &two),
+                ("[c]", &three),
+                ("]", &two),
+                ("]", one),
+                (" x", "source.test"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,877 @@
+//! Conversion of tokens into styled output: HTML with inline styles from a
+//! theme, HTML with a class per scope along with the matching stylesheet from
+//! `Theme::to_css`, terminal text with 24-bit or 256 colour ANSI escapes, a
+//! plain text overlay of each token's scopes for debugging grammars, and
+//! vscode-textmate's token JSON.
+
+use std::{fmt::Write, io};
+
+use serde::Serialize;
+
+use crate::{Colour, DEFAULT_TAB_WIDTH, ResolvedStyle, Style, Theme, Token};
+
+/// Options for rendering tokens as HTML
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HtmlOptions {
+    /// Wrap the output in a `<pre>` with the theme's default foreground and
+    /// background colours.  Defaults to false.
+    pub pre: bool,
+}
+
+/// Render a piece of text as HTML, with a `<span>` giving the style of each
+/// run of text that the theme styles differently to its defaults.  Token
+/// ranges are byte offsets into `text`, so to render several lines with
+/// `Registry::tokenize_line`, either render each line separately or offset
+/// their tokens to match.  Any text not covered by a token is unstyled.
+pub fn render_html(text: &str, tokens: &[Token], theme: &Theme, options: &HtmlOptions) -> String {
+    let default = theme.style_for(&[]);
+    let mut html = String::new();
+
+    if options.pre {
+        html.push_str(&pre_tag(&default));
+    }
+
+    // adjacent tokens with the same style are written as a single span
+    let mut run: Option<(String, usize)> = None;
+    let mut pos = 0;
+
+    for token in tokens {
+        let range = token.text_range.start.max(pos)..token.text_range.end.min(text.len());
+        if range.is_empty() {
+            continue;
+        }
+
+        if range.start > pos {
+            finish_run(&mut html, run.take(), text, pos);
+            escape(&mut html, &text[pos..range.start]);
+        }
+
+        let style = css(&theme.style_for(&token.scopes), &default);
+        match &run {
+            Some((run_style, _)) if *run_style == style => (),
+            _ => {
+                finish_run(&mut html, run.take(), text, range.start);
+                run = Some((style, range.start));
+            }
+        }
+
+        pos = range.end;
+    }
+
+    finish_run(&mut html, run, text, pos);
+    escape(&mut html, &text[pos..]);
+
+    if options.pre {
+        html.push_str("</pre>");
+    }
+
+    html
+}
+
+/// Render a document as HTML, the same as `render_html`, writing each line to
+/// `writer` as soon as it has been rendered rather than building the whole
+/// output in memory.  `lines` gives the text of each line, without its line
+/// ending, along with the tokens of that line, e.g. from `Registry::parse`.
+/// Each line is followed by a `\n`, and the writer is flushed after each one.
+pub fn render_html_to<'a, W: io::Write>(
+    lines: impl IntoIterator<Item = (&'a str, &'a [Token])>,
+    theme: &Theme,
+    options: &HtmlOptions,
+    writer: &mut W,
+) -> io::Result<()> {
+    if options.pre {
+        writer.write_all(pre_tag(&theme.style_for(&[])).as_bytes())?;
+    }
+
+    let line_options = HtmlOptions { pre: false };
+    for (text, tokens) in lines {
+        let html = render_html(text, tokens, theme, &line_options);
+        writer.write_all(html.as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    if options.pre {
+        writer.write_all(b"</pre>")?;
+    }
+    writer.flush()
+}
+
+/// Render a piece of text as HTML, using classes rather than inline styles,
+/// for use with the stylesheet from `Theme::to_css`.  Tokens are written as
+/// nested spans following their scopes, each with a class for the scope and
+/// every prefix of it, e.g. `mtk-string mtk-string-quoted` for
+/// `string.quoted`, so that the stylesheet can match selectors the same way
+/// `Theme::style_for` does.  See `scope_class` for how classes are named.
+/// With the `pre` option, the output is wrapped in `<pre class="mtk">`.
+pub fn render_html_classed(text: &str, tokens: &[Token], options: &HtmlOptions) -> String {
+    let mut html = String::new();
+    if options.pre {
+        html.push_str("<pre class=\"mtk\">");
+    }
+
+    // scopes of the spans currently open
+    let mut open: &[String] = &[];
+    let mut pos = 0;
+
+    for token in tokens {
+        let range = token.text_range.start.max(pos)..token.text_range.end.min(text.len());
+        if range.is_empty() {
+            continue;
+        }
+
+        if range.start > pos {
+            close_spans(&mut html, open.len());
+            open = &[];
+            escape(&mut html, &text[pos..range.start]);
+        }
+
+        let common = open
+            .iter()
+            .zip(&token.scopes)
+            .take_while(|(a, b)| a == b)
+            .count();
+        close_spans(&mut html, open.len() - common);
+
+        for scope in &token.scopes[common..] {
+            let mut classes = vec![];
+            for (idx, _) in scope.match_indices('.').chain([(scope.len(), "")]) {
+                classes.push(scope_class(&scope[..idx]));
+            }
+            write!(html, "<span class=\"{}\">", classes.join(" ")).unwrap();
+        }
+
+        escape(&mut html, &text[range.clone()]);
+        open = &token.scopes;
+        pos = range.end;
+    }
+
+    close_spans(&mut html, open.len());
+    escape(&mut html, &text[pos..]);
+
+    if options.pre {
+        html.push_str("</pre>");
+    }
+
+    html
+}
+
+/// The class used by `render_html_classed` and `Theme::to_css` for a scope,
+/// e.g. `mtk-variable-other` for `variable.other`.  Letters and digits are
+/// kept, each `.` becomes a `-`, and any other character is written as `_`,
+/// its hex code, then `_`, so no two scopes have the same class.
+pub fn scope_class(scope: &str) -> String {
+    let mut class = "mtk-".to_string();
+
+    for c in scope.chars() {
+        match c {
+            '.' => class.push('-'),
+            c if c.is_ascii_alphanumeric() => class.push(c),
+            c => write!(class, "_{:x}_", c as u32).unwrap(),
+        }
+    }
+
+    class
+}
+
+impl Theme {
+    /// Create a stylesheet for HTML from `render_html_classed`.  The `mtk`
+    /// class has the theme's default colours, then each rule's selectors are
+    /// converted to CSS selectors, with the scopes of descendant selectors
+    /// becoming descendant CSS selectors.  Rules are written from least to
+    /// most specific, in the same order `Theme::style_for` applies them, and
+    /// wrapped in `:where()` so that the browser picks between them by that
+    /// order alone.
+    pub fn to_css(&self) -> String {
+        let mut css = String::new();
+
+        let default = style_css(&self.settings);
+        if !default.is_empty() {
+            writeln!(css, ".mtk {{ {default} }}").unwrap();
+        }
+
+        let mut rules: Vec<_> = self
+            .rules
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, rule)| {
+                rule.scope.iter().filter_map(move |selector| {
+                    let parts: Vec<_> = selector.split_whitespace().collect();
+                    let specificity = (parts.last()?.split('.').count(), parts.len());
+                    Some((specificity, idx, parts, &rule.settings))
+                })
+            })
+            .collect();
+        rules.sort_by_key(|&(specificity, idx, ..)| (specificity, idx));
+
+        for (_, _, parts, settings) in rules {
+            let style = style_css(settings);
+            if style.is_empty() {
+                continue;
+            }
+
+            let selector: Vec<_> = parts
+                .iter()
+                .map(|part| format!(".{}", scope_class(part)))
+                .collect();
+            writeln!(css, ":where({}) {{ {style} }}", selector.join(" ")).unwrap();
+        }
+
+        css
+    }
+}
+
+/// Render a piece of text for a terminal, using 24-bit colour escape codes.
+/// Each run of text with a different style starts by resetting the terminal's
+/// style, then setting the foreground colour and font style, and the
+/// background colour if it differs from the theme's default.  The style is
+/// reset before every line ending and at the end of the output, so it never
+/// applies to anything written afterwards.  Token ranges are the same as for
+/// `render_html`.
+pub fn render_ansi(text: &str, tokens: &[Token], theme: &Theme) -> String {
+    render_ansi_with(text, tokens, theme, |colour| {
+        format!("2;{};{};{}", colour.r, colour.g, colour.b)
+    })
+}
+
+/// Render a piece of text for a terminal that only supports the xterm 256
+/// colour palette, with each colour replaced by the closest one in the
+/// palette, see `rgb_to_ansi256`.  Otherwise the same as `render_ansi`.
+pub fn render_ansi_256(text: &str, tokens: &[Token], theme: &Theme) -> String {
+    render_ansi_with(text, tokens, theme, |colour| {
+        format!("5;{}", rgb_to_ansi256(colour.r, colour.g, colour.b))
+    })
+}
+
+/// Render a document for a terminal, the same as `render_ansi`, writing each
+/// line to `writer` as soon as it has been rendered.  `lines` is the same as
+/// for `render_html_to`.  Each line is followed by a `\n`, and the writer is
+/// flushed after each one.
+pub fn render_ansi_to<'a, W: io::Write>(
+    lines: impl IntoIterator<Item = (&'a str, &'a [Token])>,
+    theme: &Theme,
+    writer: &mut W,
+) -> io::Result<()> {
+    for (text, tokens) in lines {
+        writer.write_all(render_ansi(text, tokens, theme).as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Render a document as plain text for debugging a grammar, with each line
+/// followed by a line for each of its tokens, underlining the token with `^`
+/// and giving its scopes.  The outermost scope, which is the grammar's own
+/// and applies to every token, is left out, as are tokens with no other
+/// scope.  Tabs are expanded to spaces, see `Token::visual_column_range`.
+/// `lines` is the same as for `render_html_to`.  For example:
+///
+/// ```text
+/// x := 1 # one
+///   ^^ keyword.operator
+///      ^ constant.numeric
+///        ^^^^^ comment.line
+/// ```
+pub fn render_scope_overlay<'a>(lines: impl IntoIterator<Item = (&'a str, &'a [Token])>) -> String {
+    let mut output = String::new();
+
+    for (text, tokens) in lines {
+        let mut column = 0;
+        for c in text.chars() {
+            if c == '\t' {
+                let next = (column / DEFAULT_TAB_WIDTH + 1) * DEFAULT_TAB_WIDTH;
+                output.extend(std::iter::repeat_n(' ', next - column));
+                column = next;
+            } else {
+                output.push(c);
+                column += 1;
+            }
+        }
+        output.push('\n');
+
+        for token in tokens.iter().filter(|token| token.scopes.len() > 1) {
+            let range = token.visual_column_range(text, DEFAULT_TAB_WIDTH);
+            let width = range.len().max(1);
+            writeln!(
+                output,
+                "{}{} {}",
+                " ".repeat(range.start),
+                "^".repeat(width),
+                token.scopes[1..].join(" ")
+            )
+            .unwrap();
+        }
+    }
+
+    output
+}
+
+/// A token in the format `vscode-textmate` gives them, see
+/// `render_vscode_json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VscodeToken {
+    /// Start of the token within its line, in UTF-16 code units
+    pub start_index: usize,
+
+    /// End of the token within its line, in UTF-16 code units
+    pub end_index: usize,
+
+    pub scopes: Vec<String>,
+}
+
+impl VscodeToken {
+    /// Convert the tokens of a line, with byte ranges, to use UTF-16 indices
+    /// the same as JavaScript strings do
+    pub fn from_tokens(text: &str, tokens: &[Token]) -> Vec<VscodeToken> {
+        let index = |end: usize| -> usize {
+            text[..end.min(text.len())]
+                .chars()
+                .map(char::len_utf16)
+                .sum()
+        };
+
+        tokens
+            .iter()
+            .map(|token| VscodeToken {
+                start_index: index(token.text_range.start),
+                end_index: index(token.text_range.end),
+                scopes: token.scopes.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Render the tokens of a document as JSON in the format of `IToken` from
+/// `vscode-textmate`, for tools that expect its output.  The output is an
+/// array with an entry for each line, each an array of objects with the
+/// `startIndex`, `endIndex` and `scopes` of each token.  Indices are in UTF-16
+/// code units, see `VscodeToken`.  Unlike `vscode-textmate`, empty lines have
+/// no tokens, and the last token of a line ends at the end of its text rather
+/// than after the line ending.  `lines` is the same as for `render_html_to`.
+pub fn render_vscode_json<'a>(lines: impl IntoIterator<Item = (&'a str, &'a [Token])>) -> String {
+    let lines: Vec<_> = lines
+        .into_iter()
+        .map(|(text, tokens)| VscodeToken::from_tokens(text, tokens))
+        .collect();
+
+    // only strings and numbers, which can always be serialized
+    serde_json::to_string(&lines).unwrap()
+}
+
+/// Find the closest colour in the xterm 256 colour palette.  Only the 6x6x6
+/// colour cube (16 to 231) and the grey ramp (232 to 255) are used, as the
+/// first 16 colours are often changed by terminal themes.
+pub fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    let distance = |(r2, g2, b2): (u8, u8, u8)| {
+        let diff = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        diff(r, r2) + diff(g, g2) + diff(b, b2)
+    };
+
+    // closest level of the cube for each channel
+    let cube_index = |value: u8| {
+        (0..6)
+            .min_by_key(|&idx| (CUBE_LEVELS[idx] as i32 - value as i32).abs())
+            .unwrap()
+    };
+    let (ri, gi, bi) = (cube_index(r), cube_index(g), cube_index(b));
+    let cube = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+
+    // the greys go from 8 to 238 in steps of 10
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let grey_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let grey = 8 + 10 * grey_index;
+
+    if distance((grey, grey, grey)) < distance(cube) {
+        232 + grey_index
+    } else {
+        16 + 36 * ri as u8 + 6 * gi as u8 + bi as u8
+    }
+}
+
+/// Render text for a terminal, writing colours with the given function, which
+/// gives the part of the escape code after `38;` or `48;`
+fn render_ansi_with(
+    text: &str,
+    tokens: &[Token],
+    theme: &Theme,
+    colour: impl Fn(Colour) -> String,
+) -> String {
+    let default = theme.style_for(&[]);
+    let mut output = String::new();
+
+    // the style the terminal is currently using, so that adjacent tokens with
+    // the same style are written with a single escape
+    let mut current = None;
+    let mut pos = 0;
+
+    let mut write_text = |output: &mut String, text: &str, style: &ResolvedStyle| {
+        for (idx, line) in text.split('\n').enumerate() {
+            if idx > 0 {
+                output.push_str("\x1b[0m\n");
+                current = None;
+            }
+            if !line.is_empty() && current.as_ref() != Some(style) {
+                output.push_str(&sgr(style, &default, &colour));
+                current = Some(*style);
+            }
+            output.push_str(line);
+        }
+    };
+
+    for token in tokens {
+        let range = token.text_range.start.max(pos)..token.text_range.end.min(text.len());
+        if range.is_empty() {
+            continue;
+        }
+
+        if range.start > pos {
+            write_text(&mut output, &text[pos..range.start], &default);
+        }
+
+        let style = theme.style_for(&token.scopes);
+        write_text(&mut output, &text[range.clone()], &style);
+        pos = range.end;
+    }
+
+    write_text(&mut output, &text[pos..], &default);
+    output.push_str("\x1b[0m");
+
+    output
+}
+
+/// The escape code to reset the terminal's style, then switch to a style
+fn sgr(
+    style: &ResolvedStyle,
+    default: &ResolvedStyle,
+    colour: impl Fn(Colour) -> String,
+) -> String {
+    let mut codes = vec!["0".to_string()];
+
+    if let Some(foreground) = style.foreground {
+        codes.push(format!("38;{}", colour(foreground)));
+    }
+    if let Some(background) = style.background
+        && style.background != default.background
+    {
+        codes.push(format!("48;{}", colour(background)));
+    }
+
+    let font = style.font_style;
+    for (set, code) in [
+        (font.bold, "1"),
+        (font.italic, "3"),
+        (font.underline, "4"),
+        (font.strikethrough, "9"),
+    ] {
+        if set {
+            codes.push(code.to_string());
+        }
+    }
+
+    format!("\x1b[{}m", codes.join(";"))
+}
+
+/// The opening `<pre>` tag for HTML output, with the theme's default colours
+fn pre_tag(default: &ResolvedStyle) -> String {
+    let style = css(default, &ResolvedStyle::default());
+    if style.is_empty() {
+        "<pre>".to_string()
+    } else {
+        format!("<pre style=\"{style}\">")
+    }
+}
+
+/// Close the given number of spans
+fn close_spans(html: &mut String, count: usize) {
+    for _ in 0..count {
+        html.push_str("</span>");
+    }
+}
+
+/// CSS for the parts of a theme's style that are set.  A font style clears
+/// any font styles it doesn't set, as it does when matching scopes.
+fn style_css(style: &Style) -> String {
+    let mut css = vec![];
+
+    if let Some(colour) = style.foreground {
+        css.push(format!("color: {};", colour.to_hex()));
+    }
+    if let Some(colour) = style.background {
+        css.push(format!("background-color: {};", colour.to_hex()));
+    }
+    if let Some(font) = style.font_style {
+        let weight = if font.bold { "bold" } else { "normal" };
+        let font_style = if font.italic { "italic" } else { "normal" };
+        let decoration = match (font.underline, font.strikethrough) {
+            (true, true) => "underline line-through",
+            (true, false) => "underline",
+            (false, true) => "line-through",
+            (false, false) => "none",
+        };
+        css.push(format!("font-weight: {weight};"));
+        css.push(format!("font-style: {font_style};"));
+        css.push(format!("text-decoration: {decoration};"));
+    }
+
+    css.join(" ")
+}
+
+/// Write out a run of text with the same style, as a span if it has one
+fn finish_run(html: &mut String, run: Option<(String, usize)>, text: &str, end: usize) {
+    let Some((style, start)) = run else {
+        return;
+    };
+
+    if style.is_empty() {
+        escape(html, &text[start..end]);
+    } else {
+        write!(html, "<span style=\"{style}\">").unwrap();
+        escape(html, &text[start..end]);
+        html.push_str("</span>");
+    }
+}
+
+/// CSS for the parts of a style that differ from the default style
+fn css(style: &ResolvedStyle, default: &ResolvedStyle) -> String {
+    let mut css = vec![];
+
+    if let Some(colour) = style.foreground
+        && style.foreground != default.foreground
+    {
+        css.push(format!("color:{}", colour.to_hex()));
+    }
+    if let Some(colour) = style.background
+        && style.background != default.background
+    {
+        css.push(format!("background-color:{}", colour.to_hex()));
+    }
+
+    let font = style.font_style;
+    if font.bold {
+        css.push("font-weight:bold".to_string());
+    }
+    if font.italic {
+        css.push("font-style:italic".to_string());
+    }
+    match (font.underline, font.strikethrough) {
+        (true, true) => css.push("text-decoration:underline line-through".to_string()),
+        (true, false) => css.push("text-decoration:underline".to_string()),
+        (false, true) => css.push("text-decoration:line-through".to_string()),
+        (false, false) => (),
+    }
+
+    css.join(";")
+}
+
+/// Add text to HTML, escaped so that it is shown as written
+fn escape(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Config;
+
+    fn theme() -> Theme {
+        let cfg = Config::from_json(
+            "theme.json",
+            r##"{
+                "colors": {"editor.foreground": "#111111", "editor.background": "#222222"},
+                "tokenColors": [
+                    {"scope": "keyword", "settings": {"foreground": "#ff0000", "fontStyle": "bold"}},
+                    {"scope": "string", "settings": {"foreground": "#00ff00"}},
+                    {"scope": "string.quoted", "settings": {"background": "#0000ff"}}
+                ]
+            }"##,
+        )
+        .unwrap();
+        Theme::from_vscode_config(&cfg).unwrap()
+    }
+
+    fn token(text_range: std::ops::Range<usize>, scopes: &[&str]) -> Token {
+        Token {
+            text_range,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn html_inline_styles() {
+        let tokens = [
+            token(0..2, &["source", "keyword"]),
+            token(2..3, &["source"]),
+            token(3..6, &["source", "string.quoted"]),
+        ];
+
+        assert_eq!(
+            render_html("if \"a\"", &tokens, &theme(), &HtmlOptions::default()),
+            "<span style=\"color:#ff0000;font-weight:bold\">if</span> \
+            <span style=\"color:#00ff00;background-color:#0000ff\">&quot;a&quot;</span>"
+        );
+    }
+
+    #[test]
+    fn html_coalesces_and_wraps() {
+        let tokens = [
+            token(0..1, &["source", "keyword.a"]),
+            token(1..2, &["source", "keyword.b"]),
+            token(3..4, &["source", "keyword"]),
+        ];
+        let options = HtmlOptions { pre: true };
+
+        assert_eq!(
+            render_html("ab<c&", &tokens, &theme(), &options),
+            "<pre style=\"color:#111111;background-color:#222222\">\
+            <span style=\"color:#ff0000;font-weight:bold\">ab</span>&lt;\
+            <span style=\"color:#ff0000;font-weight:bold\">c</span>&amp;</pre>"
+        );
+    }
+
+    #[test]
+    fn html_classed_matches_css() {
+        let tokens = [
+            token(0..2, &["source", "keyword"]),
+            token(2..3, &["source"]),
+            token(3..6, &["source", "string.quoted"]),
+        ];
+        let html = render_html_classed("if \"a\"", &tokens, &HtmlOptions { pre: true });
+
+        assert_eq!(
+            html,
+            "<pre class=\"mtk\"><span class=\"mtk-source\">\
+            <span class=\"mtk-keyword\">if</span> \
+            <span class=\"mtk-string mtk-string-quoted\">&quot;a&quot;</span>\
+            </span></pre>"
+        );
+
+        let css = theme().to_css();
+        assert_eq!(
+            css.lines().collect::<Vec<_>>(),
+            [
+                ".mtk { color: #111111; background-color: #222222; }",
+                ":where(.mtk-keyword) { color: #ff0000; font-weight: bold; font-style: normal; text-decoration: none; }",
+                ":where(.mtk-string) { color: #00ff00; }",
+                ":where(.mtk-string-quoted) { background-color: #0000ff; }",
+            ]
+        );
+        for class in ["mtk-keyword", "mtk-string-quoted"] {
+            assert!(html.contains(class) && css.contains(&format!(".{class})")));
+        }
+    }
+
+    #[test]
+    fn scope_classes_are_distinct() {
+        assert_eq!(scope_class("variable.other"), "mtk-variable-other");
+
+        let scopes = ["a.b", "a-b", "a_b", "a_2d_b", "a/b", "a\u{e9}"];
+        let classes: std::collections::HashSet<_> = scopes.iter().map(|s| scope_class(s)).collect();
+        assert_eq!(classes.len(), scopes.len());
+    }
+
+    #[test]
+    fn ansi_truecolor() {
+        let tokens = [
+            token(0..2, &["source", "keyword"]),
+            token(2..3, &["source"]),
+            token(3..6, &["source", "string.quoted"]),
+        ];
+
+        assert_eq!(
+            render_ansi("if \"a\"", &tokens, &theme()),
+            "\x1b[0;38;2;255;0;0;1mif\
+            \x1b[0;38;2;17;17;17m \
+            \x1b[0;38;2;0;255;0;48;2;0;0;255m\"a\"\
+            \x1b[0m"
+        );
+    }
+
+    #[test]
+    fn ansi_resets_at_line_ends() {
+        let tokens = [token(0..5, &["source", "keyword"])];
+        let output = render_ansi("ab\ncd", &tokens, &theme());
+
+        assert_eq!(
+            output,
+            "\x1b[0;38;2;255;0;0;1mab\x1b[0m\n\x1b[0;38;2;255;0;0;1mcd\x1b[0m"
+        );
+        assert_eq!(render_ansi("", &[], &theme()), "\x1b[0m");
+    }
+
+    #[test]
+    fn ansi256_palette() {
+        assert_eq!(rgb_to_ansi256(0, 0, 0), 16);
+        assert_eq!(rgb_to_ansi256(255, 255, 255), 231);
+        assert_eq!(rgb_to_ansi256(128, 128, 128), 244);
+        assert_eq!(rgb_to_ansi256(255, 0, 0), 196);
+        assert_eq!(rgb_to_ansi256(0, 0, 255), 21);
+    }
+
+    #[test]
+    fn ansi256_render() {
+        let tokens = [token(0..2, &["source", "keyword"])];
+        assert_eq!(
+            render_ansi_256("if", &tokens, &theme()),
+            "\x1b[0;38;5;196;1mif\x1b[0m"
+        );
+    }
+
+    /// The text and tokens of each line of a document
+    type Lines = Vec<(&'static str, Vec<Token>)>;
+
+    /// Two lines of text with their tokens, and the same tokens offset to be
+    /// relative to the whole text
+    fn document() -> (Lines, &'static str, Vec<Token>) {
+        let lines = vec![
+            (
+                "if \"a\"",
+                vec![
+                    token(0..2, &["source", "keyword"]),
+                    token(2..3, &["source"]),
+                    token(3..6, &["source", "string.quoted"]),
+                ],
+            ),
+            ("<b> & if", vec![token(6..8, &["source", "keyword"])]),
+        ];
+        let text = "if \"a\"\n<b> & if";
+
+        let mut tokens = lines[0].1.clone();
+        tokens.push(token(13..15, &["source", "keyword"]));
+
+        (lines, text, tokens)
+    }
+
+    #[test]
+    fn render_html_to_writer() {
+        let (lines, text, tokens) = document();
+        let theme = theme();
+        let options = HtmlOptions { pre: true };
+
+        let mut output = vec![];
+        render_html_to(
+            lines
+                .iter()
+                .map(|(line, tokens)| (*line, tokens.as_slice())),
+            &theme,
+            &options,
+            &mut output,
+        )
+        .unwrap();
+
+        let expected = render_html(text, &tokens, &theme, &HtmlOptions { pre: false });
+        let expected = format!("{}{expected}\n</pre>", pre_tag(&theme.style_for(&[])));
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn render_ansi_to_writer() {
+        let (lines, text, tokens) = document();
+        let theme = theme();
+
+        let mut output = vec![];
+        render_ansi_to(
+            lines
+                .iter()
+                .map(|(line, tokens)| (*line, tokens.as_slice())),
+            &theme,
+            &mut output,
+        )
+        .unwrap();
+
+        let expected = format!("{}\n", render_ansi(text, &tokens, &theme));
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn scope_overlay_snapshot() {
+        let mut registry = crate::Registry::new();
+        registry
+            .add_grammar(
+                "make.json",
+                r##"{
+                    "scopeName": "source.makefile",
+                    "patterns": [
+                        {"match": "#.*", "name": "comment.line"},
+                        {"match": ":?=", "name": "keyword.operator"},
+                        {"match": "[0-9]+", "name": "constant.numeric"},
+                        {"match": "^(\\w+)(:)", "captures": {
+                            "1": {"name": "entity.name.target"},
+                            "2": {"name": "punctuation.separator"}
+                        }}
+                    ]
+                }"##,
+            )
+            .unwrap();
+
+        let text = "x := 1 # one\nall:\n\techo 2\n\ny = é3";
+        let lines = registry.parse("source.makefile", text).unwrap();
+        let overlay = render_scope_overlay(
+            text.lines()
+                .zip(&lines)
+                .map(|(line, tokens)| (line, &tokens[..])),
+        );
+
+        assert_eq!(
+            overlay,
+            "x := 1 # one\n\
+             \x20 ^^ keyword.operator\n\
+             \x20    ^ constant.numeric\n\
+             \x20      ^^^^^ comment.line\n\
+             all:\n\
+             ^^^ entity.name.target\n\
+             \x20  ^ punctuation.separator\n\
+             \x20   echo 2\n\
+             \x20        ^ constant.numeric\n\
+             \n\
+             y = é3\n\
+             \x20 ^ keyword.operator\n\
+             \x20    ^ constant.numeric\n"
+        );
+    }
+
+    #[test]
+    fn vscode_json_fixture() {
+        let lines = [
+            (
+                "if \"é😀\"",
+                vec![
+                    token(0..2, &["source.js", "keyword.control.js"]),
+                    token(2..3, &["source.js"]),
+                    token(3..11, &["source.js", "string.quoted.double.js"]),
+                ],
+            ),
+            ("", vec![]),
+        ];
+
+        let json = render_vscode_json(lines.iter().map(|(text, tokens)| (*text, &tokens[..])));
+        let fixture = r#"[
+            [
+                {"startIndex": 0, "endIndex": 2, "scopes": ["source.js", "keyword.control.js"]},
+                {"startIndex": 2, "endIndex": 3, "scopes": ["source.js"]},
+                {"startIndex": 3, "endIndex": 8, "scopes": ["source.js", "string.quoted.double.js"]}
+            ],
+            []
+        ]"#;
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::from_str::<serde_json::Value>(fixture).unwrap()
+        );
+        assert!(json.starts_with(r#"[[{"startIndex":0,"endIndex":2,"scopes":["#));
+    }
+}
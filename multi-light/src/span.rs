@@ -0,0 +1,150 @@
+//! Locations within source text, shared by everything that needs to point at a
+//! position in an input file.
+
+/// A single position within some source text.  Lines and columns both start at
+/// 1, columns are counted in characters rather than bytes, and only `\n` starts
+/// a new line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLocation {
+    /// Byte offset from the start of the text
+    pub byte: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for SourceLocation {
+    fn default() -> Self {
+        Self {
+            byte: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+impl SourceLocation {
+    /// Get the location of a byte offset within the source text.  Offsets past
+    /// the end of the text or within a character are moved back to the nearest
+    /// character boundary.
+    pub fn from_offset(source: &str, byte: usize) -> Self {
+        let mut end = byte.min(source.len());
+        while !source.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let mut location = Self::default();
+        for c in source[..end].chars() {
+            location.advance(c);
+        }
+
+        location
+    }
+
+    /// Get the location of a line and column within the source text, or None if
+    /// the text doesn't have that line or the line doesn't have that column.
+    /// The column just past the end of a line is allowed.
+    pub fn from_line_column(source: &str, line: usize, column: usize) -> Option<Self> {
+        let mut location = Self::default();
+        let mut chars = source.chars();
+
+        while (location.line, location.column) != (line, column) {
+            let c = chars.next()?;
+            if location.line == line && c == '\n' {
+                return None;
+            }
+            location.advance(c);
+        }
+
+        Some(location)
+    }
+
+    /// Move the location past a character
+    pub(crate) fn advance(&mut self, c: char) {
+        self.byte += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
+
+/// A range of source text, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Span {
+    pub start: SourceLocation,
+    pub end: SourceLocation,
+}
+
+impl Span {
+    /// Get the span between two byte offsets within the source text
+    pub fn from_offsets(source: &str, start: usize, end: usize) -> Self {
+        Self {
+            start: SourceLocation::from_offset(source, start),
+            end: SourceLocation::from_offset(source, end),
+        }
+    }
+
+    /// Length of the span in bytes
+    pub fn len(&self) -> usize {
+        self.end.byte.saturating_sub(self.start.byte)
+    }
+
+    /// Whether the span contains no text
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The text covered by the span, if it is within the source text
+    pub fn text<'a>(&self, source: &'a str) -> Option<&'a str> {
+        source.get(self.start.byte..self.end.byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_and_line_columns() {
+        let source = "ab\ncé\nd";
+
+        let location = SourceLocation::from_offset(source, 4);
+        assert_eq!(
+            location,
+            SourceLocation {
+                byte: 4,
+                line: 2,
+                column: 2,
+            }
+        );
+        assert_eq!(
+            SourceLocation::from_line_column(source, 2, 2),
+            Some(location)
+        );
+
+        // within `é`, moved back to its start
+        assert_eq!(SourceLocation::from_offset(source, 5), location);
+        assert_eq!(SourceLocation::from_offset(source, 100).line, 3);
+
+        assert_eq!(
+            SourceLocation::from_line_column(source, 2, 3).unwrap().byte,
+            6
+        );
+        assert_eq!(SourceLocation::from_line_column(source, 2, 4), None);
+        assert_eq!(SourceLocation::from_line_column(source, 4, 1), None);
+    }
+
+    #[test]
+    fn span_text() {
+        let source = "ab\ncé\nd";
+        let span = Span::from_offsets(source, 3, 6);
+
+        assert_eq!(span.len(), 3);
+        assert!(!span.is_empty());
+        assert_eq!(span.text(source), Some("cé"));
+        assert!(Span::from_offsets(source, 2, 2).is_empty());
+    }
+}
@@ -0,0 +1,868 @@
+//! Typed representation of a colour theme, built from a parsed document in any
+//! of the supported file formats.
+
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::{Config, ConfigTree, Error};
+
+/// A colour theme, e.g. from a `.tmTheme` file
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Theme {
+    pub name: Option<String>,
+
+    /// Style of any text that no rule applies to
+    pub settings: Style,
+
+    /// Any other colours that apply to the whole editor rather than to text,
+    /// e.g. `caret` or `selection`, which are readable but not used when
+    /// highlighting
+    pub colours: IndexMap<String, Colour>,
+
+    /// Styles for scopes, in the order they were defined
+    pub rules: Vec<ThemeRule>,
+}
+
+/// A style applied to any text matching one of the rule's scope selectors
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ThemeRule {
+    pub name: Option<String>,
+
+    /// Scope selectors, e.g. `string.quoted` or `meta.function entity.name`
+    pub scope: Vec<String>,
+
+    pub settings: Style,
+}
+
+/// The parts of a text style set by a theme, each of which is None if not set.
+/// Serializes as the `settings` object of a `.tmTheme` rule, without the
+/// parts that aren't set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Style {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub foreground: Option<Colour>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<Colour>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font_style: Option<FontStyle>,
+}
+
+/// The style to use for a token, after applying every matching rule.  The
+/// colours are None if the theme doesn't set a default for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ResolvedStyle {
+    pub foreground: Option<Colour>,
+    pub background: Option<Colour>,
+    pub font_style: FontStyle,
+}
+
+/// A theme rule that applies to a token, see `Theme::resolve_all`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuleMatch<'a> {
+    /// The rule's most specific selector matching the token
+    pub selector: &'a str,
+    pub rule: &'a ThemeRule,
+    pub specificity: Specificity,
+}
+
+/// How specifically a selector matches a token's scopes, where rules that are
+/// more specific override less specific ones.  Compares by each field in
+/// order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Specificity {
+    /// Position within the token's scopes, outermost first, of the scope
+    /// matched by the selector's last scope
+    pub depth: usize,
+
+    /// Number of `.` separated parts in the selector's last scope
+    pub parts: usize,
+
+    /// Number of scopes in the selector
+    pub scopes: usize,
+}
+
+/// An sRGB colour, with alpha
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+/// Font styles that a theme can apply to text.  A `fontStyle` of an empty
+/// string is all false, which clears any font style from less specific rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FontStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+}
+
+impl Colour {
+    /// Parse a hex colour, as `#RGB`, `#RGBA`, `#RRGGBB` or `#RRGGBBAA`.
+    /// Colours without an alpha are opaque.
+    pub fn from_hex(value: &str) -> Option<Colour> {
+        let hex = value.strip_prefix('#')?;
+        if !hex.is_ascii() {
+            return None;
+        }
+
+        let digit = |idx: usize, len: usize| {
+            let value = u8::from_str_radix(hex.get(idx * len..(idx + 1) * len)?, 16).ok()?;
+            // expand short form colours, so `#f00` is the same as `#ff0000`
+            Some(if len == 1 { value * 17 } else { value })
+        };
+
+        let len = match hex.len() {
+            3 | 4 => 1,
+            6 | 8 => 2,
+            _ => return None,
+        };
+        let has_alpha = matches!(hex.len(), 4 | 8);
+
+        Some(Colour {
+            r: digit(0, len)?,
+            g: digit(1, len)?,
+            b: digit(2, len)?,
+            a: if has_alpha { digit(3, len)? } else { 255 },
+        })
+    }
+
+    /// Format the colour as `#RRGGBB`, or `#RRGGBBAA` if it isn't opaque
+    pub fn to_hex(&self) -> String {
+        let Colour { r, g, b, a } = *self;
+        if a == 255 {
+            format!("#{r:02x}{g:02x}{b:02x}")
+        } else {
+            format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
+        }
+    }
+}
+
+/// Serializes as a hex string, see `Colour::to_hex`
+impl Serialize for Colour {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl FontStyle {
+    /// Parse a space separated list of font styles, ignoring any unknown ones
+    pub fn parse(value: &str) -> FontStyle {
+        let mut style = FontStyle::default();
+
+        for word in value.split_whitespace() {
+            match word {
+                "bold" => style.bold = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                "strikethrough" => style.strikethrough = true,
+                _ => (),
+            }
+        }
+
+        style
+    }
+
+    /// Format the font styles that are set as a space separated list, the
+    /// reverse of `parse`
+    pub fn to_words(&self) -> String {
+        [
+            (self.bold, "bold"),
+            (self.italic, "italic"),
+            (self.underline, "underline"),
+            (self.strikethrough, "strikethrough"),
+        ]
+        .into_iter()
+        .filter_map(|(set, word)| set.then_some(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+}
+
+/// Serializes as a space separated list, see `FontStyle::to_words`
+impl Serialize for FontStyle {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_words())
+    }
+}
+
+impl Theme {
+    /// Build a theme from a parsed `.tmTheme` document.  Entries of its
+    /// `settings` array without a `scope`, usually only the first one, set
+    /// the default style and editor colours; every other entry is a rule.
+    /// Errors say which entry was invalid, as a slash separated path from the
+    /// root of the document.
+    pub fn from_config(cfg: &Config) -> Result<Theme, Error> {
+        let parser = ThemeParser { cfg };
+        let tree = cfg.tree();
+
+        let mut theme = Theme {
+            name: parser.optional_string(tree, "name", "")?,
+            ..Theme::default()
+        };
+
+        let Some(settings) = tree.get("settings") else {
+            return Err(parser.error("", "missing required field `settings`"));
+        };
+        parser.settings(&mut theme, settings, "settings")?;
+
+        Ok(theme)
+    }
+
+    /// Build a theme from a parsed VSCode colour theme, which is usually JSON.
+    /// Its `tokenColors` are the same as a `.tmTheme`'s `settings`, except
+    /// that scopes can also be arrays.  The `colors` object sets the editor
+    /// colours, with `editor.foreground` and `editor.background` used as the
+    /// default style if `tokenColors` doesn't set one.
+    pub fn from_vscode_config(cfg: &Config) -> Result<Theme, Error> {
+        let parser = ThemeParser { cfg };
+        let tree = cfg.tree();
+
+        let mut theme = Theme {
+            name: parser.optional_string(tree, "name", "")?,
+            ..Theme::default()
+        };
+
+        if let Some(colours) = tree.get("colors") {
+            let Some(colours) = colours.as_object() else {
+                return Err(parser.error("colors", "expected an object"));
+            };
+
+            for (key, value) in colours {
+                if let Some(colour) = value.as_str().and_then(Colour::from_hex) {
+                    theme.colours.insert(key.clone(), colour);
+                }
+            }
+        }
+
+        match tree.get("tokenColors") {
+            Some(ConfigTree::String(_)) => {
+                return Err(parser.error(
+                    "tokenColors",
+                    "token colours in a separate file are not supported",
+                ));
+            }
+            Some(settings) => parser.settings(&mut theme, settings, "tokenColors")?,
+            None => (),
+        }
+
+        let editor = Style {
+            foreground: theme.colours.get("editor.foreground").copied(),
+            background: theme.colours.get("editor.background").copied(),
+            font_style: None,
+        };
+        theme.settings = merge(editor, theme.settings);
+
+        Ok(theme)
+    }
+
+    /// Find the style of a token from its scopes, outermost first, e.g. as
+    /// given by `Registry::tokenize_line`.
+    ///
+    /// Each scope is styled in turn, starting from the theme's defaults, by
+    /// the rules with a selector whose last scope matches it and whose other
+    /// scopes match scopes it is nested within, in order.  A selector's scope
+    /// matches any scope it is a prefix of, so `string` matches
+    /// `string.quoted`.  More specific selectors are applied after less
+    /// specific ones, so win wherever both set a style: a longer last scope
+    /// is more specific, then having more scopes.  Between equally specific
+    /// rules, the one defined later wins.  Each part of the style is set by
+    /// the last rule to set it, so rules only override what they set.
+    pub fn style_for(&self, scopes: &[String]) -> ResolvedStyle {
+        let style = self
+            .matching_rules(scopes)
+            .into_iter()
+            .fold(self.settings, |style, (_, _, found)| {
+                merge(style, found.rule.settings)
+            });
+
+        ResolvedStyle {
+            foreground: style.foreground,
+            background: style.background,
+            font_style: style.font_style.unwrap_or_default(),
+        }
+    }
+
+    /// Get one of the theme's editor colours, rather than a colour for text,
+    /// by the name the theme file uses for it, e.g. `selection` in a
+    /// `.tmTheme` or `editor.selectionBackground` in a VSCode theme.  The
+    /// colours that have names in both kinds of theme can be looked up by
+    /// either name:
+    ///
+    /// | `.tmTheme`         | VSCode                               |
+    /// |--------------------|--------------------------------------|
+    /// | `caret`            | `editorCursor.foreground`            |
+    /// | `selection`        | `editor.selectionBackground`         |
+    /// | `lineHighlight`    | `editor.lineHighlightBackground`     |
+    /// | `findHighlight`    | `editor.findMatchHighlightBackground`|
+    /// | `invisibles`       | `editorWhitespace.foreground`        |
+    /// | `gutterForeground` | `editorLineNumber.foreground`        |
+    pub fn ui_colour(&self, name: &str) -> Option<Colour> {
+        if let Some(colour) = self.colours.get(name) {
+            return Some(*colour);
+        }
+
+        UI_COLOURS.iter().find_map(|&(tm_theme, vscode)| {
+            let other = if name == tm_theme {
+                vscode
+            } else if name == vscode {
+                tm_theme
+            } else {
+                return None;
+            };
+            self.colours.get(other).copied()
+        })
+    }
+
+    /// Every rule that applies to a token with the given scopes, most specific
+    /// first, e.g. to explain why a token has the style it does.  A rule is
+    /// listed once for each of the scopes it styles.  This is the reverse of
+    /// the order `style_for` applies them in, so each part of the style comes
+    /// from the first rule listed that sets it.
+    pub fn resolve_all(&self, scopes: &[String]) -> Vec<RuleMatch<'_>> {
+        self.matching_rules(scopes)
+            .into_iter()
+            .rev()
+            .map(|(_, _, rule)| rule)
+            .collect()
+    }
+
+    /// Every rule that applies to a token, in the order they are applied, with
+    /// the index of each rule to break ties between equally specific ones
+    fn matching_rules(&self, scopes: &[String]) -> Vec<(Specificity, usize, RuleMatch<'_>)> {
+        let mut matches = vec![];
+
+        for depth in 0..scopes.len() {
+            matches.extend(self.rules.iter().enumerate().filter_map(|(idx, rule)| {
+                let (specificity, selector) = rule
+                    .scope
+                    .iter()
+                    .filter_map(|selector| {
+                        let (parts, count) = selector_specificity(selector, &scopes[..=depth])?;
+                        let specificity = Specificity {
+                            depth,
+                            parts,
+                            scopes: count,
+                        };
+                        Some((specificity, selector.as_str()))
+                    })
+                    .max_by_key(|&(specificity, _)| specificity)?;
+
+                let rule = RuleMatch {
+                    selector,
+                    rule,
+                    specificity,
+                };
+                Some((specificity, idx, rule))
+            }));
+        }
+
+        matches.sort_by_key(|&(specificity, idx, _)| (specificity, idx));
+        matches
+    }
+}
+
+/// Editor colours with a different name in `.tmTheme` files to VSCode themes,
+/// as the `.tmTheme` name then the VSCode name, see `Theme::ui_colour`
+const UI_COLOURS: &[(&str, &str)] = &[
+    ("caret", "editorCursor.foreground"),
+    ("selection", "editor.selectionBackground"),
+    ("lineHighlight", "editor.lineHighlightBackground"),
+    ("findHighlight", "editor.findMatchHighlightBackground"),
+    ("invisibles", "editorWhitespace.foreground"),
+    ("gutterForeground", "editorLineNumber.foreground"),
+];
+
+/// Conversion of the document's tree into a theme, keeping the document around
+/// for error messages
+struct ThemeParser<'a> {
+    cfg: &'a Config,
+}
+
+impl ThemeParser<'_> {
+    /// Parse an array of settings into the theme.  Entries without a `scope`
+    /// set the default style and editor colours; every other entry is a rule.
+    fn settings(&self, theme: &mut Theme, tree: &ConfigTree, path: &str) -> Result<(), Error> {
+        let Some(settings) = tree.as_array() else {
+            return Err(self.error(path, "expected an array"));
+        };
+
+        for (idx, entry) in settings.iter().enumerate() {
+            let path = format!("{path}/{idx}");
+            if entry.as_object().is_none() {
+                return Err(self.error(&path, "expected an object"));
+            }
+
+            let Some(style) = entry.get("settings") else {
+                return Err(self.error(&path, "missing required field `settings`"));
+            };
+            let style_path = format!("{path}/settings");
+            let Some(style_values) = style.as_object() else {
+                return Err(self.error(&style_path, "expected an object"));
+            };
+
+            let settings = self.style(style, &style_path)?;
+
+            let Some(scope) = self.scope(entry, &path)? else {
+                theme.settings = merge(theme.settings, settings);
+
+                // anything else that is a colour is for the editor
+                for (key, value) in style_values {
+                    if !["foreground", "background", "fontStyle"].contains(&key.as_str())
+                        && let Some(colour) = value.as_str().and_then(Colour::from_hex)
+                    {
+                        theme.colours.insert(key.clone(), colour);
+                    }
+                }
+
+                continue;
+            };
+
+            theme.rules.push(ThemeRule {
+                name: self.optional_string(entry, "name", &path)?,
+                scope,
+                settings,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Get the scope selectors of an entry, either a comma separated string or
+    /// an array of strings that are each comma separated
+    fn scope(&self, tree: &ConfigTree, path: &str) -> Result<Option<Vec<String>>, Error> {
+        let path = join(path, "scope");
+
+        match tree.get("scope") {
+            None => Ok(None),
+            Some(ConfigTree::String(scope)) => Ok(Some(split_selectors(scope))),
+            Some(ConfigTree::Array(scopes)) => {
+                let mut selectors = vec![];
+                for (idx, scope) in scopes.iter().enumerate() {
+                    let Some(scope) = scope.as_str() else {
+                        return Err(self.error(&format!("{path}/{idx}"), "expected a string"));
+                    };
+                    selectors.extend(split_selectors(scope));
+                }
+                Ok(Some(selectors))
+            }
+            Some(_) => Err(self.error(&path, "expected a string or an array")),
+        }
+    }
+
+    /// Parse the `foreground`, `background` and `fontStyle` of an object
+    fn style(&self, tree: &ConfigTree, path: &str) -> Result<Style, Error> {
+        Ok(Style {
+            foreground: self.colour(tree, "foreground", path)?,
+            background: self.colour(tree, "background", path)?,
+            font_style: self
+                .optional_string(tree, "fontStyle", path)?
+                .map(|style| FontStyle::parse(&style)),
+        })
+    }
+
+    /// Get a colour stored in an object, if it has one under the given key
+    fn colour(&self, tree: &ConfigTree, key: &str, path: &str) -> Result<Option<Colour>, Error> {
+        let Some(value) = self.optional_string(tree, key, path)? else {
+            return Ok(None);
+        };
+
+        match Colour::from_hex(&value) {
+            Some(colour) => Ok(Some(colour)),
+            None => Err(self.error(
+                &join(path, key),
+                &format!("expected a colour like `#RRGGBB`, got `{value}`"),
+            )),
+        }
+    }
+
+    /// Get a string stored in an object, if it has one under the given key
+    fn optional_string(
+        &self,
+        tree: &ConfigTree,
+        key: &str,
+        path: &str,
+    ) -> Result<Option<String>, Error> {
+        let Some(value) = tree.get(key) else {
+            return Ok(None);
+        };
+
+        match value.as_str() {
+            Some(value) => Ok(Some(value.to_string())),
+            None => Err(self.error(&join(path, key), "expected a string")),
+        }
+    }
+
+    /// Create an error message
+    fn error(&self, path: &str, err: &str) -> Error {
+        let err = if path.is_empty() {
+            err.to_string()
+        } else {
+            format!("`{path}`: {err}")
+        };
+
+        Error::ThemeError {
+            err,
+            file_name: self.cfg.file_name().to_path_buf(),
+        }
+    }
+}
+
+/// Split a comma separated list of scope selectors
+fn split_selectors(scope: &str) -> Vec<String> {
+    scope
+        .split(',')
+        .map(str::trim)
+        .filter(|selector| !selector.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// How specifically a selector, e.g. `meta.function entity.name`, matches the
+/// last of the scopes, or None if it doesn't match.  The last scope of the
+/// selector has to match the last scope, and its other scopes have to match
+/// earlier scopes, in the same order, but not necessarily next to each other.
+/// Returns the number of parts in the selector's last scope, then the number
+/// of scopes in the selector.
+fn selector_specificity(selector: &str, scopes: &[String]) -> Option<(usize, usize)> {
+    let mut parts = selector.split_whitespace().rev();
+    let (last, mut scopes) = (parts.next()?, scopes.iter().rev());
+
+    if !scope_matches(last, scopes.next()?) {
+        return None;
+    }
+
+    let mut count = 1;
+    for part in parts {
+        scopes.find(|scope| scope_matches(part, scope))?;
+        count += 1;
+    }
+
+    Some((last.split('.').count(), count))
+}
+
+/// Check whether a scope from a selector matches a scope, i.e. whether the
+/// scope is the same or starts with the selector's scope followed by a `.`
+fn scope_matches(selector: &str, scope: &str) -> bool {
+    scope
+        .strip_prefix(selector)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Combine two styles, with anything set in `new` replacing `old`
+fn merge(old: Style, new: Style) -> Style {
+    Style {
+        foreground: new.foreground.or(old.foreground),
+        background: new.background.or(old.background),
+        font_style: new.font_style.or(old.font_style),
+    }
+}
+
+/// Add a key onto the end of a path
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}/{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vscode(content: &str) -> Theme {
+        Theme::from_vscode_config(&Config::from_json("theme.json", content).unwrap()).unwrap()
+    }
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    }
+
+    const TM_THEME: &str = "<plist><dict>\
+        <key>name</key><string>Test</string>\
+        <key>settings</key><array>\
+            <dict><key>settings</key><dict>\
+                <key>background</key><string>#102030</string>\
+                <key>foreground</key><string>#F0F0F080</string>\
+                <key>caret</key><string>#FFFFFF</string>\
+            </dict></dict>\
+            <dict>\
+                <key>name</key><string>Comment</string>\
+                <key>scope</key><string>comment, string.quoted</string>\
+                <key>settings</key><dict>\
+                    <key>foreground</key><string>#808080</string>\
+                    <key>fontStyle</key><string>italic underline</string>\
+                </dict>\
+            </dict>\
+        </array>\
+        </dict></plist>";
+
+    #[test]
+    fn tm_theme() {
+        let cfg = Config::from_plist("test.tmTheme", TM_THEME).unwrap();
+        let theme = Theme::from_config(&cfg).unwrap();
+
+        assert_eq!(theme.name.as_deref(), Some("Test"));
+        let colour = |r, g, b, a| Some(Colour { r, g, b, a });
+        assert_eq!(theme.settings.background, colour(0x10, 0x20, 0x30, 0xFF));
+        assert_eq!(theme.settings.foreground, colour(0xF0, 0xF0, 0xF0, 0x80));
+        assert_eq!(
+            theme.colours.get("caret").copied(),
+            colour(0xFF, 0xFF, 0xFF, 0xFF)
+        );
+
+        assert_eq!(theme.rules.len(), 1);
+        let rule = &theme.rules[0];
+        assert_eq!(rule.name.as_deref(), Some("Comment"));
+        assert_eq!(rule.scope, ["comment", "string.quoted"]);
+        assert_eq!(
+            rule.settings.font_style,
+            Some(FontStyle {
+                bold: false,
+                italic: true,
+                underline: true,
+                strikethrough: false,
+            })
+        );
+    }
+
+    #[test]
+    fn tm_theme_errors() {
+        let theme = |content: &str| {
+            let cfg = Config::from_json("test.json", content).unwrap();
+            Theme::from_config(&cfg).unwrap_err().to_string()
+        };
+
+        assert!(theme("{}").contains("missing required field `settings`"));
+        let err = theme(r#"{"settings": [{"scope": "a", "settings": {"foreground": "red"}}]}"#);
+        assert!(err.contains("`settings/0/settings/foreground`"), "{err}");
+    }
+
+    #[test]
+    fn vscode_scope_shapes() {
+        let theme = vscode(
+            r##"{
+                "name": "Dark",
+                "colors": {"editor.background": "#1e1e1e", "editor.foreground": "#d4d4d4", "bad": 3},
+                "tokenColors": [
+                    {"scope": "comment, string", "settings": {"foreground": "#6a9955"}},
+                    {"scope": ["keyword", "storage.type, storage.modifier"], "settings": {"fontStyle": "bold"}},
+                    {"settings": {"foreground": "#ffffff"}}
+                ]
+            }"##,
+        );
+
+        assert_eq!(theme.rules[0].scope, ["comment", "string"]);
+        assert_eq!(
+            theme.rules[1].scope,
+            ["keyword", "storage.type", "storage.modifier"]
+        );
+
+        // `tokenColors` without a scope override the editor colours
+        assert_eq!(theme.settings.background, Colour::from_hex("#1e1e1e"));
+        assert_eq!(theme.settings.foreground, Colour::from_hex("#ffffff"));
+        assert_eq!(
+            theme.colours.get("editor.background").copied(),
+            Colour::from_hex("#1e1e1e")
+        );
+        assert!(!theme.colours.contains_key("bad"));
+    }
+
+    #[test]
+    fn vscode_errors() {
+        let theme = |content: &str| {
+            let cfg = Config::from_json("test.json", content).unwrap();
+            Theme::from_vscode_config(&cfg).unwrap_err().to_string()
+        };
+
+        assert!(theme(r#"{"tokenColors": "./tokens.json"}"#).contains("separate file"));
+        let err = theme(r#"{"tokenColors": [{"scope": ["a", 1], "settings": {}}]}"#);
+        assert!(
+            err.contains("`tokenColors/0/scope/1`: expected a string"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn style_for_selectors() {
+        let theme = vscode(
+            r##"{
+                "colors": {"editor.foreground": "#000000"},
+                "tokenColors": [
+                    {"scope": "string", "settings": {"foreground": "#000001"}},
+                    {"scope": "meta.function entity.name", "settings": {"foreground": "#000002"}},
+                    {"scope": "entity.name", "settings": {"foreground": "#000003", "fontStyle": "bold"}},
+                    {"scope": "keyword", "settings": {"foreground": "#000004"}},
+                    {"scope": "keyword", "settings": {"foreground": "#000005"}},
+                    {"scope": "keyword.control", "settings": {"fontStyle": ""}},
+                    {"scope": "source keyword", "settings": {"fontStyle": "italic"}}
+                ]
+            }"##,
+        );
+        let style = |scopes_of: &[&str]| theme.style_for(&scopes(scopes_of));
+
+        // prefix matching, but only at `.` boundaries
+        assert_eq!(
+            style(&["source.c", "string.quoted.double"]).foreground,
+            Colour::from_hex("#000001")
+        );
+        assert_eq!(
+            style(&["source.c", "stringy"]).foreground,
+            Colour::from_hex("#000000")
+        );
+
+        // the descendant selector is more specific than `entity.name` alone,
+        // but leaves the font style from it
+        let function = style(&["source.c", "meta.function.c", "entity.name.function"]);
+        assert_eq!(function.foreground, Colour::from_hex("#000002"));
+        assert!(function.font_style.bold);
+        let name = style(&["source.c", "entity.name.function"]);
+        assert_eq!(name.foreground, Colour::from_hex("#000003"));
+
+        // equally specific rules go to the later one, and a longer last scope
+        // beats having more scopes
+        let keyword = style(&["source.c", "keyword.control"]);
+        assert_eq!(keyword.foreground, Colour::from_hex("#000005"));
+        assert_eq!(keyword.font_style, FontStyle::default());
+        assert!(style(&["source.c", "keyword.other"]).font_style.italic);
+    }
+
+    #[test]
+    fn style_for_nested_scopes() {
+        // rules for outer scopes apply to tokens within them, unless a rule for
+        // an inner scope overrides them
+        let theme = vscode(
+            r##"{"tokenColors": [
+                {"scope": "comment", "settings": {"foreground": "#000001", "background": "#000002"}},
+                {"scope": "comment.block.documentation keyword", "settings": {"foreground": "#000003"}}
+            ]}"##,
+        );
+
+        let style = theme.style_for(&scopes(&[
+            "source.c",
+            "comment.block.documentation",
+            "keyword.tag",
+        ]));
+        assert_eq!(style.foreground, Colour::from_hex("#000003"));
+        assert_eq!(style.background, Colour::from_hex("#000002"));
+        assert_eq!(theme.style_for(&[]), ResolvedStyle::default());
+    }
+
+    #[test]
+    fn resolve_all_in_specificity_order() {
+        let theme = vscode(
+            r##"{"tokenColors": [
+                {"scope": "string", "settings": {"foreground": "#000001"}},
+                {"scope": "source string.quoted", "settings": {"foreground": "#000002"}},
+                {"scope": "string.quoted", "settings": {"foreground": "#000003"}},
+                {"scope": "source", "settings": {"fontStyle": "bold"}},
+                {"scope": "comment", "settings": {"foreground": "#000004"}}
+            ]}"##,
+        );
+
+        let found = theme.resolve_all(&scopes(&["source.c", "string.quoted.double"]));
+        let selectors: Vec<_> = found.iter().map(|found| found.selector).collect();
+        assert_eq!(
+            selectors,
+            ["source string.quoted", "string.quoted", "string", "source"]
+        );
+
+        assert_eq!(
+            found[0].specificity,
+            Specificity {
+                depth: 1,
+                parts: 2,
+                scopes: 2
+            }
+        );
+        assert_eq!(
+            found[0].rule.settings.foreground,
+            Colour::from_hex("#000002")
+        );
+
+        // the first match setting each part of the style is what is used
+        let style = theme.style_for(&scopes(&["source.c", "string.quoted.double"]));
+        assert_eq!(style.foreground, Colour::from_hex("#000002"));
+        assert!(style.font_style.bold);
+    }
+
+    #[test]
+    fn colour_hex_round_trip() {
+        for hex in ["#ff8000", "#12345678", "#000000"] {
+            let colour = Colour::from_hex(hex).unwrap();
+            assert_eq!(colour.to_hex(), hex);
+            assert_eq!(Colour::from_hex(&colour.to_hex()), Some(colour));
+        }
+
+        let short = Colour::from_hex("#f80").unwrap();
+        assert_eq!(short.to_hex(), "#ff8800");
+        assert_eq!(Colour::from_hex("#f808").unwrap().a, 0x88);
+        assert_eq!(Colour::from_hex("ff8000"), None);
+        assert_eq!(
+            Colour::from_hex("#ff80"),
+            Some(Colour::from_hex("#ffff8800").unwrap())
+        );
+    }
+
+    #[test]
+    fn serialize_style() {
+        let style = Style {
+            foreground: Colour::from_hex("#ff8000"),
+            background: None,
+            font_style: Some(FontStyle::parse("italic bold")),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&style).unwrap(),
+            r##"{"foreground":"#ff8000","fontStyle":"bold italic"}"##
+        );
+        assert_eq!(serde_json::to_string(&Style::default()).unwrap(), "{}");
+    }
+
+    #[test]
+    fn ui_colours_from_vscode_theme() {
+        let theme = vscode(
+            r##"{"colors": {
+                "editor.selectionBackground": "#264f78",
+                "editorCursor.foreground": "#aeafad",
+                "statusBar.background": "not a colour"
+            }}"##,
+        );
+
+        let selection = Colour::from_hex("#264f78");
+        assert_eq!(theme.ui_colour("editor.selectionBackground"), selection);
+        assert_eq!(theme.ui_colour("selection"), selection);
+        assert_eq!(theme.ui_colour("caret"), Colour::from_hex("#aeafad"));
+        assert_eq!(theme.ui_colour("statusBar.background"), None);
+        assert_eq!(theme.ui_colour("lineHighlight"), None);
+    }
+
+    #[test]
+    fn ui_colours_from_tm_theme() {
+        let cfg = Config::from_plist(
+            "theme.tmTheme",
+            "<plist><dict><key>settings</key><array><dict><key>settings</key><dict>\
+            <key>foreground</key><string>#ffffff</string>\
+            <key>lineHighlight</key><string>#333333</string>\
+            </dict></dict></array></dict></plist>",
+        )
+        .unwrap();
+        let theme = Theme::from_config(&cfg).unwrap();
+
+        let highlight = Colour::from_hex("#333333");
+        assert_eq!(theme.ui_colour("lineHighlight"), highlight);
+        assert_eq!(theme.ui_colour("editor.lineHighlightBackground"), highlight);
+        assert_eq!(theme.ui_colour("foreground"), None);
+    }
+}